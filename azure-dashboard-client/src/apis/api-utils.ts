@@ -41,6 +41,11 @@ const authorizedActionAsync = async <T>(method: Verb, url: string, message?: any
                 // Return text
                 return response.text()
             }
+            // If the server described the problem (RFC 7807), show its detail
+            if (-1 !== (response.headers.get('content-type') ?? '').indexOf('application/problem+json')) {
+                const problem = await response.json()
+                throw new Error(`${response.status} ${response.statusText} - ${problem.detail}`)
+            }
             const text = await response.text()
             const message = text ? `${response.status} ${response.statusText} - ${text}` : `${response.status} ${response.statusText}`
             throw new Error(message)
@@ -1,5 +1,31 @@
 import {getAsync} from "./api-utils";
 
+export type PoolDatabaseUsageViewModel = {
+    // The database name
+    databaseName: string,
+    // The amount of data used
+    databaseSizeUsed: number,
+    // The amount of data allocated
+    databaseSizeAllocated: number,
+    // The maximum size of the database
+    databaseSizeMax: number,
+    // The SKU name, e.g. "ElasticPool"
+    skuName?: string,
+    // The SKU tier, e.g. "Standard"
+    skuTier?: string,
+    // The database status, e.g. "Online"
+    status: string,
+    // The fraction of the pool's maximum size the database uses, from 0 to 1
+    poolShare: number,
+}
+
+export type FailedDatabaseViewModel = {
+    // The database name
+    databaseName: string,
+    // Why its usage couldn't be had
+    error: string,
+}
+
 export type ElasticPoolUsageViewModel = {
     // The amount of data used
     databaseSizeUsed: number,
@@ -7,6 +33,14 @@ export type ElasticPoolUsageViewModel = {
     databaseSizeAllocated: number,
     // The maximum size of the elasticPool
     databaseSizeMax: number,
+    // Where the amounts used and allocated came from
+    source: 'databases' | 'metrics',
+    // The usage of each database in the pool, largest first.  Empty if the usage came from the pool's metrics.
+    databases: PoolDatabaseUsageViewModel[],
+    // Whether some databases' usages couldn't be had, so the amounts used and allocated are too low
+    partial: boolean,
+    // The databases whose usages couldn't be had
+    failedDatabases: FailedDatabaseViewModel[],
 }
 
 // Fetches an elastic pool from the server.
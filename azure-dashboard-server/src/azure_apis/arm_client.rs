@@ -0,0 +1,483 @@
+use crate::azure_apis::concurrency::{ConcurrencyLimiter, ConcurrencyMetrics};
+use crate::azure_apis::error::AzureError;
+use crate::azure_apis::retry::{ReadThrottle, RetryPolicy, SubscriptionLimits};
+use crate::settings::ArmSettings;
+use crate::AccessTokenCacheMap;
+use futures::TryStreamExt;
+use serde::de::DeserializeOwned;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+// The API version used for each resource type, unless configured otherwise.
+const DEFAULT_API_VERSIONS: [(&str, &str); 6] = [
+    (
+        "Microsoft.Sql/servers/databases/usages",
+        "2022-02-01-preview",
+    ),
+    ("Microsoft.Sql/servers/elasticPools", "2021-05-01-preview"),
+    (
+        "Microsoft.Sql/servers/elasticPools/databases",
+        "2021-11-01-preview",
+    ),
+    ("Microsoft.Authorization/permissions", "2022-04-01"),
+    ("Microsoft.Insights/metrics", "2018-01-01"),
+    ("batch", "2020-06-01"),
+];
+
+// A page of a list response, e.g.
+// ```json
+// {
+//   "value": [ ... ],
+//   "nextLink": "https://management.azure.com/subscriptions/...&$skiptoken=..."
+// }
+// ```
+#[derive(Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase", bound(deserialize = "T: DeserializeOwned"))]
+struct Page<T> {
+    // The values on this page
+    #[serde(default = "Vec::new")]
+    value: Vec<T>,
+    // The URL of the next page, if there is one
+    next_link: Option<String>,
+}
+
+// Identifies a resource group.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, serde::Deserialize)]
+pub struct ResourceGroupId {
+    // The subscription ID (a GUID)
+    pub subscription_id: String,
+    // The resource group name
+    pub resource_group_name: String,
+}
+
+impl ResourceGroupId {
+    // The resource group's path, e.g. "/subscriptions/SUBSCRIPTION_ID/resourceGroups/RESOURCE_GROUP_NAME"
+    pub fn path(&self) -> String {
+        format!(
+            "/subscriptions/{}/resourceGroups/{}",
+            self.subscription_id, self.resource_group_name
+        )
+    }
+}
+
+// Identifies a database.  Can be read from a route path with the same parameter names.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, serde::Deserialize)]
+pub struct DatabaseId {
+    // The subscription ID (a GUID)
+    pub subscription_id: String,
+    // The resource group name
+    pub resource_group_name: String,
+    // The server name
+    pub server_name: String,
+    // The database name
+    pub database_name: String,
+}
+
+impl DatabaseId {
+    // The database's path, e.g. ".../providers/Microsoft.Sql/servers/SERVER_NAME/databases/DATABASE_NAME"
+    pub fn path(&self) -> String {
+        format!(
+            "/subscriptions/{}/resourceGroups/{}/providers/Microsoft.Sql/servers/{}/databases/{}",
+            self.subscription_id, self.resource_group_name, self.server_name, self.database_name
+        )
+    }
+}
+
+// Identifies an elastic pool.  Can be read from a route path with the same parameter names.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, serde::Deserialize)]
+pub struct ElasticPoolId {
+    // The subscription ID (a GUID)
+    pub subscription_id: String,
+    // The resource group name
+    pub resource_group_name: String,
+    // The server name
+    pub server_name: String,
+    // The elastic pool name
+    pub elastic_pool_name: String,
+}
+
+impl ElasticPoolId {
+    // The elastic pool's path, e.g. ".../providers/Microsoft.Sql/servers/SERVER_NAME/elasticPools/ELASTIC_POOL_NAME"
+    pub fn path(&self) -> String {
+        format!(
+            "/subscriptions/{}/resourceGroups/{}/providers/Microsoft.Sql/servers/{}/elasticPools/{}",
+            self.subscription_id, self.resource_group_name, self.server_name, self.elastic_pool_name
+        )
+    }
+    // Identifies a database on the same server as the pool.
+    pub fn database(&self, database_name: &str) -> DatabaseId {
+        DatabaseId {
+            subscription_id: self.subscription_id.clone(),
+            resource_group_name: self.resource_group_name.clone(),
+            server_name: self.server_name.clone(),
+            database_name: database_name.to_string(),
+        }
+    }
+}
+
+// Checks that a URL ARM gave us to follow, e.g. a nextLink, is on the same scheme, host and port as the base URL,
+// so the access token is never sent anywhere else.
+fn check_same_origin(base_url: &str, url: &str) -> anyhow::Result<()> {
+    let base_origin = reqwest::Url::parse(base_url)?.origin();
+    let origin = reqwest::Url::parse(url)
+        .map_err(|e| AzureError::unexpected(format!("Azure returned the invalid URL {url}: {e}")))?
+        .origin();
+    if base_origin != origin {
+        return Err(AzureError::unexpected(format!(
+            "Azure returned the URL {url}, which isn't on {base_url}"
+        ))
+        .into());
+    }
+    Ok(())
+}
+
+// A client for the Azure Resource Manager API.
+// The calls themselves are implemented alongside their response types, e.g. in get_database_usage.rs.
+pub struct ArmClient {
+    // The HTTP client
+    http_client: reqwest::Client,
+    // The access token caches
+    token_cache_map: Arc<AccessTokenCacheMap>,
+    // The endpoint to send all requests to, in place of each subscription's cloud's, if any
+    base_url: Option<String>,
+    // The API version for each resource type, by lower-case resource type
+    api_versions: HashMap<String, String>,
+    // How failed requests are retried
+    retry_policy: RetryPolicy,
+    // Slows down requests to subscriptions that are close to being throttled
+    read_throttle: ReadThrottle,
+    // Limits how many requests run at once
+    concurrency_limiter: ConcurrencyLimiter,
+}
+
+impl ArmClient {
+    // Creates a new client
+    pub fn new(
+        http_client: reqwest::Client,
+        token_cache_map: Arc<AccessTokenCacheMap>,
+        settings: &ArmSettings,
+        retry_policy: RetryPolicy,
+        concurrency_limiter: ConcurrencyLimiter,
+    ) -> Self {
+        // Start with the default API versions, and replace any that are configured.
+        // The configuration library lower-cases keys, so look them up in lower case.
+        let mut api_versions = DEFAULT_API_VERSIONS
+            .iter()
+            .map(|(resource_type, api_version)| {
+                (resource_type.to_ascii_lowercase(), api_version.to_string())
+            })
+            .collect::<HashMap<_, _>>();
+        for (resource_type, api_version) in &settings.api_versions {
+            api_versions.insert(resource_type.to_ascii_lowercase(), api_version.clone());
+        }
+        ArmClient {
+            http_client,
+            token_cache_map,
+            base_url: settings.base_url.clone(),
+            api_versions,
+            retry_policy,
+            read_throttle: ReadThrottle::new(settings),
+            concurrency_limiter,
+        }
+    }
+
+    // Gets the concurrency limits, and how requests have queued because of them.
+    pub fn concurrency_metrics(&self) -> ConcurrencyMetrics {
+        self.concurrency_limiter.metrics()
+    }
+
+    // Gets the number of reads ARM said the subscription had left at the last response, if known.
+    pub fn remaining_reads(&self, subscription_id: &str) -> Option<u32> {
+        self.read_throttle.remaining_reads(subscription_id)
+    }
+
+    // Gets the API version to use for the given resource type, e.g. "Microsoft.Sql/servers/elasticPools".
+    pub fn api_version(&self, resource_type: &str) -> anyhow::Result<&str> {
+        self.api_versions
+            .get(&resource_type.to_ascii_lowercase())
+            .map(String::as_str)
+            .ok_or_else(|| anyhow::anyhow!("No API version is known for {resource_type}"))
+    }
+
+    // Gets the base URL for the given subscription's requests: the configured one, or its cloud's.
+    pub fn base_url(&self, subscription_id: &str) -> anyhow::Result<String> {
+        let base_url = match &self.base_url {
+            Some(base_url) => base_url.clone(),
+            None => self
+                .token_cache_map
+                .cloud_profile(subscription_id)?
+                .resource_manager_endpoint
+                .clone(),
+        };
+        Ok(base_url.trim_end_matches('/').to_string())
+    }
+
+    // Checks that a URL ARM gave us to follow in a subscription, e.g. a nextLink or a Location header, is on the
+    // subscription's base URL.
+    pub fn check_url(&self, subscription_id: &str, url: &str) -> anyhow::Result<()> {
+        check_same_origin(&self.base_url(subscription_id)?, url)
+    }
+
+    // Forms the URL of the resource at the given path in a subscription, using the API version for its resource type,
+    // with any other query parameters given.
+    fn url(
+        &self,
+        subscription_id: &str,
+        path: &str,
+        resource_type: &str,
+        query: &[(&str, &str)],
+    ) -> anyhow::Result<String> {
+        let mut url = reqwest::Url::parse(&format!(
+            "{}{path}?api-version={}",
+            self.base_url(subscription_id)?,
+            self.api_version(resource_type)?
+        ))?;
+        if !query.is_empty() {
+            url.query_pairs_mut().extend_pairs(query);
+        }
+        Ok(url.to_string())
+    }
+
+    // Forms the URL of the resource at the given path, relative to the base URL, using the API version for its
+    // resource type, e.g. "/subscriptions/.../elasticPools/NAME?api-version=2021-05-01-preview".
+    pub fn relative_url(&self, path: &str, resource_type: &str) -> anyhow::Result<String> {
+        Ok(format!(
+            "{path}?api-version={}",
+            self.api_version(resource_type)?
+        ))
+    }
+
+    // Gets the given URL in a subscription as JSON.
+    async fn get_url<T>(&self, subscription_id: &str, url: String) -> anyhow::Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        // Get the response
+        let response = self
+            .send_request(subscription_id, reqwest::Method::GET, &url, None)
+            .await?;
+        // Get the response as JSON
+        let status = response.status();
+        response
+            .json::<T>()
+            .await
+            .map_err(|e| AzureError::unreadable(status, e).into())
+    }
+
+    // Sends a request to the given URL in a subscription, with a JSON body if one is given, and returns the
+    // response if it was successful.
+    pub async fn send_request(
+        &self,
+        subscription_id: &str,
+        method: reqwest::Method,
+        url: &str,
+        body: Option<&serde_json::Value>,
+    ) -> anyhow::Result<reqwest::Response> {
+        // Get the cloud the subscription is in
+        let cloud = self.token_cache_map.cloud_profile(subscription_id)?;
+        // Try to get a Resource Manager access token for this subscription
+        let access_token = self
+            .token_cache_map
+            .access_token(
+                subscription_id.to_string(),
+                &cloud.resource_manager_audience,
+            )
+            .await
+            .map_err(AzureError::from_token_error)?;
+        // Send the request, keeping track of how many reads the subscription has left.  Each attempt waits its turn,
+        // so fanning out over many resources doesn't flood ARM.
+        super::send_request(
+            &self.http_client,
+            method,
+            url,
+            body,
+            &access_token,
+            &self.retry_policy,
+            Some(SubscriptionLimits {
+                read_throttle: &self.read_throttle,
+                concurrency_limiter: &self.concurrency_limiter,
+                subscription_id,
+            }),
+        )
+        .await
+    }
+
+    // Gets the resource at the given path, e.g. "/subscriptions/.../elasticPools/NAME", in a subscription as JSON,
+    // using the API version for its resource type.
+    pub async fn get<T>(
+        &self,
+        subscription_id: &str,
+        path: &str,
+        resource_type: &str,
+    ) -> anyhow::Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        self.get_with_query(subscription_id, path, resource_type, &[])
+            .await
+    }
+
+    // Gets the resource at the given path in a subscription as JSON, like get, with other query parameters,
+    // e.g. [("metricnames", "storage_used")].
+    pub async fn get_with_query<T>(
+        &self,
+        subscription_id: &str,
+        path: &str,
+        resource_type: &str,
+        query: &[(&str, &str)],
+    ) -> anyhow::Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        log::debug!("ArmClient.get - path = {path}");
+        self.get_url(
+            subscription_id,
+            self.url(subscription_id, path, resource_type, query)?,
+        )
+        .await
+    }
+
+    // Lists the resources at the given path, e.g. "/subscriptions/.../databases", in a subscription, one page
+    // at a time.  Each page's "nextLink" is followed until there isn't one.
+    pub fn list_pages<'a, T>(
+        &'a self,
+        subscription_id: &'a str,
+        path: &str,
+        resource_type: &str,
+    ) -> impl futures::Stream<Item = anyhow::Result<Vec<T>>> + 'a
+    where
+        T: DeserializeOwned + 'a,
+    {
+        log::debug!("ArmClient.list_pages - path = {path}");
+        // Start with the first page's URL
+        let first_url = self.url(subscription_id, path, resource_type, &[]);
+        self.list_pages_from(subscription_id, first_url)
+    }
+
+    // Lists the resources from the page at the given URL on, one page at a time.
+    fn list_pages_from<'a, T>(
+        &'a self,
+        subscription_id: &'a str,
+        first_url: anyhow::Result<String>,
+    ) -> impl futures::Stream<Item = anyhow::Result<Vec<T>>> + 'a
+    where
+        T: DeserializeOwned + 'a,
+    {
+        futures::stream::try_unfold(
+            (Some(first_url), HashSet::new()),
+            move |(url, mut visited_urls)| async move {
+                // If there are no more pages, we're done
+                let url = match url {
+                    Some(url) => url?,
+                    None => return Ok(None),
+                };
+                // Don't go round in circles if a nextLink points back to a page we've had
+                if !visited_urls.insert(url.clone()) {
+                    return Err(AzureError::unexpected(format!(
+                        "The nextLink {url} was already visited"
+                    ))
+                    .into());
+                }
+                // Don't send the access token anywhere but ARM
+                self.check_url(subscription_id, &url)?;
+                // Get the page, and move on to the next
+                let page = self.get_url::<Page<T>>(subscription_id, url).await?;
+                log::debug!(
+                    " - got {} values, next link = {:?}",
+                    page.value.len(),
+                    page.next_link
+                );
+                Ok(Some((page.value, (page.next_link.map(Ok), visited_urls))))
+            },
+        )
+    }
+
+    // Lists all the resources at the given path in a subscription, following "nextLink"s.
+    pub async fn list<T>(
+        &self,
+        subscription_id: &str,
+        path: &str,
+        resource_type: &str,
+    ) -> anyhow::Result<Vec<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let pages = self
+            .list_pages::<T>(subscription_id, path, resource_type)
+            .try_collect::<Vec<_>>()
+            .await?;
+        Ok(pages.into_iter().flatten().collect())
+    }
+
+    // Lists all the resources in a list response's first page, which was got some other way (e.g. in a batch),
+    // and in the pages after it.
+    pub async fn list_rest<T>(
+        &self,
+        subscription_id: &str,
+        first_page: serde_json::Value,
+    ) -> anyhow::Result<Vec<T>>
+    where
+        T: DeserializeOwned,
+    {
+        let first_page = serde_json::from_value::<Page<T>>(first_page)
+            .map_err(|e| AzureError::unreadable(actix_web::http::StatusCode::OK, e))?;
+        let mut values = first_page.value;
+        // Get the pages after it, if any
+        if let Some(next_link) = first_page.next_link {
+            let pages = self
+                .list_pages_from::<T>(subscription_id, Ok(next_link))
+                .try_collect::<Vec<_>>()
+                .await?;
+            values.extend(pages.into_iter().flatten());
+        }
+        Ok(values)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls_on_the_base_url_are_allowed() {
+        let base_url = "https://management.azure.com";
+        check_same_origin(
+            base_url,
+            "https://management.azure.com/subscriptions/s/resourceGroups/rg/providers/Microsoft.Sql/servers/x/databases?api-version=2021-11-01-preview&$skiptoken=abc",
+        )
+        .unwrap();
+        // The host is case-insensitive, and the default port may be explicit
+        check_same_origin(base_url, "https://MANAGEMENT.azure.com:443/subscriptions/s").unwrap();
+        check_same_origin(
+            "http://127.0.0.1:9101/",
+            "http://127.0.0.1:9101/batchpoll/1",
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn urls_on_another_host_are_rejected() {
+        let base_url = "https://management.azure.com";
+        check_same_origin(base_url, "https://example.com/subscriptions/s").unwrap_err();
+        check_same_origin(base_url, "https://management.azure.com.example.com/").unwrap_err();
+        check_same_origin(base_url, "https://management.usgovcloudapi.net/").unwrap_err();
+    }
+
+    #[test]
+    fn urls_with_another_scheme_or_port_are_rejected() {
+        let base_url = "https://management.azure.com";
+        check_same_origin(base_url, "http://management.azure.com/subscriptions/s").unwrap_err();
+        check_same_origin(
+            base_url,
+            "https://management.azure.com:8443/subscriptions/s",
+        )
+        .unwrap_err();
+    }
+
+    #[test]
+    fn relative_or_invalid_urls_are_rejected() {
+        let base_url = "https://management.azure.com";
+        check_same_origin(base_url, "/subscriptions/s").unwrap_err();
+        check_same_origin(base_url, "not a url").unwrap_err();
+    }
+}
@@ -0,0 +1,550 @@
+use crate::azure_apis::arm_client::ArmClient;
+use crate::azure_apis::error::{AzureError, AzureErrorDetails};
+use actix_web::http;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+// The most requests sent in one batch.  Larger batches are split.
+const MAX_BATCH_SIZE: usize = 20;
+// The most times to poll for a batch's responses before giving up.
+const MAX_BATCH_POLLS: u32 = 30;
+// How long to wait between polls if ARM doesn't say.
+const DEFAULT_BATCH_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// A request in a batch.
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchRequest {
+    // The name that identifies the request's response
+    name: String,
+    // The HTTP method, e.g. "GET"
+    http_method: &'static str,
+    // The URL, relative to the Resource Manager endpoint, including the API version
+    url: String,
+}
+
+// A batch request body, e.g.
+// ```json
+// {
+//   "requests": [
+//     { "name": "...", "httpMethod": "GET", "url": "/subscriptions/.../usages?api-version=2022-02-01-preview" }
+//   ]
+// }
+// ```
+#[derive(Debug, serde::Serialize)]
+struct BatchRequestBody<'a> {
+    // The requests
+    requests: &'a [BatchRequest],
+}
+
+// The response to a request in a batch.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BatchResponse {
+    // The name of the request the response is to
+    name: String,
+    // The HTTP status, e.g. 200
+    http_status_code: u16,
+    // The response headers
+    #[serde(default)]
+    headers: HashMap<String, String>,
+    // The response body
+    #[serde(default)]
+    content: serde_json::Value,
+}
+
+// A batch response body, e.g.
+// ```json
+// {
+//   "responses": [
+//     { "name": "...", "httpStatusCode": 200, "headers": { ... }, "content": { "value": [ ... ] } }
+//   ]
+// }
+// ```
+#[derive(Debug, Deserialize)]
+struct BatchResponseBody {
+    // The responses, in no particular order
+    #[serde(default)]
+    responses: Vec<BatchResponse>,
+}
+
+// A request added to a batch, whose response is a T.
+pub struct BatchItem<T> {
+    // The request's position in the batch
+    index: usize,
+    // The response type
+    response_type: PhantomData<T>,
+}
+
+// A list request added to a batch, whose values are Ts, collected into an R.
+pub struct BatchListItem<T, R> {
+    // The request's position in the batch
+    index: usize,
+    // Makes the response from the values
+    into_response: fn(Vec<T>) -> R,
+}
+
+// A set of GET requests to a subscription to be sent to ARM in as few round trips as possible.
+pub struct Batch<'a> {
+    // The client to send the batch with
+    arm_client: &'a ArmClient,
+    // The subscription ID (a GUID)
+    subscription_id: String,
+    // The requests
+    requests: Vec<BatchRequest>,
+}
+
+impl<'a> Batch<'a> {
+    // Adds a GET request for the resource at the given path, using the API version for its resource type,
+    // and returns its position.
+    fn add(&mut self, path: &str, resource_type: &str) -> anyhow::Result<usize> {
+        self.requests.push(BatchRequest {
+            name: uuid::Uuid::new_v4().to_string(),
+            http_method: "GET",
+            url: self.arm_client.relative_url(path, resource_type)?,
+        });
+        Ok(self.requests.len() - 1)
+    }
+
+    // Adds a request for the resource at the given path, as ArmClient.get would get it.
+    pub fn get<T>(&mut self, path: &str, resource_type: &str) -> anyhow::Result<BatchItem<T>>
+    where
+        T: DeserializeOwned,
+    {
+        Ok(BatchItem {
+            index: self.add(path, resource_type)?,
+            response_type: PhantomData,
+        })
+    }
+
+    // Adds a request for the resources at the given path, as ArmClient.list would list them, which will be
+    // made into a response with into_response.
+    pub fn list<T, R>(
+        &mut self,
+        path: &str,
+        resource_type: &str,
+        into_response: fn(Vec<T>) -> R,
+    ) -> anyhow::Result<BatchListItem<T, R>>
+    where
+        T: DeserializeOwned,
+    {
+        Ok(BatchListItem {
+            index: self.add(path, resource_type)?,
+            into_response,
+        })
+    }
+
+    // The number of requests in the batch.
+    pub fn len(&self) -> usize {
+        self.requests.len()
+    }
+
+    // Whether the batch has no requests.
+    pub fn is_empty(&self) -> bool {
+        self.requests.is_empty()
+    }
+
+    // Sends some of the requests as one batch, and gets their responses by name.
+    async fn send_chunk(
+        &self,
+        requests: &[BatchRequest],
+    ) -> anyhow::Result<HashMap<String, BatchResponse>> {
+        log::debug!(" - sending a batch of {} requests", requests.len());
+        let url = format!(
+            "{}/batch?api-version={}",
+            self.arm_client.base_url(&self.subscription_id)?,
+            self.arm_client.api_version("batch")?
+        );
+        let body = serde_json::to_value(BatchRequestBody { requests })?;
+        let mut response = self
+            .arm_client
+            .send_request(
+                &self.subscription_id,
+                reqwest::Method::POST,
+                &url,
+                Some(&body),
+            )
+            .await?;
+        // If ARM is still working on some of the requests, poll for the responses until they're all done
+        let mut polls = 0;
+        while http::StatusCode::ACCEPTED == response.status() {
+            let location = response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|value| value.to_str().ok())
+                .ok_or_else(|| {
+                    AzureError::unexpected("The batch was accepted without a location to poll")
+                })?
+                .to_string();
+            // Don't send the access token anywhere but ARM
+            self.arm_client
+                .check_url(&self.subscription_id, &location)?;
+            polls += 1;
+            if MAX_BATCH_POLLS < polls {
+                return Err(AzureError::unexpected(format!(
+                    "The batch wasn't done after {MAX_BATCH_POLLS} polls"
+                ))
+                .into());
+            }
+            let poll_interval = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+                .map(Duration::from_secs)
+                .unwrap_or(DEFAULT_BATCH_POLL_INTERVAL);
+            log::debug!(" - polling {location} for the batch in {poll_interval:?}");
+            actix_web::rt::time::sleep(poll_interval).await;
+            response = self
+                .arm_client
+                .send_request(&self.subscription_id, reqwest::Method::GET, &location, None)
+                .await?;
+        }
+        // Get the responses
+        let status = response.status();
+        let body = response
+            .json::<BatchResponseBody>()
+            .await
+            .map_err(|e| AzureError::unreadable(status, e))?;
+        Ok(body
+            .responses
+            .into_iter()
+            .map(|response| (response.name.clone(), response))
+            .collect())
+    }
+
+    // Sends the requests, in batches of up to 20, and gets the responses.  If a batch fails as a whole, each of
+    // its requests gets the error.
+    pub async fn send(self) -> BatchResponses<'a> {
+        log::debug!("Batch.send - {} requests", self.requests.len());
+        // Send the batches in parallel
+        let chunks = self.requests.chunks(MAX_BATCH_SIZE).collect::<Vec<_>>();
+        let chunk_responses =
+            futures::future::join_all(chunks.iter().map(|chunk| self.send_chunk(chunk))).await;
+        // Match the responses to the requests
+        let mut responses = Vec::with_capacity(self.requests.len());
+        for (chunk, chunk_response) in chunks.iter().zip(chunk_responses) {
+            match chunk_response {
+                Ok(mut chunk_response) => {
+                    for request in chunk.iter() {
+                        responses.push(Some(match chunk_response.remove(&request.name) {
+                            Some(response) => Self::into_result(response),
+                            None => Err(AzureError::unexpected(format!(
+                                "The batch had no response to {}",
+                                request.url
+                            ))
+                            .into()),
+                        }));
+                    }
+                }
+                Err(e) => {
+                    // Give each request the error, keeping it typed if it's from Azure
+                    let azure_error = e.downcast_ref::<AzureError>();
+                    for _ in chunk.iter() {
+                        responses.push(Some(Err(match azure_error {
+                            Some(azure_error) => azure_error.clone().into(),
+                            None => anyhow::anyhow!("{e}"),
+                        })));
+                    }
+                }
+            }
+        }
+        BatchResponses {
+            arm_client: self.arm_client,
+            subscription_id: self.subscription_id,
+            responses,
+        }
+    }
+
+    // Gets the content of a successful response, or the error of a failed one.
+    fn into_result(response: BatchResponse) -> anyhow::Result<serde_json::Value> {
+        let status = http::StatusCode::from_u16(response.http_status_code)?;
+        if status.is_success() {
+            return Ok(response.content);
+        }
+        // The headers' names may be in any case
+        let request_id = response
+            .headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("x-ms-request-id"))
+            .map(|(_, value)| value.clone());
+        let error = match &response.content {
+            serde_json::Value::Null => AzureError::new(AzureErrorDetails {
+                status: Some(status),
+                message: format!("Azure returned status {status}"),
+                request_id,
+                ..Default::default()
+            }),
+            content => AzureError::from_body(status, request_id, &content.to_string()),
+        };
+        log::warn!("Error in batch: {error} (status {status})");
+        Err(error.into())
+    }
+}
+
+// The responses to a batch's requests, to be taken by the items the requests were added as.
+pub struct BatchResponses<'a> {
+    // The client the batch was sent with, for following nextLinks
+    arm_client: &'a ArmClient,
+    // The subscription ID (a GUID)
+    subscription_id: String,
+    // The responses' contents or errors, in the order the requests were added, until they're taken
+    responses: Vec<Option<anyhow::Result<serde_json::Value>>>,
+}
+
+impl BatchResponses<'_> {
+    // Takes the content of the response at the given position.
+    fn take_content(&mut self, index: usize) -> anyhow::Result<serde_json::Value> {
+        self.responses
+            .get_mut(index)
+            .and_then(Option::take)
+            .unwrap_or_else(|| Err(anyhow::anyhow!("The batch response was already taken")))
+    }
+
+    // Takes the response to a request.
+    pub fn take<T>(&mut self, item: BatchItem<T>) -> anyhow::Result<T>
+    where
+        T: DeserializeOwned,
+    {
+        let content = self.take_content(item.index)?;
+        serde_json::from_value(content)
+            .map_err(|e| AzureError::unreadable(http::StatusCode::OK, e).into())
+    }
+
+    // Takes the response to a list request, following its "nextLink"s, if any.
+    pub async fn take_list<T, R>(&mut self, item: BatchListItem<T, R>) -> anyhow::Result<R>
+    where
+        T: DeserializeOwned,
+    {
+        let content = self.take_content(item.index)?;
+        let values = self
+            .arm_client
+            .list_rest::<T>(&self.subscription_id, content)
+            .await?;
+        Ok((item.into_response)(values))
+    }
+}
+
+impl ArmClient {
+    // Starts a batch of requests to a subscription.
+    pub fn batch(&self, subscription_id: &str) -> Batch<'_> {
+        Batch {
+            arm_client: self,
+            subscription_id: subscription_id.to_string(),
+            requests: Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::azure_apis::arm_client::DatabaseId;
+    use crate::test_server::{
+        arm_client, dashboard_settings, database_usages_json, TestRequest, TestResponse, TestServer,
+    };
+    use std::sync::Mutex;
+
+    // Creates settings with one subscription, whose ARM requests go to the server.
+    fn settings(server: &TestServer) -> crate::settings::DashboardSettings {
+        dashboard_settings(
+            server,
+            serde_json::json!({
+                "subscriptions": [{
+                    "display_name": "Subscription 1",
+                    "subscription_id": "s1",
+                    "resource_groups": [],
+                }],
+            }),
+        )
+    }
+
+    // Identifies the database with the given number.
+    fn database(number: u64) -> DatabaseId {
+        DatabaseId {
+            subscription_id: "s1".to_string(),
+            resource_group_name: "rg1".to_string(),
+            server_name: "server1".to_string(),
+            database_name: format!("db{number}"),
+        }
+    }
+
+    // Answers each request in a batch with the usages of the database it's for, whose numbers are its sizes,
+    // in the reverse order.
+    fn batch_responses(request: &TestRequest) -> serde_json::Value {
+        let mut responses = request.json()["requests"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|request| {
+                let url = request["url"].as_str().unwrap();
+                let number = url
+                    .split("/databases/db")
+                    .nth(1)
+                    .and_then(|rest| rest.split('/').next())
+                    .and_then(|number| number.parse::<u64>().ok())
+                    .unwrap();
+                serde_json::json!({
+                    "name": request["name"],
+                    "httpStatusCode": 200,
+                    "content": { "value": database_usages_json(number, number * 2, 1000) },
+                })
+            })
+            .collect::<Vec<_>>();
+        responses.reverse();
+        serde_json::json!({ "responses": responses })
+    }
+
+    #[actix_web::test]
+    async fn requests_are_sent_in_chunks_and_matched_to_their_responses() {
+        let server =
+            TestServer::start_arm(|request| TestResponse::json(200, batch_responses(request)));
+        let settings = settings(&server);
+        let arm_client = arm_client(&settings);
+        let mut batch = arm_client.batch("s1");
+        let items = (1..=21)
+            .map(|number| batch.get_database_usage(&database(number)).unwrap())
+            .collect::<Vec<_>>();
+        let mut responses = batch.send().await;
+        for (number, item) in (1..=21).zip(items) {
+            let usage = responses.take_list(item).await.unwrap();
+            assert_eq!((number, number * 2, 1000), usage.get_sizes());
+        }
+        // The 21 requests were sent as batches of 20 and 1
+        let mut batch_sizes = server
+            .requests()
+            .iter()
+            .filter(|request| request.path.starts_with("/batch?"))
+            .map(|request| request.json()["requests"].as_array().unwrap().len())
+            .collect::<Vec<_>>();
+        batch_sizes.sort();
+        assert_eq!(vec![1, 20], batch_sizes);
+    }
+
+    #[actix_web::test]
+    async fn accepted_batch_is_polled_until_done() {
+        // The batch is accepted, and its responses are ready at the first poll
+        let batch_request = Mutex::new(None);
+        let server = TestServer::start_arm(move |request| {
+            if "POST" == request.method {
+                *batch_request.lock().unwrap() = Some(request.clone());
+                let location = format!(
+                    "http://{}/batch/operation?api-version=2020-06-01",
+                    request.header("host").unwrap()
+                );
+                TestResponse::new(202)
+                    .header("Location", &location)
+                    .header("Retry-After", "0")
+            } else {
+                let batch_request = batch_request.lock().unwrap().clone().unwrap();
+                TestResponse::json(200, batch_responses(&batch_request))
+            }
+        });
+        let settings = settings(&server);
+        let arm_client = arm_client(&settings);
+        let mut batch = arm_client.batch("s1");
+        let item = batch.get_database_usage(&database(7)).unwrap();
+        let mut responses = batch.send().await;
+        let usage = responses.take_list(item).await.unwrap();
+        assert_eq!((7, 14, 1000), usage.get_sizes());
+        assert!(
+            server
+                .requests()
+                .iter()
+                .any(|request| "GET" == request.method
+                    && request.path.starts_with("/batch/operation"))
+        );
+    }
+
+    #[actix_web::test]
+    async fn location_elsewhere_is_not_polled() {
+        let server = TestServer::start_arm(|_| {
+            TestResponse::new(202).header("Location", "https://example.com/batch/operation")
+        });
+        let settings = settings(&server);
+        let arm_client = arm_client(&settings);
+        let mut batch = arm_client.batch("s1");
+        let item = batch.get_database_usage(&database(1)).unwrap();
+        let mut responses = batch.send().await;
+        let error = responses.take_list(item).await.unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<AzureError>(),
+            Some(AzureError::Upstream(_))
+        ));
+    }
+
+    #[actix_web::test]
+    async fn failed_batch_gives_each_request_its_azure_error() {
+        let server = TestServer::start_arm(|_| {
+            TestResponse::json(
+                403,
+                serde_json::json!({ "error": { "code": "AuthorizationFailed", "message": "No" } }),
+            )
+        });
+        let settings = settings(&server);
+        let arm_client = arm_client(&settings);
+        let mut batch = arm_client.batch("s1");
+        let items = (1..=3)
+            .map(|number| batch.get_database_usage(&database(number)).unwrap())
+            .collect::<Vec<_>>();
+        let mut responses = batch.send().await;
+        for item in items {
+            let error = responses.take_list(item).await.unwrap_err();
+            match error.downcast_ref::<AzureError>() {
+                Some(AzureError::Forbidden(details)) => {
+                    assert_eq!(Some("AuthorizationFailed"), details.code.as_deref())
+                }
+                _ => panic!("Expected a Forbidden error, got {error:?}"),
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn missing_resource_in_a_batch_is_not_found() {
+        // The second database doesn't exist
+        let server = TestServer::start_arm(|request| {
+            let mut body = batch_responses(request);
+            for response in body["responses"].as_array_mut().unwrap() {
+                let is_second = response["content"]["value"][0]["properties"]["currentValue"] == 2;
+                if is_second {
+                    response["httpStatusCode"] = 404.into();
+                    response["headers"] = serde_json::json!({ "X-MS-Request-Id": "r2" });
+                    response["content"] = serde_json::json!({
+                        "error": { "code": "ResourceNotFound", "message": "Not found" },
+                    });
+                }
+            }
+            TestResponse::json(200, body)
+        });
+        let settings = settings(&server);
+        let arm_client = arm_client(&settings);
+        let mut batch = arm_client.batch("s1");
+        let items = (1..=3)
+            .map(|number| batch.get_database_usage(&database(number)).unwrap())
+            .collect::<Vec<_>>();
+        let mut responses = batch.send().await;
+        let mut results = Vec::new();
+        for item in items {
+            results.push(responses.take_list(item).await);
+        }
+        assert!(results[0].is_ok());
+        match results[1]
+            .as_ref()
+            .unwrap_err()
+            .downcast_ref::<AzureError>()
+        {
+            Some(AzureError::NotFound(details)) => {
+                assert_eq!(Some("ResourceNotFound"), details.code.as_deref());
+                assert_eq!(Some("r2"), details.request_id.as_deref());
+            }
+            _ => panic!(
+                "Expected a NotFound error, got {:?}",
+                results[1].as_ref().err()
+            ),
+        }
+        assert!(results[2].is_ok());
+    }
+}
@@ -0,0 +1,312 @@
+use crate::settings::ConcurrencySettings;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+// How long requests have waited for their turn, and how many are waiting and running.
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueueMetrics {
+    // The number of requests made since startup
+    pub requests: u64,
+    // The number of requests waiting for their turn now
+    pub waiting: u64,
+    // The number of requests running now
+    pub in_flight: u64,
+    // The average time requests waited for their turn, in milliseconds
+    pub average_queue_time_ms: f64,
+    // The longest time a request waited for its turn, in milliseconds
+    pub max_queue_time_ms: u64,
+}
+
+// The concurrency limits, and how requests have queued because of them.
+#[derive(Clone, Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConcurrencyMetrics {
+    // The most requests that can run at once
+    pub max_concurrent_requests: usize,
+    // The most requests to one subscription that can run at once
+    pub max_concurrent_requests_per_subscription: usize,
+    // The queueing of all requests
+    pub all: QueueMetrics,
+    // The queueing of requests to each subscription, by lower-case subscription ID
+    pub subscriptions: BTreeMap<String, QueueMetrics>,
+}
+
+// The running totals behind the queue metrics.
+#[derive(Debug, Default)]
+struct QueueStats {
+    // The number of requests made
+    requests: u64,
+    // The number of requests waiting
+    waiting: u64,
+    // The number of requests running
+    in_flight: u64,
+    // The number of requests that got their turn
+    started: u64,
+    // The total time requests that got their turn waited for it
+    total_queue_time: Duration,
+    // The longest time a request waited for its turn
+    max_queue_time: Duration,
+}
+
+impl QueueStats {
+    // Gets the metrics from the totals.
+    fn metrics(&self) -> QueueMetrics {
+        QueueMetrics {
+            requests: self.requests,
+            waiting: self.waiting,
+            in_flight: self.in_flight,
+            average_queue_time_ms: if 0 < self.started {
+                self.total_queue_time.as_secs_f64() * 1000.0 / self.started as f64
+            } else {
+                0.0
+            },
+            max_queue_time_ms: self.max_queue_time.as_millis() as u64,
+        }
+    }
+}
+
+// The running totals for all requests, and for each subscription's.
+#[derive(Debug, Default)]
+struct Stats {
+    // The totals for all requests
+    all: QueueStats,
+    // The totals for each subscription's requests, by lower-case subscription ID
+    subscriptions: HashMap<String, QueueStats>,
+}
+
+impl Stats {
+    // Updates the totals for all requests and the given subscription's.
+    fn update(&mut self, subscription_key: &str, update: impl Fn(&mut QueueStats)) {
+        update(&mut self.all);
+        update(
+            self.subscriptions
+                .entry(subscription_key.to_string())
+                .or_default(),
+        );
+    }
+}
+
+// Permission to make a request, given by the ConcurrencyLimiter.  The request's turn ends when it's dropped.
+pub struct ConcurrencyPermit {
+    // The limiter's running totals
+    stats: Arc<Mutex<Stats>>,
+    // The lower-case ID of the subscription the request is to
+    subscription_key: String,
+    // The permits from the subscription's and the global semaphore, once the request has its turn
+    permits: Option<(OwnedSemaphorePermit, OwnedSemaphorePermit)>,
+}
+
+impl Drop for ConcurrencyPermit {
+    fn drop(&mut self) {
+        // The request either finished, or gave up waiting for its turn
+        let running = self.permits.is_some();
+        self.stats
+            .lock()
+            .unwrap()
+            .update(&self.subscription_key, |stats| {
+                if running {
+                    stats.in_flight -= 1;
+                } else {
+                    stats.waiting -= 1;
+                }
+            });
+    }
+}
+
+// Limits how many Azure requests can run at once, both in all and to each subscription, so that fanning out
+// over many resources doesn't trip Azure's throttling.  Requests over the limits wait their turn.
+pub struct ConcurrencyLimiter {
+    // The most requests to one subscription that can run at once
+    max_concurrent_requests_per_subscription: usize,
+    // The most requests that can run at once
+    max_concurrent_requests: usize,
+    // The permits for all requests
+    global: Arc<Semaphore>,
+    // The permits for each subscription's requests, by lower-case subscription ID
+    subscriptions: Mutex<HashMap<String, Arc<Semaphore>>>,
+    // The running totals behind the metrics
+    stats: Arc<Mutex<Stats>>,
+}
+
+impl ConcurrencyLimiter {
+    // Creates the limiter from the settings.  Limits of 0 are taken to be 1.
+    pub fn new(settings: &ConcurrencySettings) -> Self {
+        let max_concurrent_requests = settings.max_concurrent_requests.max(1);
+        ConcurrencyLimiter {
+            max_concurrent_requests_per_subscription: settings
+                .max_concurrent_requests_per_subscription
+                .max(1),
+            max_concurrent_requests,
+            global: Arc::new(Semaphore::new(max_concurrent_requests)),
+            subscriptions: Mutex::new(HashMap::new()),
+            stats: Arc::new(Mutex::new(Stats::default())),
+        }
+    }
+
+    // Waits for a request to the given subscription to have its turn.
+    pub async fn acquire(&self, subscription_id: &str) -> ConcurrencyPermit {
+        let subscription_key = subscription_id.to_ascii_lowercase();
+        // Get the subscription's semaphore, creating it if this is its first request
+        let subscription_semaphore = self
+            .subscriptions
+            .lock()
+            .unwrap()
+            .entry(subscription_key.clone())
+            .or_insert_with(|| {
+                Arc::new(Semaphore::new(
+                    self.max_concurrent_requests_per_subscription,
+                ))
+            })
+            .clone();
+        // Join the queue
+        self.stats
+            .lock()
+            .unwrap()
+            .update(&subscription_key, |stats| {
+                stats.requests += 1;
+                stats.waiting += 1;
+            });
+        let mut permit = ConcurrencyPermit {
+            stats: self.stats.clone(),
+            subscription_key,
+            permits: None,
+        };
+        let queued_at = Instant::now();
+        // Wait for the subscription's turn first, so requests to a busy subscription don't hold up others
+        let subscription_permit = subscription_semaphore
+            .acquire_owned()
+            .await
+            .expect("The semaphore is never closed");
+        let global_permit = self
+            .global
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("The semaphore is never closed");
+        // Note how long we waited
+        let queue_time = queued_at.elapsed();
+        log::debug!(" - waited {queue_time:?} for a request to subscription {subscription_id}");
+        self.stats
+            .lock()
+            .unwrap()
+            .update(&permit.subscription_key, |stats| {
+                stats.waiting -= 1;
+                stats.in_flight += 1;
+                stats.started += 1;
+                stats.total_queue_time += queue_time;
+                stats.max_queue_time = stats.max_queue_time.max(queue_time);
+            });
+        permit.permits = Some((subscription_permit, global_permit));
+        permit
+    }
+
+    // Gets the limits, and how requests have queued because of them.
+    pub fn metrics(&self) -> ConcurrencyMetrics {
+        let stats = self.stats.lock().unwrap();
+        ConcurrencyMetrics {
+            max_concurrent_requests: self.max_concurrent_requests,
+            max_concurrent_requests_per_subscription: self.max_concurrent_requests_per_subscription,
+            all: stats.all.metrics(),
+            subscriptions: stats
+                .subscriptions
+                .iter()
+                .map(|(subscription_key, stats)| (subscription_key.clone(), stats.metrics()))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    // Creates a limiter with the given limits.
+    fn limiter(
+        max_concurrent_requests: usize,
+        max_concurrent_requests_per_subscription: usize,
+    ) -> ConcurrencyLimiter {
+        ConcurrencyLimiter::new(&ConcurrencySettings {
+            max_concurrent_requests,
+            max_concurrent_requests_per_subscription,
+        })
+    }
+
+    #[test]
+    fn subscription_limit_holds_up_only_its_own_requests() {
+        let limiter = limiter(3, 1);
+        let first = limiter.acquire("s1").now_or_never().unwrap();
+        // Another request to the subscription, in any case, waits
+        assert!(limiter.acquire("S1").now_or_never().is_none());
+        // ...but one to another subscription doesn't
+        let other = limiter.acquire("s2").now_or_never().unwrap();
+        // Once the first request is done, the subscription's next one runs
+        drop(first);
+        let next = limiter.acquire("s1").now_or_never().unwrap();
+        drop((other, next));
+        assert_eq!(0, limiter.metrics().all.in_flight);
+        assert_eq!(0, limiter.metrics().all.waiting);
+    }
+
+    #[test]
+    fn global_limit_holds_up_all_requests() {
+        let limiter = limiter(2, 2);
+        let first = limiter.acquire("s1").now_or_never().unwrap();
+        let _second = limiter.acquire("s2").now_or_never().unwrap();
+        assert!(limiter.acquire("s3").now_or_never().is_none());
+        drop(first);
+        assert!(limiter.acquire("s3").now_or_never().is_some());
+    }
+
+    #[test]
+    fn limits_of_zero_are_taken_to_be_one() {
+        let limiter = limiter(0, 0);
+        assert_eq!(1, limiter.metrics().max_concurrent_requests);
+        assert_eq!(
+            1,
+            limiter.metrics().max_concurrent_requests_per_subscription
+        );
+        let _first = limiter.acquire("s1").now_or_never().unwrap();
+        assert!(limiter.acquire("s1").now_or_never().is_none());
+    }
+
+    #[actix_web::test]
+    async fn queue_time_and_requests_are_measured() {
+        let limiter = limiter(2, 1);
+        let first = limiter.acquire("S1").await;
+        // The second request waits for the first to finish
+        let second = async {
+            let _permit = limiter.acquire("s1").await;
+        };
+        let finish_first = async {
+            actix_web::rt::time::sleep(Duration::from_millis(200)).await;
+            let metrics = limiter.metrics();
+            assert_eq!(2, metrics.all.requests);
+            assert_eq!(1, metrics.all.waiting);
+            assert_eq!(1, metrics.all.in_flight);
+            drop(first);
+        };
+        futures::join!(second, finish_first);
+        // A request to another subscription doesn't wait
+        drop(limiter.acquire("s2").await);
+        let metrics = limiter.metrics();
+        assert_eq!(3, metrics.all.requests);
+        assert_eq!(0, metrics.all.waiting);
+        assert_eq!(0, metrics.all.in_flight);
+        let s1 = &metrics.subscriptions["s1"];
+        assert_eq!(2, s1.requests);
+        assert!(s1.max_queue_time_ms >= 200, "{s1:?}");
+        // Only the second request waited
+        assert!(
+            (s1.average_queue_time_ms - s1.max_queue_time_ms as f64 / 2.0).abs() < 5.0,
+            "{s1:?}"
+        );
+        let s2 = &metrics.subscriptions["s2"];
+        assert_eq!(1, s2.requests);
+        assert!(s2.max_queue_time_ms < 100, "{s2:?}");
+        assert_eq!(s1.max_queue_time_ms, metrics.all.max_queue_time_ms);
+    }
+}
@@ -0,0 +1,343 @@
+use crate::azure_token_cache::is_token_request_failure;
+use actix_web::http;
+use serde::Deserialize;
+
+// The longest part of a non-JSON error body that's logged.
+const MAX_LOGGED_BODY_LENGTH: usize = 500;
+
+// The error in an Azure error response.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AzureErrorBody {
+    // The error code, e.g. "InvalidAuthenticationTokenTenant"
+    code: String,
+    // The error message
+    message: String,
+    // The part of the request the error is about, if any
+    #[serde(default)]
+    target: Option<String>,
+}
+
+// An Azure error response, e.g.
+// ```json
+// {
+//   "error": {
+//     "code": "ResourceNotFound",
+//     "message": "The Resource 'Microsoft.Sql/servers/x/databases/y' under resource group 'z' was not found.",
+//     "target": null
+//   }
+// }
+// ```
+// A few APIs return the error without the wrapper.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum AzureErrorResponse {
+    // The error, wrapped in "error"
+    Wrapped { error: AzureErrorBody },
+    // The error on its own
+    Bare(AzureErrorBody),
+}
+
+// The details of a failed Azure API call.
+#[derive(Clone, Debug, Default)]
+pub struct AzureErrorDetails {
+    // The status Azure returned, if it returned one
+    pub status: Option<http::StatusCode>,
+    // The error code, e.g. "ResourceNotFound", if Azure returned one
+    pub code: Option<String>,
+    // The error message
+    pub message: String,
+    // The part of the request the error is about, if any
+    pub target: Option<String>,
+    // The ID Azure gave the request (the x-ms-request-id or request-id header), for support requests
+    pub request_id: Option<String>,
+}
+
+impl std::fmt::Display for AzureErrorDetails {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.code {
+            Some(code) => write!(f, "{code}: {}", self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+// A failed Azure API call, by what it means for the dashboard.
+#[derive(Clone, Debug, thiserror::Error)]
+pub enum AzureError {
+    // The resource, or its resource group or subscription, doesn't exist
+    #[error("{0}")]
+    NotFound(AzureErrorDetails),
+    // We're not allowed to read the resource
+    #[error("{0}")]
+    Forbidden(AzureErrorDetails),
+    // Azure is throttling our requests
+    #[error("{0}")]
+    Throttled(AzureErrorDetails),
+    // Azure failed, returned something we didn't understand, or couldn't be reached
+    #[error("{0}")]
+    Upstream(AzureErrorDetails),
+}
+
+impl AzureError {
+    // Classifies a failed call by its error code, e.g. "ResourceGroupNotFound", "LinkedAuthorizationFailed", or
+    // "SubscriptionRequestsThrottled", or failing that, its status.
+    pub fn new(details: AzureErrorDetails) -> Self {
+        let code = details.code.as_deref().unwrap_or_default();
+        let status = details.status;
+        if code.ends_with("NotFound") || status == Some(http::StatusCode::NOT_FOUND) {
+            AzureError::NotFound(details)
+        } else if code.ends_with("AuthorizationFailed")
+            || status == Some(http::StatusCode::FORBIDDEN)
+        {
+            AzureError::Forbidden(details)
+        } else if code.contains("Throttled")
+            || code == "TooManyRequests"
+            || status == Some(http::StatusCode::TOO_MANY_REQUESTS)
+        {
+            AzureError::Throttled(details)
+        } else {
+            AzureError::Upstream(details)
+        }
+    }
+
+    // Creates the error for a call that got no response, e.g. because of a network error.
+    pub fn unreachable(error: impl std::fmt::Display) -> Self {
+        AzureError::Upstream(AzureErrorDetails {
+            message: format!("Could not call Azure: {error}"),
+            ..Default::default()
+        })
+    }
+
+    // Creates the error for an access token that couldn't be had because the token endpoint couldn't be reached or
+    // refused the request.  Other failures, e.g. a secret that couldn't be resolved, are returned as they are.
+    pub fn from_token_error(error: anyhow::Error) -> anyhow::Error {
+        if !is_token_request_failure(&error) {
+            return error;
+        }
+        AzureError::Upstream(AzureErrorDetails {
+            message: format!("Could not get an access token: {error}"),
+            ..Default::default()
+        })
+        .into()
+    }
+
+    // Creates the error for a response that wasn't what we expected, e.g. a nextLink to another host.
+    pub fn unexpected(message: impl std::fmt::Display) -> Self {
+        AzureError::Upstream(AzureErrorDetails {
+            message: message.to_string(),
+            ..Default::default()
+        })
+    }
+
+    // Creates the error for a successful response that couldn't be read.
+    pub fn unreadable(status: http::StatusCode, error: impl std::fmt::Display) -> Self {
+        AzureError::Upstream(AzureErrorDetails {
+            status: Some(status),
+            message: format!("Could not read the Azure response: {error}"),
+            ..Default::default()
+        })
+    }
+
+    // Creates the error from an error response.  The body may not be JSON, e.g. a gateway's HTML error page.
+    pub async fn from_response(response: reqwest::Response) -> Self {
+        let status = response.status();
+        // Get the request ID: ARM's header, or Microsoft Graph's
+        let request_id = ["x-ms-request-id", "request-id"].iter().find_map(|name| {
+            response
+                .headers()
+                .get(*name)
+                .and_then(|value| value.to_str().ok())
+                .map(String::from)
+        });
+        // Get the body as text, and try to read the error from it
+        match response.text().await {
+            Ok(text) => AzureError::from_body(status, request_id, &text),
+            Err(e) => AzureError::new(AzureErrorDetails {
+                status: Some(status),
+                message: format!(
+                    "Azure returned status {status}, and the body couldn't be read: {e}"
+                ),
+                request_id,
+                ..Default::default()
+            }),
+        }
+    }
+
+    // Creates the error from an error response's status, request ID, and body, which may not be JSON.
+    pub fn from_body(status: http::StatusCode, request_id: Option<String>, text: &str) -> Self {
+        let details = match serde_json::from_str::<AzureErrorResponse>(text) {
+            Ok(AzureErrorResponse::Wrapped { error }) | Ok(AzureErrorResponse::Bare(error)) => {
+                AzureErrorDetails {
+                    status: Some(status),
+                    code: Some(error.code),
+                    message: error.message,
+                    target: error.target,
+                    request_id,
+                }
+            }
+            Err(_) => {
+                log::debug!(
+                    " - non-JSON error body: {}",
+                    text.chars()
+                        .take(MAX_LOGGED_BODY_LENGTH)
+                        .collect::<String>()
+                );
+                AzureErrorDetails {
+                    status: Some(status),
+                    message: format!("Azure returned status {status}"),
+                    request_id,
+                    ..Default::default()
+                }
+            }
+        };
+        AzureError::new(details)
+    }
+
+    // Gets the details of the failed call.
+    pub fn details(&self) -> &AzureErrorDetails {
+        match self {
+            AzureError::NotFound(details)
+            | AzureError::Forbidden(details)
+            | AzureError::Throttled(details)
+            | AzureError::Upstream(details) => details,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::azure_token_cache::TokenRequestError;
+
+    // Classifies an error with the given code and status.
+    fn classify(code: Option<&str>, status: Option<u16>) -> AzureError {
+        AzureError::new(AzureErrorDetails {
+            status: status.map(|s| http::StatusCode::from_u16(s).unwrap()),
+            code: code.map(String::from),
+            message: "message".to_string(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn errors_are_classified_by_code() {
+        for code in [
+            "ResourceNotFound",
+            "ResourceGroupNotFound",
+            "SubscriptionNotFound",
+        ] {
+            assert!(
+                matches!(classify(Some(code), Some(400)), AzureError::NotFound(_)),
+                "{code}"
+            );
+        }
+        for code in ["AuthorizationFailed", "LinkedAuthorizationFailed"] {
+            assert!(
+                matches!(classify(Some(code), Some(400)), AzureError::Forbidden(_)),
+                "{code}"
+            );
+        }
+        for code in ["SubscriptionRequestsThrottled", "TooManyRequests"] {
+            assert!(
+                matches!(classify(Some(code), Some(400)), AzureError::Throttled(_)),
+                "{code}"
+            );
+        }
+        assert!(matches!(
+            classify(Some("InternalServerError"), Some(400)),
+            AzureError::Upstream(_)
+        ));
+    }
+
+    #[test]
+    fn errors_without_a_known_code_are_classified_by_status() {
+        assert!(matches!(classify(None, Some(404)), AzureError::NotFound(_)));
+        assert!(matches!(
+            classify(None, Some(403)),
+            AzureError::Forbidden(_)
+        ));
+        assert!(matches!(
+            classify(None, Some(429)),
+            AzureError::Throttled(_)
+        ));
+        assert!(matches!(
+            classify(Some("InvalidRequest"), Some(403)),
+            AzureError::Forbidden(_)
+        ));
+        for status in [400, 401, 500, 502, 503] {
+            assert!(
+                matches!(classify(None, Some(status)), AzureError::Upstream(_)),
+                "{status}"
+            );
+        }
+        assert!(matches!(classify(None, None), AzureError::Upstream(_)));
+    }
+
+    #[test]
+    fn error_body_is_read() {
+        let body = r#"{"error":{"code":"ResourceNotFound","message":"The Resource 'x' was not found.","target":"x"}}"#;
+        let error = AzureError::from_body(
+            http::StatusCode::NOT_FOUND,
+            Some("request".to_string()),
+            body,
+        );
+        assert!(matches!(error, AzureError::NotFound(_)));
+        let details = error.details();
+        assert_eq!(Some(http::StatusCode::NOT_FOUND), details.status);
+        assert_eq!(Some("ResourceNotFound"), details.code.as_deref());
+        assert_eq!("The Resource 'x' was not found.", details.message);
+        assert_eq!(Some("x"), details.target.as_deref());
+        assert_eq!(Some("request"), details.request_id.as_deref());
+        // A few APIs leave out the wrapper
+        let body = r#"{"code":"AuthorizationFailed","message":"No."}"#;
+        let error = AzureError::from_body(http::StatusCode::FORBIDDEN, None, body);
+        assert!(matches!(error, AzureError::Forbidden(_)));
+        assert_eq!(Some("AuthorizationFailed"), error.details().code.as_deref());
+    }
+
+    #[test]
+    fn html_error_body_is_described_by_its_status() {
+        let body =
+            "<html><head><title>502 Bad Gateway</title></head><body>Bad Gateway</body></html>";
+        let error = AzureError::from_body(
+            http::StatusCode::BAD_GATEWAY,
+            Some("request".to_string()),
+            body,
+        );
+        assert!(matches!(error, AzureError::Upstream(_)));
+        let details = error.details();
+        assert_eq!(Some(http::StatusCode::BAD_GATEWAY), details.status);
+        assert_eq!(None, details.code);
+        assert_eq!("Azure returned status 502 Bad Gateway", details.message);
+        assert_eq!(Some("request"), details.request_id.as_deref());
+        // Its status still classifies it
+        let error = AzureError::from_body(http::StatusCode::NOT_FOUND, None, body);
+        assert!(matches!(error, AzureError::NotFound(_)));
+    }
+
+    #[test]
+    fn refused_token_request_is_an_upstream_error() {
+        let error = AzureError::from_token_error(
+            TokenRequestError {
+                status: reqwest::StatusCode::UNAUTHORIZED,
+                error: Some("invalid_client".to_string()),
+                error_codes: vec![7000215],
+                body: String::new(),
+            }
+            .into(),
+        );
+        assert!(matches!(
+            error.downcast_ref::<AzureError>(),
+            Some(AzureError::Upstream(_))
+        ));
+    }
+
+    #[test]
+    fn credential_problem_is_not_an_azure_error() {
+        let error =
+            AzureError::from_token_error(anyhow::anyhow!("Could not resolve a secret: nope"));
+        assert!(error.downcast_ref::<AzureError>().is_none());
+        assert_eq!("Could not resolve a secret: nope", error.to_string());
+    }
+}
@@ -0,0 +1,74 @@
+use crate::azure_apis::error::AzureError;
+use crate::azure_apis::retry::RetryPolicy;
+use crate::AccessTokenCacheMap;
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+
+// A client secret of an app registration.  Graph never returns the secret itself.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PasswordCredential {
+    // The secret ID (a GUID)
+    pub key_id: String,
+    // The secret description
+    pub display_name: Option<String>,
+    // The first few characters of the secret
+    pub hint: Option<String>,
+    // When the secret expires
+    pub end_date_time: Option<DateTime<Utc>>,
+}
+
+// An app registration, e.g.
+// ```json
+// {
+//   "displayName": "azure-dashboard",
+//   "passwordCredentials": [
+//     {
+//       "keyId": "...",
+//       "displayName": "dashboard",
+//       "hint": "abc",
+//       "endDateTime": "2024-01-01T00:00:00Z"
+//     }
+//   ]
+// }
+// ```
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Application {
+    // The app registration's name
+    pub display_name: Option<String>,
+    // The client secrets
+    #[serde(default)]
+    pub password_credentials: Vec<PasswordCredential>,
+}
+
+// Gets the app registration with the given client ID from Microsoft Graph, using the given subscription's credential.
+// The app needs permission to read it, e.g. the Application.Read.All application permission, or to own it.
+pub async fn get_application(
+    http_client: &reqwest::Client,
+    retry_policy: &RetryPolicy,
+    token_cache_map: &AccessTokenCacheMap,
+    subscription_id: String,
+    graph_endpoint: Option<&str>,
+    client_id: &str,
+) -> anyhow::Result<Application> {
+    log::debug!("get_application - client_id = {client_id}");
+    // Get the cloud the subscription is in
+    let cloud = token_cache_map.cloud_profile(&subscription_id)?;
+    // Try to get a Graph access token for this subscription
+    let access_token = token_cache_map
+        .access_token(subscription_id.clone(), &cloud.graph_audience)
+        .await
+        .map_err(AzureError::from_token_error)?;
+    // Get the URL, using the configured endpoint if there is one
+    let graph_endpoint = graph_endpoint
+        .unwrap_or(&cloud.graph_endpoint)
+        .trim_end_matches('/');
+    let url = format!(
+        "{graph_endpoint}\
+        /v1.0/applications(appId='{client_id}')\
+        ?$select=displayName,passwordCredentials"
+    );
+    // Get the response as JSON
+    super::get_json::<Application>(http_client, url, access_token, retry_policy, None).await
+}
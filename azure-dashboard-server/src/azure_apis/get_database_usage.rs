@@ -1,4 +1,6 @@
-use crate::{AccessTokenCacheMap, AzureDashboardError};
+use crate::azure_apis::arm_client::{ArmClient, DatabaseId};
+use crate::azure_apis::batch::{Batch, BatchListItem};
+use crate::AzureDashboardError;
 use actix_web::http::StatusCode;
 
 use serde::{Deserialize, Serialize};
@@ -105,34 +107,34 @@ impl DatabaseUsageResponse {
     }
 }
 
-// Calls the Azure API to get the usage for the given database.
-pub async fn get_database_usage(
-    http_client: &reqwest::Client,
-    token_cache_map: &AccessTokenCacheMap,
-    subscription_id: String,
-    resource_group_name: String,
-    server_name: String,
-    database_name: String,
-) -> anyhow::Result<DatabaseUsageResponse> {
-    log::debug!("get_database_usage");
-    log::debug!(" - getting access token");
-    // Try to get an access token for this subscription
-    let access_token = token_cache_map
-        .access_token(subscription_id.clone())
-        .await?;
-    log::debug!(" - got access token");
-    // Call the azure API for the database
-    let url = format!(
-        "https://management.azure.com\
-        /subscriptions/{subscription_id}\
-        /resourceGroups/{resource_group_name}\
-        /providers/Microsoft.Sql\
-        /servers/{server_name}\
-        /databases/{database_name}\
-        /usages\
-        ?api-version=2022-02-01-preview"
-    );
-    // Get the result from JSON
-    log::debug!(" - getting result from JSON");
-    super::get_json::<DatabaseUsageResponse>(http_client, url, access_token).await
+impl ArmClient {
+    // Calls the Azure API to get the usage for the given database.
+    pub async fn get_database_usage(
+        &self,
+        database: &DatabaseId,
+    ) -> anyhow::Result<DatabaseUsageResponse> {
+        log::debug!("get_database_usage - database = {database:?}");
+        let values = self
+            .list(
+                &database.subscription_id,
+                &format!("{}/usages", database.path()),
+                "Microsoft.Sql/servers/databases/usages",
+            )
+            .await?;
+        Ok(DatabaseUsageResponse { values })
+    }
+}
+
+impl Batch<'_> {
+    // Adds a request for the usage for the given database to the batch.
+    pub fn get_database_usage(
+        &mut self,
+        database: &DatabaseId,
+    ) -> anyhow::Result<BatchListItem<DatabaseUsage, DatabaseUsageResponse>> {
+        self.list(
+            &format!("{}/usages", database.path()),
+            "Microsoft.Sql/servers/databases/usages",
+            |values| DatabaseUsageResponse { values },
+        )
+    }
 }
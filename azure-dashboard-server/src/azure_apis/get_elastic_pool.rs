@@ -1,4 +1,7 @@
-use crate::{AccessTokenCacheMap, AzureDashboardError};
+use crate::azure_apis::arm_client::{ArmClient, ElasticPoolId};
+use crate::azure_apis::batch::{Batch, BatchItem};
+use crate::azure_apis::error::AzureError;
+use crate::AzureDashboardError;
 use actix_web::http;
 use chrono::{DateTime, Utc};
 use reqwest::StatusCode;
@@ -61,32 +64,71 @@ pub struct ElasticPool {
     pub pool_type: String,
 }
 
-pub async fn get_elastic_pool(
-    http_client: &reqwest::Client,
-    token_cache_map: &AccessTokenCacheMap,
-    subscription_id: String,
-    resource_group_name: String,
-    server_name: String,
-    elastic_pool_name: String,
-) -> anyhow::Result<ElasticPool> {
-    log::debug!("get_elastic_pool");
-    log::debug!(" - getting access token");
-    // Try to get an access token for this subscription
-    let access_token = token_cache_map
-        .access_token(subscription_id.clone())
-        .await?;
-    log::debug!(" - got access token");
-    // Get the URL
-    let url = format!(
-        "https://management.azure.com\
-        /subscriptions/{subscription_id}\
-        /resourceGroups/{resource_group_name}\
-        /providers/Microsoft.Sql\
-        /servers/{server_name}\
-        /elasticPools/{elastic_pool_name}\
-        ?api-version=2021-05-01-preview"
-    );
-    // Get the response as JSON
-    log::debug!(" - getting response from JSON");
-    super::get_json::<ElasticPool>(http_client, url, access_token).await
+// The storage an elastic pool uses, according to its Azure Monitor metrics.
+#[derive(Debug)]
+pub struct ElasticPoolStorage {
+    // The amount of data used, from the "storage_used" metric
+    pub used: u64,
+    // The amount of data allocated, from the "allocated_data_storage" metric
+    pub allocated: u64,
+}
+
+impl ArmClient {
+    // Gets an elastic pool.
+    pub async fn get_elastic_pool(
+        &self,
+        elastic_pool: &ElasticPoolId,
+    ) -> anyhow::Result<ElasticPool> {
+        log::debug!("get_elastic_pool - elastic_pool = {elastic_pool:?}");
+        self.get(
+            &elastic_pool.subscription_id,
+            &elastic_pool.path(),
+            "Microsoft.Sql/servers/elasticPools",
+        )
+        .await
+    }
+
+    // Gets the storage an elastic pool uses from its latest Azure Monitor metrics, in one call.  Fails if either
+    // metric has no recent value.
+    pub async fn get_elastic_pool_storage(
+        &self,
+        elastic_pool: &ElasticPoolId,
+    ) -> anyhow::Result<ElasticPoolStorage> {
+        log::debug!("get_elastic_pool_storage - elastic_pool = {elastic_pool:?}");
+        // The metrics are emitted every few minutes, so look at the last half hour
+        let metrics_response = self
+            .get_metrics(
+                &elastic_pool.subscription_id,
+                &elastic_pool.path(),
+                &["storage_used", "allocated_data_storage"],
+                "Maximum",
+                chrono::Duration::minutes(30),
+                "PT5M",
+            )
+            .await?;
+        // Get the latest value of a metric
+        let latest = |name: &str| {
+            metrics_response
+                .find_metric(name)
+                .and_then(|metric| metric.latest(|value| value.maximum))
+                .map(|value| value.round() as u64)
+                .ok_or_else(|| {
+                    AzureError::unexpected(format!("The {name} metric has no recent value"))
+                })
+        };
+        Ok(ElasticPoolStorage {
+            used: latest("storage_used")?,
+            allocated: latest("allocated_data_storage")?,
+        })
+    }
+}
+
+impl Batch<'_> {
+    // Adds a request for an elastic pool to the batch.
+    pub fn get_elastic_pool(
+        &mut self,
+        elastic_pool: &ElasticPoolId,
+    ) -> anyhow::Result<BatchItem<ElasticPool>> {
+        self.get(&elastic_pool.path(), "Microsoft.Sql/servers/elasticPools")
+    }
 }
@@ -0,0 +1,131 @@
+use crate::azure_apis::arm_client::ArmClient;
+use chrono::{DateTime, Duration, SecondsFormat, Utc};
+use serde::Deserialize;
+
+// A metric's name.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricName {
+    // The name, e.g. "storage_used"
+    pub value: String,
+    // The display name, e.g. "Data space used"
+    pub localized_value: Option<String>,
+}
+
+// A metric's value over one interval.  Only the requested aggregations are present.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricValue {
+    // The start of the interval
+    pub time_stamp: DateTime<Utc>,
+    // The average value
+    pub average: Option<f64>,
+    // The minimum value
+    pub minimum: Option<f64>,
+    // The maximum value
+    pub maximum: Option<f64>,
+    // The total value
+    pub total: Option<f64>,
+    // The number of samples
+    pub count: Option<f64>,
+}
+
+// A metric's values over the timespan.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TimeSeries {
+    // The values, oldest first
+    #[serde(default)]
+    pub data: Vec<MetricValue>,
+}
+
+// A metric, e.g.
+// ```json
+// {
+//   "name": { "value": "storage_used", "localizedValue": "Data space used" },
+//   "unit": "Bytes",
+//   "timeseries": [ { "data": [ { "timeStamp": "2022-08-01T00:00:00Z", "maximum": 1234.0 } ] } ],
+//   "errorCode": "Success"
+// }
+// ```
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Metric {
+    // The metric's name
+    pub name: MetricName,
+    // The unit, e.g. "Bytes"
+    pub unit: Option<String>,
+    // The values, one series as we don't split by dimension
+    #[serde(default)]
+    pub timeseries: Vec<TimeSeries>,
+    // "Success", or why the metric couldn't be had
+    pub error_code: Option<String>,
+}
+
+impl Metric {
+    // Gets the latest value of the given aggregation, e.g. |v| v.maximum, if there is one.
+    pub fn latest(&self, aggregation: impl Fn(&MetricValue) -> Option<f64>) -> Option<f64> {
+        self.timeseries
+            .iter()
+            .flat_map(|series| &series.data)
+            .filter_map(|value| aggregation(value).map(|v| (value.time_stamp, v)))
+            .max_by_key(|(time_stamp, _)| *time_stamp)
+            .map(|(_, v)| v)
+    }
+}
+
+// The response to a metrics request.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MetricsResponse {
+    // The metrics that were requested
+    #[serde(default)]
+    pub value: Vec<Metric>,
+}
+
+impl MetricsResponse {
+    // Finds a metric by name, e.g. "storage_used".
+    pub fn find_metric(&self, name: &str) -> Option<&Metric> {
+        self.value
+            .iter()
+            .find(|metric| metric.name.value.eq_ignore_ascii_case(name))
+    }
+}
+
+impl ArmClient {
+    // Gets the given metrics of a resource from Azure Monitor over the given time up to now, aggregated over the
+    // given interval, e.g. (["storage_used"], "Maximum", 30 minutes, "PT5M").
+    pub async fn get_metrics(
+        &self,
+        subscription_id: &str,
+        resource_path: &str,
+        metric_names: &[&str],
+        aggregation: &str,
+        timespan: Duration,
+        interval: &str,
+    ) -> anyhow::Result<MetricsResponse> {
+        log::debug!(
+            "get_metrics - resource_path = {resource_path}, metric_names = {metric_names:?}"
+        );
+        // The timespan is given as "START/END"
+        let end = Utc::now();
+        let timespan = format!(
+            "{}/{}",
+            (end - timespan).to_rfc3339_opts(SecondsFormat::Secs, true),
+            end.to_rfc3339_opts(SecondsFormat::Secs, true)
+        );
+        let metric_names = metric_names.join(",");
+        self.get_with_query(
+            subscription_id,
+            &format!("{resource_path}/providers/Microsoft.Insights/metrics"),
+            "Microsoft.Insights/metrics",
+            &[
+                ("metricnames", &metric_names),
+                ("aggregation", aggregation),
+                ("timespan", &timespan),
+                ("interval", interval),
+            ],
+        )
+        .await
+    }
+}
@@ -1,4 +1,5 @@
-use crate::AccessTokenCacheMap;
+use crate::azure_apis::arm_client::{ArmClient, ElasticPoolId};
+use crate::azure_apis::batch::{Batch, BatchListItem};
 use actix_web::http::StatusCode;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
@@ -82,34 +83,34 @@ impl DatabaseListResponse {
     }
 }
 
-// Lists the databases in an elastic pool.
-pub async fn list_databases_in_elastic_pool(
-    http_client: &reqwest::Client,
-    token_cache_map: &AccessTokenCacheMap,
-    subscription_id: String,
-    resource_group_name: String,
-    server_name: String,
-    elastic_pool_name: String,
-) -> anyhow::Result<DatabaseListResponse> {
-    log::debug!("get_database_usage");
-    log::debug!(" - getting access token");
-    // Try to get an access token for this subscription
-    let access_token = token_cache_map
-        .access_token(subscription_id.clone())
-        .await?;
-    log::debug!(" - got access token");
-    // Form the URL
-    let url = format!(
-        "https://management.azure.com\
-        /subscriptions/{subscription_id}\
-        /resourceGroups/{resource_group_name}\
-        /providers/Microsoft.Sql\
-        /servers/{server_name}\
-        /elasticPools/{elastic_pool_name}\
-        /databases\
-        ?api-version=2021-11-01-preview"
-    );
-    // Get the response from JSON
-    log::debug!(" - getting result from JSON");
-    super::get_json::<DatabaseListResponse>(http_client, url, access_token).await
+impl ArmClient {
+    // Lists the databases in an elastic pool.
+    pub async fn list_databases_in_elastic_pool(
+        &self,
+        elastic_pool: &ElasticPoolId,
+    ) -> anyhow::Result<DatabaseListResponse> {
+        log::debug!("list_databases_in_elastic_pool - elastic_pool = {elastic_pool:?}");
+        let values = self
+            .list(
+                &elastic_pool.subscription_id,
+                &format!("{}/databases", elastic_pool.path()),
+                "Microsoft.Sql/servers/elasticPools/databases",
+            )
+            .await?;
+        Ok(DatabaseListResponse { values })
+    }
+}
+
+impl Batch<'_> {
+    // Adds a request listing the databases in an elastic pool to the batch.
+    pub fn list_databases_in_elastic_pool(
+        &mut self,
+        elastic_pool: &ElasticPoolId,
+    ) -> anyhow::Result<BatchListItem<Database, DatabaseListResponse>> {
+        self.list(
+            &format!("{}/databases", elastic_pool.path()),
+            "Microsoft.Sql/servers/elasticPools/databases",
+            |values| DatabaseListResponse { values },
+        )
+    }
 }
@@ -0,0 +1,198 @@
+use crate::azure_apis::arm_client::{ArmClient, ResourceGroupId};
+use serde::Deserialize;
+
+// The actions a role assignment allows, e.g.
+// ```json
+// {
+//   "actions": ["*/read"],
+//   "notActions": [],
+//   "dataActions": [],
+//   "notDataActions": []
+// }
+// ```
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Permission {
+    // The allowed actions, which may contain wildcards, e.g. "Microsoft.Sql/*/read"
+    #[serde(default)]
+    pub actions: Vec<String>,
+    // The actions excluded from the allowed actions
+    #[serde(default)]
+    pub not_actions: Vec<String>,
+}
+
+// The permissions the caller has on a scope.
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PermissionListResponse {
+    // The permissions, one per role assignment
+    pub value: Vec<Permission>,
+}
+
+// Whether an action pattern, which may contain "*" wildcards, matches an action.  Actions are case-insensitive.
+fn action_matches(pattern: &str, action: &str) -> bool {
+    let pattern = pattern.to_ascii_lowercase();
+    let action = action.to_ascii_lowercase();
+    let mut parts = pattern.split('*');
+    // The first part must be at the start
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = action.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts = parts.collect::<Vec<_>>();
+    // Without wildcards, the whole action must match
+    let Some(last) = parts.pop() else {
+        return rest.is_empty();
+    };
+    // Each middle part must come in order, and the last part must be at the end
+    for part in parts {
+        match rest.find(part) {
+            Some(index) => rest = &rest[index + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+impl PermissionListResponse {
+    // Whether the permissions allow the given action, e.g. "Microsoft.Sql/servers/databases/read".
+    // An action is allowed if a role assignment allows it and doesn't exclude it.
+    pub fn allows(&self, action: &str) -> bool {
+        self.value.iter().any(|permission| {
+            permission.actions.iter().any(|a| action_matches(a, action))
+                && !permission
+                    .not_actions
+                    .iter()
+                    .any(|a| action_matches(a, action))
+        })
+    }
+}
+
+impl ArmClient {
+    // Lists the caller's permissions on a resource group.
+    pub async fn list_permissions(
+        &self,
+        resource_group: &ResourceGroupId,
+    ) -> anyhow::Result<PermissionListResponse> {
+        log::debug!("list_permissions - resource_group = {resource_group:?}");
+        let value = self
+            .list(
+                &resource_group.subscription_id,
+                &format!(
+                    "{}/providers/Microsoft.Authorization/permissions",
+                    resource_group.path()
+                ),
+                "Microsoft.Authorization/permissions",
+            )
+            .await?;
+        Ok(PermissionListResponse { value })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Creates permissions with one role assignment with the given actions and excluded actions.
+    fn permissions(actions: &[&str], not_actions: &[&str]) -> PermissionListResponse {
+        PermissionListResponse {
+            value: vec![Permission {
+                actions: actions.iter().map(|a| a.to_string()).collect(),
+                not_actions: not_actions.iter().map(|a| a.to_string()).collect(),
+            }],
+        }
+    }
+
+    #[test]
+    fn star_matches_everything() {
+        assert!(action_matches("*", "Microsoft.Sql/servers/databases/read"));
+        assert!(action_matches("*", "Microsoft.Insights/metrics/read"));
+    }
+
+    #[test]
+    fn provider_wildcard_matches_only_the_provider() {
+        assert!(action_matches(
+            "Microsoft.Sql/*",
+            "Microsoft.Sql/servers/databases/usages/read"
+        ));
+        assert!(!action_matches(
+            "Microsoft.Sql/*",
+            "Microsoft.Insights/metrics/read"
+        ));
+        assert!(!action_matches(
+            "Microsoft.Sql/*",
+            "Microsoft.SqlVirtualMachine/read"
+        ));
+    }
+
+    #[test]
+    fn read_wildcard_matches_only_reads() {
+        assert!(action_matches(
+            "*/read",
+            "Microsoft.Sql/servers/elasticPools/read"
+        ));
+        assert!(!action_matches(
+            "*/read",
+            "Microsoft.Sql/servers/elasticPools/write"
+        ));
+        assert!(!action_matches(
+            "*/read",
+            "Microsoft.Sql/servers/elasticPools/readers"
+        ));
+    }
+
+    #[test]
+    fn middle_wildcard_matches_in_order() {
+        assert!(action_matches(
+            "Microsoft.Sql/*/read",
+            "Microsoft.Sql/servers/databases/read"
+        ));
+        assert!(!action_matches(
+            "Microsoft.Sql/*/read",
+            "Microsoft.Insights/servers/read"
+        ));
+        // The middle and the end can't overlap
+        assert!(!action_matches("a*b*b", "ab"));
+        assert!(action_matches("a*b*b", "abb"));
+    }
+
+    #[test]
+    fn actions_without_wildcards_must_match_exactly() {
+        assert!(action_matches(
+            "Microsoft.Sql/servers/databases/read",
+            "microsoft.sql/SERVERS/databases/read"
+        ));
+        assert!(!action_matches(
+            "Microsoft.Sql/servers/databases/read",
+            "Microsoft.Sql/servers/databases/readers"
+        ));
+        assert!(!action_matches(
+            "Microsoft.Sql/servers/databases/read",
+            "Microsoft.Sql/servers/read"
+        ));
+    }
+
+    #[test]
+    fn not_actions_exclude_allowed_actions() {
+        let permissions = permissions(&["*/read"], &["Microsoft.Sql/servers/databases/usages/*"]);
+        assert!(permissions.allows("Microsoft.Sql/servers/databases/read"));
+        assert!(!permissions.allows("Microsoft.Sql/servers/databases/usages/read"));
+        assert!(!permissions.allows("Microsoft.Sql/servers/databases/write"));
+    }
+
+    #[test]
+    fn an_action_excluded_by_one_role_assignment_may_be_allowed_by_another() {
+        let mut permissions = permissions(&["*"], &["Microsoft.Sql/*"]);
+        assert!(!permissions.allows("Microsoft.Sql/servers/databases/read"));
+        permissions.value.push(Permission {
+            actions: vec!["Microsoft.Sql/servers/databases/read".to_string()],
+            not_actions: vec![],
+        });
+        assert!(permissions.allows("Microsoft.Sql/servers/databases/read"));
+    }
+
+    #[test]
+    fn no_permissions_allow_nothing() {
+        assert!(!PermissionListResponse { value: vec![] }.allows("Microsoft.Sql/servers/read"));
+    }
+}
@@ -1,89 +1,105 @@
+use crate::azure_apis::error::AzureError;
+use crate::azure_apis::retry::{RetryPolicy, SubscriptionLimits};
+use crate::secrets::SecretString;
 use actix_web::http;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 
+pub mod arm_client;
+pub mod batch;
+pub mod concurrency;
+pub mod error;
+pub mod get_application;
 pub mod get_database_usage;
 pub mod get_elastic_pool;
+pub mod get_metrics;
 pub mod list_databases_in_elastic_pool;
+pub mod list_permissions;
+pub mod retry;
 
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct AzureError {
-    // The error code, e.g. "InvalidAuthenticationTokenTenant"
-    pub code: String,
-    // The error message
-    pub message: String,
-}
-#[derive(Debug, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct AzureErrorResponse {
-    // The error that this response wraps.
-    pub error: AzureError,
-}
-
-// Fetches an Azure response as json.
-pub async fn get_json<'a, T>(
+// Sends a request to Azure, with a JSON body if one is given, retrying according to the retry policy.
+// If a subscription's limits are given, the request is slowed down when the subscription is running out of
+// reads, and each attempt waits its turn.  Returns the response if it was successful; otherwise its error, an AzureError.
+pub async fn send_request(
     http_client: &reqwest::Client,
-    url: String,
-    access_token: String,
-) -> anyhow::Result<T>
-where
-    T: DeserializeOwned,
-{
-    log::debug!("get_json");
+    method: reqwest::Method,
+    url: &str,
+    body: Option<&serde_json::Value>,
+    access_token: &SecretString,
+    retry_policy: &RetryPolicy,
+    limits: Option<SubscriptionLimits<'_>>,
+) -> anyhow::Result<reqwest::Response> {
+    log::debug!("send_request - method = {method}");
     log::debug!(" - sending request");
     // We'll want to get a response
-    let response = http_client
-        // Call the URL
-        .get(url)
-        // Add the auth header
-        .header("Authorization", format!("Bearer {access_token}"))
-        // Make the request
-        .send()
-        .await?;
-    log::debug!(" - got response with status code {:?}", response.status());
+    let response = retry_policy
+        .send(
+            || {
+                let request = http_client
+                    // Call the URL
+                    .request(method.clone(), url)
+                    // Add the auth header
+                    .header(
+                        "Authorization",
+                        format!("Bearer {}", access_token.expose_secret()),
+                    );
+                // Add the body, if any
+                match body {
+                    Some(body) => request.json(body),
+                    None => request,
+                }
+            },
+            limits,
+        )
+        .await
+        .map_err(AzureError::unreachable)?;
+    let status = response.status();
+    log::debug!(" - got response with status code {:?}", status);
     // If successful...
-    if http::StatusCode::OK == response.status() {
-        // Get the response as json
-        let value = response.json::<T>().await?;
-        // Return it
-        Ok(value)
+    if status.is_success() {
+        // Return the response
+        Ok(response)
     } else {
-        // Get the response as error json
-        let error_response = response.json::<AzureErrorResponse>().await?;
-        // Get the underlying code and message
-        let code = error_response.error.code;
-        let message = error_response.error.message;
-        // Format the message
-        let formatted_message = format!("{code}: {message}");
-        log::warn!("Error: {formatted_message}");
+        // Get the error from the response
+        let error = AzureError::from_response(response).await;
+        log::warn!(
+            "Error: {error} (status {status}, request ID {})",
+            error.details().request_id.as_deref().unwrap_or("unknown")
+        );
         // Return that we had an error
-        Err(anyhow::anyhow!(formatted_message))
+        Err(error.into())
     }
 }
 
-/*
-    let response = client
-        // Get the data from the URL
-        .get(url)
-        // Add the auth header
-        .header("Authorization", format!("Bearer {access_token}"))
-        // Make the request
-        .send()
-        .await?;
-    // If successful...
-    if StatusCode::OK == response.status() {
-        // Get the response as json
-        let database_usage = response.json::<DatabaseUsageResponse>().await?;
-        // Return it
-        Ok(database_usage)
-    } else {
-        // Get the response as text
-        let text = response.text().await?;
-        // Log it
-        log::debug!("Error: {text}");
-        // Return that we had an error
-        Err(anyhow::anyhow!("test"))
-    }
+// Fetches an Azure response as json, as send_request does.
+pub async fn get_json<T>(
+    http_client: &reqwest::Client,
+    url: String,
+    access_token: SecretString,
+    retry_policy: &RetryPolicy,
+    limits: Option<SubscriptionLimits<'_>>,
+) -> anyhow::Result<T>
+where
+    T: DeserializeOwned,
+{
+    log::debug!("get_json");
+    // Get the response
+    let response = send_request(
+        http_client,
+        reqwest::Method::GET,
+        &url,
+        None,
+        &access_token,
+        retry_policy,
+        limits,
+    )
+    .await?;
+    // Get the response as json
+    let status = response.status();
+    let value = response
+        .json::<T>()
+        .await
+        .map_err(|e| AzureError::unreadable(status, e))?;
+    // Return it
+    Ok(value)
 }
- */
@@ -0,0 +1,526 @@
+use crate::azure_apis::concurrency::ConcurrencyLimiter;
+use crate::settings::{ArmSettings, RetrySettings};
+use actix_web::http;
+use rand::Rng;
+use reqwest::header::HeaderMap;
+use reqwest::ResponseBuilderExt;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+// The header ARM uses to say how many more reads a subscription can make before it's throttled.
+const REMAINING_SUBSCRIPTION_READS_HEADER: &str = "x-ms-ratelimit-remaining-subscription-reads";
+
+// What paces the requests to a subscription.
+#[derive(Clone, Copy)]
+pub struct SubscriptionLimits<'a> {
+    // Slows down requests to the subscription when it's close to being throttled
+    pub read_throttle: &'a ReadThrottle,
+    // Limits how many requests run at once
+    pub concurrency_limiter: &'a ConcurrencyLimiter,
+    // The subscription ID (a GUID)
+    pub subscription_id: &'a str,
+}
+
+// How requests that fail in a way that may not happen again are retried.
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    // How many times a request is retried before giving up
+    max_retries: u32,
+    // The delay before the first retry, which doubles with each retry
+    initial_delay: Duration,
+    // The longest delay before a retry, including one asked for by a Retry-After header
+    max_delay: Duration,
+}
+
+impl RetryPolicy {
+    // Creates the policy from the settings
+    pub fn new(settings: &RetrySettings) -> Self {
+        RetryPolicy {
+            max_retries: settings.max_retries,
+            initial_delay: Duration::from_millis(settings.initial_delay_ms),
+            max_delay: Duration::from_millis(settings.max_delay_ms),
+        }
+    }
+
+    // Gets the delay before the given retry (0 for the first): a random delay up to the exponential backoff,
+    // so that requests that failed together aren't all retried together.
+    fn backoff(&self, retry: u32) -> Duration {
+        let backoff = self
+            .initial_delay
+            .saturating_mul(2u32.saturating_pow(retry))
+            .min(self.max_delay);
+        backoff.mul_f64(rand::thread_rng().gen_range(0.0..=1.0))
+    }
+
+    // Gets the delay a response asks for before trying again, if any, from the "retry-after-ms",
+    // "x-ms-retry-after-ms", or "Retry-After" header.  Retry-After may be in seconds or an HTTP date.
+    fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+        if let Some(milliseconds) = header("retry-after-ms").or(header("x-ms-retry-after-ms")) {
+            return milliseconds.trim().parse().ok().map(Duration::from_millis);
+        }
+        let retry_after = header("retry-after")?.trim();
+        match retry_after.parse::<u64>() {
+            Ok(seconds) => Some(Duration::from_secs(seconds)),
+            Err(_) => {
+                let date = chrono::DateTime::parse_from_rfc2822(retry_after).ok()?;
+                Some(
+                    (date.with_timezone(&chrono::Utc) - chrono::Utc::now())
+                        .to_std()
+                        .unwrap_or_default(),
+                )
+            }
+        }
+    }
+
+    // Gets the delay before retrying a request whose response had a retryable status: the delay the response asks
+    // for, or the backoff if it doesn't.  None if it asks us to wait longer than we're willing to, so we give up.
+    fn retry_delay(&self, retry: u32, headers: &HeaderMap) -> Option<Duration> {
+        match Self::retry_after(headers) {
+            Some(retry_after) if retry_after > self.max_delay => None,
+            Some(retry_after) => Some(retry_after),
+            None => Some(self.backoff(retry)),
+        }
+    }
+
+    // Whether a response with the given status may succeed if the request is retried.
+    fn is_retryable_status(status: http::StatusCode) -> bool {
+        status == http::StatusCode::REQUEST_TIMEOUT
+            || status == http::StatusCode::TOO_MANY_REQUESTS
+            || (status.is_server_error() && status != http::StatusCode::NOT_IMPLEMENTED)
+    }
+
+    // Whether a request that failed with the given error may succeed if it's retried.
+    fn is_retryable_error(error: &reqwest::Error) -> bool {
+        error.is_timeout() || error.is_connect() || error.is_request()
+    }
+
+    // Reads a response's body, and returns the response with its body in memory.
+    async fn read_body(response: reqwest::Response) -> reqwest::Result<reqwest::Response> {
+        let mut builder = ::http::Response::builder()
+            .status(response.status())
+            .version(response.version())
+            .url(response.url().clone());
+        if let Some(headers) = builder.headers_mut() {
+            *headers = response.headers().clone();
+        }
+        let body = response.bytes().await?;
+        Ok(builder
+            .body(body)
+            .expect("the parts of a valid response make a valid response")
+            .into())
+    }
+
+    // Sends the request made by make_request, retrying it after a delay if it fails with a retryable status
+    // or a transient network error.  If a subscription's limits are given, requests are slowed down when the
+    // subscription is running out of reads, and each attempt waits its turn, giving it up while waiting to retry.
+    // The last response is returned, whatever its status, with its body already read.
+    pub async fn send<F>(
+        &self,
+        make_request: F,
+        limits: Option<SubscriptionLimits<'_>>,
+    ) -> anyhow::Result<reqwest::Response>
+    where
+        F: Fn() -> reqwest::RequestBuilder,
+    {
+        let mut retry = 0;
+        loop {
+            // Slow down if the subscription is close to being throttled
+            if let Some(limits) = limits {
+                let delay = limits.read_throttle.delay(limits.subscription_id);
+                if !delay.is_zero() {
+                    log::info!(
+                        "Delaying a request to subscription {} by {delay:?} to avoid throttling",
+                        limits.subscription_id
+                    );
+                    actix_web::rt::time::sleep(delay).await;
+                }
+            }
+            // Wait for our turn, make the request, and read the response.  The turn ends once the body has been
+            // read, so it covers the whole exchange but isn't held while waiting to retry.
+            let permit = match limits {
+                Some(limits) => Some(
+                    limits
+                        .concurrency_limiter
+                        .acquire(limits.subscription_id)
+                        .await,
+                ),
+                None => None,
+            };
+            let result = match make_request().send().await {
+                Ok(response) => Self::read_body(response).await,
+                Err(e) => Err(e),
+            };
+            drop(permit);
+            // Work out how long to wait before retrying the request, if it should be
+            let delay = match result {
+                Ok(response) => {
+                    // Keep track of how many reads the subscription has left
+                    if let Some(limits) = limits {
+                        limits
+                            .read_throttle
+                            .record(limits.subscription_id, response.headers());
+                    }
+                    if retry >= self.max_retries || !Self::is_retryable_status(response.status()) {
+                        return Ok(response);
+                    }
+                    match self.retry_delay(retry, response.headers()) {
+                        Some(delay) => delay,
+                        None => {
+                            log::warn!(
+                                "Not retrying a request that failed with status {}, as it asked us to wait {:?}",
+                                response.status(),
+                                Self::retry_after(response.headers())
+                            );
+                            return Ok(response);
+                        }
+                    }
+                }
+                Err(e) => {
+                    if retry >= self.max_retries || !Self::is_retryable_error(&e) {
+                        return Err(e.into());
+                    }
+                    self.backoff(retry)
+                }
+            };
+            retry += 1;
+            log::warn!(
+                "Retrying a request (retry {retry} of {}) in {delay:?}",
+                self.max_retries
+            );
+            actix_web::rt::time::sleep(delay).await;
+        }
+    }
+}
+
+// Slows down requests to subscriptions that are close to being throttled by ARM, using the number of reads
+// they have left according to the last response.
+#[derive(Debug)]
+pub struct ReadThrottle {
+    // Requests are slowed down when a subscription has fewer reads than this left
+    slow_down_below_remaining_reads: u32,
+    // The delay added to each request when a subscription has no reads left
+    max_delay: Duration,
+    // The number of reads each subscription had left at the last response, by lower-case subscription ID
+    remaining_reads: Mutex<HashMap<String, u32>>,
+}
+
+impl ReadThrottle {
+    // Creates the throttle from the settings
+    pub fn new(settings: &ArmSettings) -> Self {
+        ReadThrottle {
+            slow_down_below_remaining_reads: settings.slow_down_below_remaining_reads,
+            max_delay: Duration::from_millis(settings.max_slow_down_delay_ms),
+            remaining_reads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Gets the number of reads the subscription had left at the last response, if known.
+    pub fn remaining_reads(&self, subscription_id: &str) -> Option<u32> {
+        self.remaining_reads
+            .lock()
+            .unwrap()
+            .get(&subscription_id.to_ascii_lowercase())
+            .copied()
+    }
+
+    // Records the number of reads the subscription has left, if the response headers say.
+    pub fn record(&self, subscription_id: &str, headers: &HeaderMap) {
+        let Some(remaining_reads) = headers
+            .get(REMAINING_SUBSCRIPTION_READS_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse::<u32>().ok())
+        else {
+            return;
+        };
+        log::debug!(" - subscription {subscription_id} has {remaining_reads} reads remaining");
+        self.remaining_reads
+            .lock()
+            .unwrap()
+            .insert(subscription_id.to_ascii_lowercase(), remaining_reads);
+    }
+
+    // Gets the delay to add before a request to the subscription: none while it has plenty of reads left,
+    // growing to the maximum as they run out.
+    pub fn delay(&self, subscription_id: &str) -> Duration {
+        match self.remaining_reads(subscription_id) {
+            Some(remaining_reads) if remaining_reads < self.slow_down_below_remaining_reads => {
+                let shortfall = (self.slow_down_below_remaining_reads - remaining_reads) as f64
+                    / self.slow_down_below_remaining_reads as f64;
+                self.max_delay.mul_f64(shortfall)
+            }
+            _ => Duration::ZERO,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::ConcurrencySettings;
+    use crate::test_server::{TestResponse, TestServer};
+    use reqwest::header::{HeaderName, HeaderValue};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // Makes response headers with the given names and values.
+    fn headers(headers: &[(&'static str, &str)]) -> HeaderMap {
+        headers
+            .iter()
+            .map(|(name, value)| {
+                (
+                    HeaderName::from_static(name),
+                    HeaderValue::from_str(value).unwrap(),
+                )
+            })
+            .collect()
+    }
+
+    // A policy that waits at most 10 seconds.
+    fn retry_policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+
+    // A throttle that slows down below the given number of reads left, by up to 2 seconds.
+    fn read_throttle(slow_down_below_remaining_reads: u32) -> ReadThrottle {
+        ReadThrottle {
+            slow_down_below_remaining_reads,
+            max_delay: Duration::from_secs(2),
+            remaining_reads: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // A limiter that only lets one request run at once.
+    fn one_at_a_time() -> ConcurrencyLimiter {
+        ConcurrencyLimiter::new(&ConcurrencySettings {
+            max_concurrent_requests: 1,
+            max_concurrent_requests_per_subscription: 1,
+        })
+    }
+
+    #[test]
+    fn retry_after_may_be_in_seconds() {
+        assert_eq!(
+            Some(Duration::from_secs(7)),
+            RetryPolicy::retry_after(&headers(&[("retry-after", " 7 ")]))
+        );
+    }
+
+    #[test]
+    fn retry_after_may_be_an_http_date() {
+        let date = (chrono::Utc::now() + chrono::Duration::seconds(30))
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string();
+        let retry_after = RetryPolicy::retry_after(&headers(&[("retry-after", &date)])).unwrap();
+        assert!(Duration::from_secs(28) < retry_after && retry_after <= Duration::from_secs(30));
+        // A date that's passed means there's no need to wait
+        assert_eq!(
+            Some(Duration::ZERO),
+            RetryPolicy::retry_after(&headers(&[(
+                "retry-after",
+                "Sun, 06 Nov 1994 08:49:37 GMT"
+            )]))
+        );
+    }
+
+    #[test]
+    fn retry_after_ms_is_preferred() {
+        assert_eq!(
+            Some(Duration::from_millis(1500)),
+            RetryPolicy::retry_after(&headers(&[
+                ("retry-after", "7"),
+                ("retry-after-ms", "1500")
+            ]))
+        );
+        assert_eq!(
+            Some(Duration::from_millis(250)),
+            RetryPolicy::retry_after(&headers(&[("x-ms-retry-after-ms", "250")]))
+        );
+    }
+
+    #[test]
+    fn missing_or_invalid_retry_after_is_ignored() {
+        assert_eq!(None, RetryPolicy::retry_after(&headers(&[])));
+        assert_eq!(
+            None,
+            RetryPolicy::retry_after(&headers(&[("retry-after", "soon")]))
+        );
+        assert_eq!(
+            None,
+            RetryPolicy::retry_after(&headers(&[("retry-after-ms", "-1")]))
+        );
+    }
+
+    #[test]
+    fn retry_waits_as_asked() {
+        assert_eq!(
+            Some(Duration::from_secs(10)),
+            retry_policy().retry_delay(0, &headers(&[("retry-after", "10")]))
+        );
+    }
+
+    #[test]
+    fn retry_is_given_up_if_asked_to_wait_too_long() {
+        assert_eq!(
+            None,
+            retry_policy().retry_delay(0, &headers(&[("retry-after", "11")]))
+        );
+        assert_eq!(
+            None,
+            retry_policy().retry_delay(0, &headers(&[("retry-after-ms", "10001")]))
+        );
+    }
+
+    #[test]
+    fn retry_backs_off_exponentially_up_to_the_max_delay() {
+        let retry_policy = retry_policy();
+        for retry in 0..10 {
+            let delay = retry_policy.retry_delay(retry, &headers(&[])).unwrap();
+            let backoff = Duration::from_millis(500 * 2u64.pow(retry)).min(Duration::from_secs(10));
+            assert!(delay <= backoff, "retry {retry} waited {delay:?}");
+        }
+    }
+
+    #[test]
+    fn transient_statuses_are_retryable() {
+        for status in [408, 429, 500, 502, 503, 504] {
+            assert!(
+                RetryPolicy::is_retryable_status(http::StatusCode::from_u16(status).unwrap()),
+                "{status}"
+            );
+        }
+    }
+
+    #[test]
+    fn other_statuses_are_not_retryable() {
+        for status in [200, 202, 400, 401, 403, 404, 409, 501] {
+            assert!(
+                !RetryPolicy::is_retryable_status(http::StatusCode::from_u16(status).unwrap()),
+                "{status}"
+            );
+        }
+    }
+
+    #[test]
+    fn requests_are_slowed_down_in_proportion_to_the_reads_used() {
+        let throttle = read_throttle(100);
+        // Nothing is known about the subscription yet
+        assert_eq!(Duration::ZERO, throttle.delay("s1"));
+        for (remaining_reads, delay) in [
+            ("12000", Duration::ZERO),
+            ("100", Duration::ZERO),
+            ("75", Duration::from_millis(500)),
+            ("50", Duration::from_secs(1)),
+            ("0", Duration::from_secs(2)),
+        ] {
+            throttle.record(
+                "S1",
+                &headers(&[(REMAINING_SUBSCRIPTION_READS_HEADER, remaining_reads)]),
+            );
+            assert_eq!(delay, throttle.delay("s1"), "{remaining_reads} reads left");
+        }
+        // Other subscriptions aren't affected
+        assert_eq!(Duration::ZERO, throttle.delay("s2"));
+    }
+
+    #[test]
+    fn requests_are_never_slowed_down_if_the_threshold_is_0() {
+        let throttle = read_throttle(0);
+        throttle.record(
+            "s1",
+            &headers(&[(REMAINING_SUBSCRIPTION_READS_HEADER, "0")]),
+        );
+        assert_eq!(Some(0), throttle.remaining_reads("s1"));
+        assert_eq!(Duration::ZERO, throttle.delay("s1"));
+    }
+
+    #[test]
+    fn invalid_remaining_reads_are_ignored() {
+        let throttle = read_throttle(100);
+        throttle.record(
+            "s1",
+            &headers(&[(REMAINING_SUBSCRIPTION_READS_HEADER, "lots")]),
+        );
+        assert_eq!(None, throttle.remaining_reads("s1"));
+    }
+
+    #[actix_web::test]
+    async fn turn_is_given_up_while_waiting_to_retry() {
+        // The first attempt is asked to retry after a second
+        let attempts = AtomicUsize::new(0);
+        let server = TestServer::start(move |_| {
+            if 0 == attempts.fetch_add(1, Ordering::SeqCst) {
+                TestResponse::new(503).header("Retry-After", "1")
+            } else {
+                TestResponse::new(200)
+            }
+        });
+        // Only one request to the subscription can run at once
+        let concurrency_limiter = one_at_a_time();
+        let read_throttle = read_throttle(0);
+        let limits = SubscriptionLimits {
+            read_throttle: &read_throttle,
+            concurrency_limiter: &concurrency_limiter,
+            subscription_id: "s1",
+        };
+        let retry_policy = retry_policy();
+        let http_client = reqwest::Client::new();
+        let send = retry_policy.send(|| http_client.get(server.url()), Some(limits));
+        // Another request, made while the first waits to retry, gets its turn without waiting
+        let other_request = async {
+            actix_web::rt::time::sleep(Duration::from_millis(500)).await;
+            let queued_at = std::time::Instant::now();
+            let _permit = concurrency_limiter.acquire("s1").await;
+            queued_at.elapsed()
+        };
+        let (response, other_queue_time) = futures::join!(send, other_request);
+        assert_eq!(http::StatusCode::OK, response.unwrap().status());
+        assert!(
+            other_queue_time < Duration::from_millis(250),
+            "waited {other_queue_time:?}"
+        );
+        // Each attempt had its own turn
+        assert_eq!(3, concurrency_limiter.metrics().all.requests);
+        assert_eq!(0, concurrency_limiter.metrics().all.in_flight);
+    }
+
+    #[actix_web::test]
+    async fn turn_is_held_until_the_body_is_read() {
+        // The body arrives well after the headers
+        let server = TestServer::start(|_| {
+            TestResponse::json(200, serde_json::json!({ "value": [] }))
+                .body_delay(Duration::from_millis(500))
+        });
+        let concurrency_limiter = one_at_a_time();
+        let read_throttle = read_throttle(0);
+        let limits = SubscriptionLimits {
+            read_throttle: &read_throttle,
+            concurrency_limiter: &concurrency_limiter,
+            subscription_id: "s1",
+        };
+        let retry_policy = retry_policy();
+        let http_client = reqwest::Client::new();
+        let send = retry_policy.send(|| http_client.get(server.url()), Some(limits));
+        // Another request, made once the headers have arrived, waits for the body too
+        let other_request = async {
+            actix_web::rt::time::sleep(Duration::from_millis(100)).await;
+            let queued_at = std::time::Instant::now();
+            let _permit = concurrency_limiter.acquire("s1").await;
+            queued_at.elapsed()
+        };
+        let (response, other_queue_time) = futures::join!(send, other_request);
+        assert!(
+            other_queue_time >= Duration::from_millis(300),
+            "waited {other_queue_time:?}"
+        );
+        // The body can still be read from the response
+        assert_eq!(
+            serde_json::json!({ "value": [] }),
+            response.unwrap().json::<serde_json::Value>().await.unwrap()
+        );
+    }
+}
@@ -0,0 +1,153 @@
+// The endpoints and token audiences of an Azure cloud.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+pub struct CloudProfile {
+    // The cloud name, e.g. "public"
+    pub name: String,
+    // The Azure AD authority host, e.g. "https://login.microsoftonline.com"
+    pub authority_host: String,
+    // The Azure Resource Manager endpoint, e.g. "https://management.azure.com"
+    pub resource_manager_endpoint: String,
+    // The token audience for Azure Resource Manager (which also serves Azure Monitor metrics and Cost Management)
+    pub resource_manager_audience: String,
+    // The Microsoft Graph endpoint, e.g. "https://graph.microsoft.com"
+    pub graph_endpoint: String,
+    // The token audience for Microsoft Graph
+    pub graph_audience: String,
+    // The token audience for Log Analytics
+    pub log_analytics_audience: String,
+    // The token audience for the Azure Monitor data plane
+    pub monitor_audience: String,
+    // The token audience for Key Vault
+    pub key_vault_audience: String,
+}
+
+impl CloudProfile {
+    // The Azure public cloud.
+    pub fn public() -> Self {
+        CloudProfile {
+            name: "public".to_string(),
+            authority_host: "https://login.microsoftonline.com".to_string(),
+            resource_manager_endpoint: "https://management.azure.com".to_string(),
+            resource_manager_audience: "https://management.azure.com".to_string(),
+            graph_endpoint: "https://graph.microsoft.com".to_string(),
+            graph_audience: "https://graph.microsoft.com".to_string(),
+            log_analytics_audience: "https://api.loganalytics.io".to_string(),
+            monitor_audience: "https://monitor.azure.com".to_string(),
+            key_vault_audience: "https://vault.azure.net".to_string(),
+        }
+    }
+
+    // Azure Government (US).
+    pub fn us_government() -> Self {
+        CloudProfile {
+            name: "us_government".to_string(),
+            authority_host: "https://login.microsoftonline.us".to_string(),
+            resource_manager_endpoint: "https://management.usgovcloudapi.net".to_string(),
+            resource_manager_audience: "https://management.usgovcloudapi.net".to_string(),
+            graph_endpoint: "https://graph.microsoft.us".to_string(),
+            graph_audience: "https://graph.microsoft.us".to_string(),
+            log_analytics_audience: "https://api.loganalytics.us".to_string(),
+            monitor_audience: "https://monitor.azure.us".to_string(),
+            key_vault_audience: "https://vault.usgovcloudapi.net".to_string(),
+        }
+    }
+
+    // Azure China, operated by 21Vianet.
+    pub fn china() -> Self {
+        CloudProfile {
+            name: "china".to_string(),
+            authority_host: "https://login.chinacloudapi.cn".to_string(),
+            resource_manager_endpoint: "https://management.chinacloudapi.cn".to_string(),
+            resource_manager_audience: "https://management.chinacloudapi.cn".to_string(),
+            graph_endpoint: "https://microsoftgraph.chinacloudapi.cn".to_string(),
+            graph_audience: "https://microsoftgraph.chinacloudapi.cn".to_string(),
+            log_analytics_audience: "https://api.loganalytics.azure.cn".to_string(),
+            monitor_audience: "https://monitor.azure.cn".to_string(),
+            key_vault_audience: "https://vault.azure.cn".to_string(),
+        }
+    }
+
+    // The token URL for a tenant in this cloud, for the given token endpoint version.
+    pub fn token_url(&self, tenant_id: &str, v2: bool) -> String {
+        let authority_host = self.authority_host.trim_end_matches('/');
+        if v2 {
+            format!("{authority_host}/{tenant_id}/oauth2/v2.0/token")
+        } else {
+            format!("{authority_host}/{tenant_id}/oauth2/token")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::CloudSettings;
+
+    #[test]
+    fn clouds_have_their_documented_endpoints() {
+        // (cloud setting, v1 token URL, Resource Manager endpoint and audience, Graph audience, Key Vault audience)
+        let cases = [
+            (
+                serde_json::json!("us_government"),
+                "https://login.microsoftonline.us/t/oauth2/token",
+                "https://management.usgovcloudapi.net",
+                "https://graph.microsoft.us",
+                "https://vault.usgovcloudapi.net",
+            ),
+            (
+                serde_json::json!("china"),
+                "https://login.chinacloudapi.cn/t/oauth2/token",
+                "https://management.chinacloudapi.cn",
+                "https://microsoftgraph.chinacloudapi.cn",
+                "https://vault.azure.cn",
+            ),
+            (
+                serde_json::json!({ "custom": {
+                    "name": "stack",
+                    "authority_host": "https://login.stack/",
+                    "resource_manager_endpoint": "https://management.stack",
+                    "resource_manager_audience": "https://management.stack",
+                    "graph_endpoint": "https://graph.stack",
+                    "graph_audience": "https://graph.stack",
+                    "log_analytics_audience": "https://logs.stack",
+                    "monitor_audience": "https://monitor.stack",
+                    "key_vault_audience": "https://vault.stack",
+                }}),
+                "https://login.stack/t/oauth2/token",
+                "https://management.stack",
+                "https://graph.stack",
+                "https://vault.stack",
+            ),
+        ];
+        for (setting, token_url, resource_manager, graph, key_vault) in cases {
+            let cloud = serde_json::from_value::<CloudSettings>(setting)
+                .unwrap()
+                .profile();
+            assert_eq!(token_url, cloud.token_url("t", false), "{}", cloud.name);
+            assert_eq!(
+                token_url.replace("/oauth2/token", "/oauth2/v2.0/token"),
+                cloud.token_url("t", true),
+                "{}",
+                cloud.name
+            );
+            assert_eq!(resource_manager, cloud.resource_manager_endpoint);
+            assert_eq!(resource_manager, cloud.resource_manager_audience);
+            assert_eq!(graph, cloud.graph_endpoint);
+            assert_eq!(graph, cloud.graph_audience);
+            assert_eq!(key_vault, cloud.key_vault_audience);
+        }
+        // The sovereign clouds' monitoring audiences
+        let us_government = CloudProfile::us_government();
+        assert_eq!(
+            "https://api.loganalytics.us",
+            us_government.log_analytics_audience
+        );
+        assert_eq!("https://monitor.azure.us", us_government.monitor_audience);
+        let china = CloudProfile::china();
+        assert_eq!(
+            "https://api.loganalytics.azure.cn",
+            china.log_analytics_audience
+        );
+        assert_eq!("https://monitor.azure.cn", china.monitor_audience);
+    }
+}
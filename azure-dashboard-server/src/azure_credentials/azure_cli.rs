@@ -0,0 +1,121 @@
+use crate::azure_credentials::{scopes_to_resource, TokenCredential};
+use crate::azure_token_cache::AccessToken;
+use crate::secrets::SecretString;
+use chrono::TimeZone;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+// The Azure CLI's MSAL token cache will be of the form:
+// ```json
+// {
+//   "AccessToken": {
+//     "...-login.microsoftonline.com-accesstoken-04b07795-...-TENANT_ID-https://management.azure.com/user_impersonation https://management.azure.com/.default": {
+//       "credential_type": "AccessToken",
+//       "secret": "eyJ0eXAiOiJKV1Qi...",
+//       "home_account_id": "...",
+//       "environment": "login.microsoftonline.com",
+//       "client_id": "04b07795-8ddb-461a-bbee-02f9e1bf7b46",
+//       "target": "https://management.azure.com/user_impersonation https://management.azure.com/.default",
+//       "realm": "TENANT_ID",
+//       "token_type": "Bearer",
+//       "cached_at": "1660000000",
+//       "expires_on": "1660003600"
+//     }
+//   }
+// }
+// ```
+
+// An access token entry in the Azure CLI's token cache.
+#[derive(Debug, serde::Deserialize)]
+struct CachedAccessToken {
+    // The access token
+    secret: SecretString,
+    // The space-separated scopes the token is for
+    target: String,
+    // The tenant ID the token is for
+    realm: String,
+    // The date/time, in unix seconds since the Epoch, on which the token expires
+    expires_on: String,
+}
+
+// The Azure CLI's token cache file.
+#[derive(Debug, serde::Deserialize)]
+struct TokenCacheFile {
+    // The cached access tokens, keyed by an MSAL-specific key
+    #[serde(rename = "AccessToken", default)]
+    access_tokens: HashMap<String, CachedAccessToken>,
+}
+
+// Returns the path of the Azure CLI's token cache.
+// The CLI stores its files in $AZURE_CONFIG_DIR, or ~/.azure if that isn't set.
+fn token_cache_path() -> anyhow::Result<PathBuf> {
+    // If the config directory has been overridden, use it
+    if let Ok(config_dir) = std::env::var("AZURE_CONFIG_DIR") {
+        return Ok(PathBuf::from(config_dir).join("msal_token_cache.json"));
+    }
+    // Otherwise use the ".azure" directory in the user's home directory
+    let home = std::env::var("HOME")
+        .or_else(|_| std::env::var("USERPROFILE"))
+        .map_err(|_| anyhow::anyhow!("Could not find the user's home directory"))?;
+    Ok(PathBuf::from(home)
+        .join(".azure")
+        .join("msal_token_cache.json"))
+}
+
+// Gets access tokens from the token cache written by the Azure CLI after "az login".
+// The CLI refreshes the tokens in its cache whenever it's run, so this only works while someone keeps them fresh.
+pub struct AzureCliCredential {
+    // The tenant ID (a GUID) the token must be for
+    tenant_id: String,
+}
+
+impl AzureCliCredential {
+    // Creates a new Azure CLI credential
+    pub fn new(tenant_id: String) -> Self {
+        AzureCliCredential { tenant_id }
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenCredential for AzureCliCredential {
+    async fn get_token(&self, scopes: &[String]) -> anyhow::Result<AccessToken> {
+        log::debug!("AzureCliCredential.get_token - scopes = {scopes:?}");
+        // The CLI caches tokens by resource
+        let resource = scopes_to_resource(scopes)?;
+        // Read the token cache
+        let path = token_cache_path()?;
+        log::debug!(" - reading {:?}", path);
+        let contents = std::fs::read_to_string(&path).map_err(|e| {
+            anyhow::anyhow!("Could not read the Azure CLI token cache {path:?}: {e}")
+        })?;
+        let token_cache: TokenCacheFile = serde_json::from_str(&contents)?;
+        // The scope prefix we're looking for, e.g. "https://management.azure.com/"
+        let scope_prefix = format!("{}/", resource.trim_end_matches('/'));
+        // Find the unexpired token for our tenant and resource that expires last
+        token_cache
+            .access_tokens
+            .into_values()
+            // Only consider tokens for our tenant
+            .filter(|t| t.realm.eq_ignore_ascii_case(&self.tenant_id))
+            // Only consider tokens for our resource
+            .filter(|t| {
+                t.target
+                    .split_whitespace()
+                    .any(|scope| scope.starts_with(&scope_prefix))
+            })
+            // Convert them to access tokens, skipping any with an unreadable expiry date
+            .filter_map(|t| {
+                let expires_on = t.expires_on.parse::<i64>().ok()?;
+                let expiry_date = chrono::Utc.timestamp_opt(expires_on, 0).single()?;
+                Some(AccessToken::new(t.secret, expiry_date))
+            })
+            .filter(|t| !t.is_expired())
+            .max_by_key(|t| t.expiry_date())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "The Azure CLI token cache has no valid token for tenant {} and resource {resource}.  Try running \"az login\".",
+                    self.tenant_id
+                )
+            })
+    }
+}
@@ -0,0 +1,378 @@
+use crate::azure_credentials::{client_assertion_params, TokenCredential};
+use crate::azure_token_cache::{get_access_token, AccessToken};
+use crate::secrets::SecretString;
+use crate::settings::TokenEndpointVersion;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use rsa::pkcs1::DecodeRsaPrivateKey;
+use rsa::pkcs1v15::SigningKey;
+use rsa::pkcs8::DecodePrivateKey;
+use rsa::sha2::Sha256;
+use rsa::signature::{SignatureEncoding, Signer};
+use rsa::RsaPrivateKey;
+use sha1::{Digest, Sha1};
+
+// How long a client assertion is valid for, in seconds.
+const ASSERTION_LIFETIME_SECONDS: i64 = 600;
+
+// A certificate and its private key, loaded from a PEM or PFX file.
+struct CertificateKey {
+    // The certificate's private key
+    private_key: RsaPrivateKey,
+    // The certificate in DER format
+    certificate_der: Vec<u8>,
+}
+
+impl CertificateKey {
+    // Loads a certificate and key from a PEM file containing both.
+    fn from_pem(contents: &[u8]) -> anyhow::Result<Self> {
+        // Parse all the PEM blocks in the file
+        let blocks = pem::parse_many(contents)?;
+        // Find the private key, which may be PKCS#8 or PKCS#1
+        let private_key = blocks
+            .iter()
+            .find_map(|block| match block.tag() {
+                "PRIVATE KEY" => Some(
+                    RsaPrivateKey::from_pkcs8_der(block.contents()).map_err(anyhow::Error::from),
+                ),
+                "RSA PRIVATE KEY" => Some(
+                    RsaPrivateKey::from_pkcs1_der(block.contents()).map_err(anyhow::Error::from),
+                ),
+                _ => None,
+            })
+            .ok_or_else(|| anyhow::anyhow!("The PEM file has no private key"))??;
+        // Find the certificate
+        let certificate_der = blocks
+            .iter()
+            .find(|block| block.tag() == "CERTIFICATE")
+            .map(|block| block.contents().to_vec())
+            .ok_or_else(|| anyhow::anyhow!("The PEM file has no certificate"))?;
+        Ok(CertificateKey {
+            private_key,
+            certificate_der,
+        })
+    }
+
+    // Loads a certificate and key from a password-protected PFX (PKCS#12) file.
+    fn from_pfx(contents: &[u8], password: &str) -> anyhow::Result<Self> {
+        // Parse the file
+        let pfx = p12::PFX::parse(contents)
+            .map_err(|e| anyhow::anyhow!("Could not parse the PFX file: {e:?}"))?;
+        // Check the password
+        if !pfx.verify_mac(password) {
+            return Err(anyhow::anyhow!("The PFX password is incorrect"));
+        }
+        // Get the first private key, which will be PKCS#8
+        let key_der = pfx
+            .key_bags(password)
+            .map_err(|e| anyhow::anyhow!("Could not read the PFX private key: {e:?}"))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("The PFX file has no private key"))?;
+        let private_key = RsaPrivateKey::from_pkcs8_der(&key_der)?;
+        // Get the first certificate
+        let certificate_der = pfx
+            .cert_x509_bags(password)
+            .map_err(|e| anyhow::anyhow!("Could not read the PFX certificate: {e:?}"))?
+            .into_iter()
+            .next()
+            .ok_or_else(|| anyhow::anyhow!("The PFX file has no certificate"))?;
+        Ok(CertificateKey {
+            private_key,
+            certificate_der,
+        })
+    }
+
+    // Loads a certificate and key from a file, treating it as PFX if it has a ".pfx" or ".p12" extension and PEM otherwise.
+    fn load(path: &str, password: Option<&str>) -> anyhow::Result<Self> {
+        let contents = std::fs::read(path)
+            .map_err(|e| anyhow::anyhow!("Could not read the certificate {path:?}: {e}"))?;
+        let lower_case_path = path.to_lowercase();
+        if lower_case_path.ends_with(".pfx") || lower_case_path.ends_with(".p12") {
+            Self::from_pfx(&contents, password.unwrap_or_default())
+        } else {
+            Self::from_pem(&contents)
+        }
+    }
+
+    // The certificate's SHA-1 thumbprint, base64url encoded, as used in the "x5t" JWT header.
+    fn thumbprint(&self) -> String {
+        URL_SAFE_NO_PAD.encode(Sha1::digest(&self.certificate_der))
+    }
+
+    // Creates a signed JWT client assertion for the given client and token URL.
+    fn create_client_assertion(&self, client_id: &str, token_url: &str) -> anyhow::Result<String> {
+        let now = chrono::Utc::now().timestamp();
+        // The header identifies the certificate by its thumbprint
+        let header = serde_json::json!({
+            "alg": "RS256",
+            "typ": "JWT",
+            "x5t": self.thumbprint(),
+        });
+        // The claims identify the client to the token URL
+        let claims = serde_json::json!({
+            "aud": token_url,
+            "iss": client_id,
+            "sub": client_id,
+            "jti": uuid::Uuid::new_v4().to_string(),
+            "nbf": now,
+            "iat": now,
+            "exp": now + ASSERTION_LIFETIME_SECONDS,
+        });
+        // Form the part of the token to be signed
+        let signing_input = format!(
+            "{}.{}",
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&header)?),
+            URL_SAFE_NO_PAD.encode(serde_json::to_vec(&claims)?)
+        );
+        // Sign it with RS256 (RSASSA-PKCS1-v1_5 using SHA-256)
+        let signing_key = SigningKey::<Sha256>::new(self.private_key.clone());
+        let signature = signing_key.try_sign(signing_input.as_bytes())?;
+        // Append the signature
+        Ok(format!(
+            "{signing_input}.{}",
+            URL_SAFE_NO_PAD.encode(signature.to_bytes())
+        ))
+    }
+}
+
+// Gets access tokens using the OAuth2 client credentials flow with a client assertion signed by a certificate.
+pub struct CertificateCredential {
+    // The Azure OAuth2 token URL, e.g. https://login.microsoftonline.com/TENANT_ID/oauth2/token
+    token_url: String,
+    // The token endpoint version token_url refers to
+    token_version: TokenEndpointVersion,
+    // The Azure AD App Registration client ID (a GUID)
+    client_id: String,
+    // The path to the PEM or PFX file with the certificate and private key
+    certificate_path: String,
+    // The PFX file password, if any
+    certificate_password: Option<SecretString>,
+}
+
+impl CertificateCredential {
+    // Creates a new certificate credential
+    pub fn new(
+        token_url: String,
+        token_version: TokenEndpointVersion,
+        client_id: String,
+        certificate_path: String,
+        certificate_password: Option<SecretString>,
+    ) -> Self {
+        CertificateCredential {
+            token_url,
+            token_version,
+            client_id,
+            certificate_path,
+            certificate_password,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenCredential for CertificateCredential {
+    async fn get_token(&self, scopes: &[String]) -> anyhow::Result<AccessToken> {
+        log::debug!("CertificateCredential.get_token - scopes = {scopes:?}");
+        // Load the certificate.  It's loaded for each request so a renewed certificate is picked up without a restart.
+        let certificate = CertificateKey::load(
+            &self.certificate_path,
+            self.certificate_password
+                .as_ref()
+                .map(|p| p.expose_secret()),
+        )?;
+        // Create a signed assertion
+        let assertion = certificate.create_client_assertion(&self.client_id, &self.token_url)?;
+        // Request the token
+        let params = client_assertion_params(&self.client_id, assertion);
+        get_access_token(self.token_url.clone(), self.token_version, scopes, params).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{TestResponse, TestServer};
+    use rsa::pkcs1v15::{Signature, VerifyingKey};
+    use rsa::pkcs8::DecodePublicKey;
+    use rsa::signature::Verifier;
+    use rsa::RsaPublicKey;
+    use x509_cert::der::{Decode, Encode};
+
+    // A self-signed certificate and its PKCS#8 private key
+    const CERTIFICATE_PEM: &[u8] = include_bytes!("../../test_fixtures/certificate.pem");
+    // The same certificate with its key in PKCS#1 ("RSA PRIVATE KEY") format
+    const CERTIFICATE_PKCS1_PEM: &[u8] =
+        include_bytes!("../../test_fixtures/certificate_pkcs1.pem");
+    // The certificate without its key
+    const CERTIFICATE_ONLY_PEM: &[u8] = include_bytes!("../../test_fixtures/certificate_only.pem");
+    // The certificate's SHA-1 thumbprint, as `openssl x509 -fingerprint -sha1` shows it
+    const THUMBPRINT_HEX: &str = "F882D45F8327C2FD7EC8710A9B4FD8BE5D957B43";
+
+    // Gets the public key from a DER certificate, as a token endpoint would.
+    fn certificate_public_key(certificate_der: &[u8]) -> RsaPublicKey {
+        let certificate = x509_cert::Certificate::from_der(certificate_der).unwrap();
+        let public_key_der = certificate
+            .tbs_certificate
+            .subject_public_key_info
+            .to_der()
+            .unwrap();
+        RsaPublicKey::from_public_key_der(&public_key_der).unwrap()
+    }
+
+    // Decodes a base64url JWT part as JSON.
+    fn decode_part(part: &str) -> serde_json::Value {
+        serde_json::from_slice(&URL_SAFE_NO_PAD.decode(part).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn client_assertion_is_signed_by_the_certificate() {
+        let certificate = CertificateKey::from_pem(CERTIFICATE_PEM).unwrap();
+        let token_url = "https://login.microsoftonline.com/tenant/oauth2/token";
+        let assertion = certificate
+            .create_client_assertion("client", token_url)
+            .unwrap();
+        // The assertion is a JWT: header.claims.signature
+        let parts = assertion.split('.').collect::<Vec<_>>();
+        assert_eq!(3, parts.len());
+        // The header names the algorithm and the certificate's thumbprint
+        let header = decode_part(parts[0]);
+        assert_eq!("RS256", header["alg"]);
+        assert_eq!("JWT", header["typ"]);
+        let thumbprint = URL_SAFE_NO_PAD
+            .decode(header["x5t"].as_str().unwrap())
+            .unwrap();
+        let thumbprint_hex = thumbprint
+            .iter()
+            .map(|b| format!("{b:02X}"))
+            .collect::<String>();
+        assert_eq!(THUMBPRINT_HEX, thumbprint_hex);
+        // The claims identify the client to the token URL
+        let claims = decode_part(parts[1]);
+        assert_eq!(token_url, claims["aud"]);
+        assert_eq!("client", claims["iss"]);
+        assert_eq!("client", claims["sub"]);
+        assert!(claims["jti"].is_string());
+        assert_eq!(
+            ASSERTION_LIFETIME_SECONDS,
+            claims["exp"].as_i64().unwrap() - claims["nbf"].as_i64().unwrap()
+        );
+        // The signature verifies with the certificate's public key
+        let verifying_key =
+            VerifyingKey::<Sha256>::new(certificate_public_key(&certificate.certificate_der));
+        let signature =
+            Signature::try_from(URL_SAFE_NO_PAD.decode(parts[2]).unwrap().as_slice()).unwrap();
+        let signing_input = format!("{}.{}", parts[0], parts[1]);
+        verifying_key
+            .verify(signing_input.as_bytes(), &signature)
+            .unwrap();
+        // ...and not with anything else
+        assert!(verifying_key
+            .verify(format!("{signing_input}x").as_bytes(), &signature)
+            .is_err());
+    }
+
+    #[actix_web::test]
+    async fn token_endpoint_gets_a_signed_assertion_and_no_secret() {
+        let certificate = CertificateKey::from_pem(CERTIFICATE_PEM).unwrap();
+        let verifying_key =
+            VerifyingKey::<Sha256>::new(certificate_public_key(&certificate.certificate_der));
+        // The token endpoint only issues a token if the assertion's signature verifies
+        let server = TestServer::start(move |request| {
+            let form = request.form();
+            let assertion = form.get("client_assertion").cloned().unwrap_or_default();
+            let verified = match assertion.rsplit_once('.') {
+                Some((signing_input, signature)) => URL_SAFE_NO_PAD
+                    .decode(signature)
+                    .ok()
+                    .and_then(|signature| Signature::try_from(signature.as_slice()).ok())
+                    .is_some_and(|signature| {
+                        verifying_key
+                            .verify(signing_input.as_bytes(), &signature)
+                            .is_ok()
+                    }),
+                None => false,
+            };
+            if verified {
+                TestResponse::token("token")
+            } else {
+                TestResponse::json(401, serde_json::json!({"error": "invalid_client"}))
+            }
+        });
+        let token_url = format!("{}/tenant/oauth2/token", server.url());
+        let credential = CertificateCredential::new(
+            token_url.clone(),
+            TokenEndpointVersion::V1,
+            "client".to_string(),
+            concat!(env!("CARGO_MANIFEST_DIR"), "/test_fixtures/certificate.pem").to_string(),
+            None,
+        );
+        let token = credential
+            .get_token(&["https://management.azure.com/.default".to_string()])
+            .await
+            .unwrap();
+        assert_eq!("token", token.access_token().expose_secret());
+        // The request was a client credentials grant with an assertion for the token URL, and no secret
+        let requests = server.requests();
+        assert_eq!(1, requests.len());
+        assert_eq!("POST", requests[0].method);
+        assert_eq!("/tenant/oauth2/token", requests[0].path);
+        let form = requests[0].form();
+        assert_eq!("client_credentials", form["grant_type"]);
+        assert_eq!("client", form["client_id"]);
+        assert_eq!(
+            "urn:ietf:params:oauth:client-assertion-type:jwt-bearer",
+            form["client_assertion_type"]
+        );
+        assert_eq!("https://management.azure.com", form["resource"]);
+        assert!(!form.contains_key("client_secret"));
+        let claims = decode_part(form["client_assertion"].split('.').nth(1).unwrap());
+        assert_eq!(token_url, claims["aud"]);
+    }
+
+    #[test]
+    fn from_pem_reads_pkcs8_and_pkcs1_keys() {
+        let pkcs8 = CertificateKey::from_pem(CERTIFICATE_PEM).unwrap();
+        let pkcs1 = CertificateKey::from_pem(CERTIFICATE_PKCS1_PEM).unwrap();
+        assert_eq!(pkcs8.private_key, pkcs1.private_key);
+        assert_eq!(pkcs8.certificate_der, pkcs1.certificate_der);
+        // The key is the certificate's
+        assert_eq!(
+            certificate_public_key(&pkcs8.certificate_der),
+            RsaPublicKey::from(&pkcs8.private_key)
+        );
+    }
+
+    #[test]
+    fn from_pem_rejects_a_file_without_a_key() {
+        let error = CertificateKey::from_pem(CERTIFICATE_ONLY_PEM)
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("no private key"), "{error}");
+    }
+
+    #[test]
+    fn from_pem_rejects_a_file_without_a_certificate() {
+        let key_only = pem::parse_many(CERTIFICATE_PEM)
+            .unwrap()
+            .into_iter()
+            .filter(|block| block.tag() != "CERTIFICATE")
+            .collect::<Vec<_>>();
+        let error = CertificateKey::from_pem(pem::encode_many(&key_only).as_bytes())
+            .err()
+            .unwrap();
+        assert!(error.to_string().contains("no certificate"), "{error}");
+    }
+
+    #[test]
+    fn from_pem_rejects_a_bad_key() {
+        let bad_key = pem::encode(&pem::Pem::new("PRIVATE KEY", b"not a key".to_vec()));
+        let mut contents = CERTIFICATE_ONLY_PEM.to_vec();
+        contents.extend_from_slice(bad_key.as_bytes());
+        assert!(CertificateKey::from_pem(&contents).is_err());
+    }
+
+    #[test]
+    fn from_pem_rejects_something_that_is_not_pem() {
+        assert!(CertificateKey::from_pem(b"-----BEGIN CERTIFICATE-----\n!!!").is_err());
+    }
+}
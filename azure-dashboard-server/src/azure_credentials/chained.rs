@@ -0,0 +1,153 @@
+use crate::azure_credentials::TokenCredential;
+use crate::azure_token_cache::{is_token_request_failure, AccessToken};
+
+// A list of credentials that are tried in order until one of them returns a token.
+pub struct ChainedTokenCredential {
+    // The credentials to try
+    credentials: Vec<Box<dyn TokenCredential>>,
+}
+
+impl ChainedTokenCredential {
+    // Creates a new credential chain
+    pub fn new(credentials: Vec<Box<dyn TokenCredential>>) -> Self {
+        ChainedTokenCredential { credentials }
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenCredential for ChainedTokenCredential {
+    async fn get_token(&self, scopes: &[String]) -> anyhow::Result<AccessToken> {
+        log::debug!("ChainedTokenCredential.get_token - scopes = {scopes:?}");
+        // The errors from each credential that failed
+        let mut errors = Vec::new();
+        // The first failure to request a token, if any, so it's known the token endpoint failed us
+        let mut token_request_failure = None;
+        // For each credential...
+        for credential in &self.credentials {
+            // Try to get a token
+            match credential.get_token(scopes).await {
+                // If successful, we're done
+                Ok(access_token) => return Ok(access_token),
+                // If unsuccessful, remember why and try the next one
+                Err(e) => {
+                    log::debug!(" - credential failed: {e}");
+                    errors.push(e.to_string());
+                    if token_request_failure.is_none() && is_token_request_failure(&e) {
+                        token_request_failure = Some(e);
+                    }
+                }
+            }
+        }
+        // None of the credentials worked
+        let message = format!(
+            "No credential could get an access token: [{}]",
+            errors.join("; ")
+        );
+        Err(match token_request_failure {
+            Some(e) => e.context(message),
+            None => anyhow::anyhow!(message),
+        })
+    }
+
+    // Resolves each credential's secrets.  Only fails if none of them could be, as a credential whose secrets
+    // can't be resolved just won't be the one that gets tokens.
+    async fn resolve_secrets(&self) -> anyhow::Result<()> {
+        // The errors from each credential that failed
+        let mut errors = Vec::new();
+        for credential in &self.credentials {
+            if let Err(e) = credential.resolve_secrets().await {
+                log::warn!("Could not resolve the secrets of a chained credential: {e}");
+                errors.push(e.to_string());
+            }
+        }
+        if !self.credentials.is_empty() && errors.len() == self.credentials.len() {
+            return Err(anyhow::anyhow!(
+                "No credential's secrets could be resolved: [{}]",
+                errors.join("; ")
+            ));
+        }
+        Ok(())
+    }
+
+    async fn client_secret_hint(&self) -> Option<String> {
+        for credential in &self.credentials {
+            if let Some(hint) = credential.client_secret_hint().await {
+                return Some(hint);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::azure_token_cache::TokenRequestError;
+
+    // A credential whose secrets can or can't be resolved.  If they can, the token endpoint refuses it.
+    struct FakeCredential {
+        // Whether its secrets can be resolved
+        resolvable: bool,
+    }
+
+    #[async_trait::async_trait]
+    impl TokenCredential for FakeCredential {
+        async fn get_token(&self, _scopes: &[String]) -> anyhow::Result<AccessToken> {
+            self.resolve_secrets().await?;
+            Err(TokenRequestError {
+                status: reqwest::StatusCode::UNAUTHORIZED,
+                error: Some("invalid_client".to_string()),
+                error_codes: vec![7000215],
+                body: String::new(),
+            }
+            .into())
+        }
+
+        async fn resolve_secrets(&self) -> anyhow::Result<()> {
+            if self.resolvable {
+                Ok(())
+            } else {
+                Err(anyhow::anyhow!("Environment variable SECRET is not set"))
+            }
+        }
+    }
+
+    // Chains credentials whose secrets can or can't be resolved.
+    fn chain(resolvable: &[bool]) -> ChainedTokenCredential {
+        ChainedTokenCredential::new(
+            resolvable
+                .iter()
+                .map(|&resolvable| {
+                    Box::new(FakeCredential { resolvable }) as Box<dyn TokenCredential>
+                })
+                .collect(),
+        )
+    }
+
+    #[actix_web::test]
+    async fn resolve_secrets_succeeds_if_a_later_credential_resolves() {
+        assert!(chain(&[false, true]).resolve_secrets().await.is_ok());
+        assert!(chain(&[true, false]).resolve_secrets().await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn token_request_failure_is_kept_if_no_credential_gets_a_token() {
+        let error = chain(&[false, true]).get_token(&[]).await.unwrap_err();
+        assert!(is_token_request_failure(&error), "{error}");
+        // Every credential's failure is described
+        assert!(error.to_string().contains("SECRET"), "{error}");
+        assert!(error.to_string().contains("401"), "{error}");
+    }
+
+    #[actix_web::test]
+    async fn credential_problems_are_not_token_request_failures() {
+        let error = chain(&[false, false]).get_token(&[]).await.unwrap_err();
+        assert!(!is_token_request_failure(&error), "{error}");
+    }
+
+    #[actix_web::test]
+    async fn resolve_secrets_fails_if_no_credential_resolves() {
+        let error = chain(&[false, false]).resolve_secrets().await.unwrap_err();
+        assert!(error.to_string().contains("SECRET"), "{error}");
+    }
+}
@@ -0,0 +1,89 @@
+use crate::azure_credentials::{TokenCredential, CLIENT_SECRET_HINT_LENGTH};
+use crate::azure_token_cache::{get_access_token, AccessToken, TokenRequestError};
+use crate::secrets::{ResolvableSecret, SecretString};
+use crate::settings::TokenEndpointVersion;
+use std::collections::HashMap;
+
+// Gets access tokens using the OAuth2 client credentials flow with a client ID and secret.
+pub struct ClientSecretCredential {
+    // The Azure OAuth2 token URL, e.g. https://login.microsoftonline.com/TENANT_ID/oauth2/token
+    token_url: String,
+    // The token endpoint version token_url refers to
+    token_version: TokenEndpointVersion,
+    // The Azure AD App Registration client ID (a GUID)
+    client_id: String,
+    // The Azure AD App Registration secret value
+    client_secret: ResolvableSecret,
+}
+
+impl ClientSecretCredential {
+    // Creates a new client secret credential
+    pub fn new(
+        token_url: String,
+        token_version: TokenEndpointVersion,
+        client_id: String,
+        client_secret: ResolvableSecret,
+    ) -> Self {
+        ClientSecretCredential {
+            token_url,
+            token_version,
+            client_id,
+            client_secret,
+        }
+    }
+
+    // Requests a token using the given secret value.
+    async fn request_token(
+        &self,
+        client_secret: SecretString,
+        scopes: &[String],
+    ) -> anyhow::Result<AccessToken> {
+        // Create our parameters
+        let mut params = HashMap::new();
+        params.insert("grant_type", "client_credentials".to_string());
+        params.insert("client_id", self.client_id.clone());
+        params.insert("client_secret", client_secret.expose_secret().to_string());
+        // Request the token
+        get_access_token(self.token_url.clone(), self.token_version, scopes, params).await
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenCredential for ClientSecretCredential {
+    async fn get_token(&self, scopes: &[String]) -> anyhow::Result<AccessToken> {
+        log::debug!("ClientSecretCredential.get_token - scopes = {scopes:?}");
+        // Request a token with the current secret
+        let client_secret = self.client_secret.value().await?;
+        match self.request_token(client_secret, scopes).await {
+            // If the secret was rejected and may have been rotated, get it again and retry
+            Err(e)
+                if self.client_secret.is_refreshable()
+                    && e.downcast_ref::<TokenRequestError>()
+                        .is_some_and(|e| e.is_invalid_client()) =>
+            {
+                log::warn!(
+                    "The client secret for {} was rejected, so resolving it again",
+                    self.client_id
+                );
+                let client_secret = self.client_secret.refresh().await?;
+                self.request_token(client_secret, scopes).await
+            }
+            result => result,
+        }
+    }
+
+    async fn resolve_secrets(&self) -> anyhow::Result<()> {
+        self.client_secret.value().await.map(|_| ())
+    }
+
+    async fn client_secret_hint(&self) -> Option<String> {
+        let client_secret = self.client_secret.value().await.ok()?;
+        Some(
+            client_secret
+                .expose_secret()
+                .chars()
+                .take(CLIENT_SECRET_HINT_LENGTH)
+                .collect(),
+        )
+    }
+}
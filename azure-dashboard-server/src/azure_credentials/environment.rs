@@ -0,0 +1,68 @@
+use crate::azure_credentials::client_secret::ClientSecretCredential;
+use crate::azure_credentials::{TokenCredential, CLIENT_SECRET_HINT_LENGTH};
+use crate::azure_token_cache::AccessToken;
+use crate::secrets::{ResolvableSecret, SecretString};
+use crate::settings::TokenEndpointVersion;
+
+// Gets an environment variable, returning an error naming the variable if it isn't set.
+fn required_env_var(name: &str) -> anyhow::Result<String> {
+    std::env::var(name).map_err(|_| anyhow::anyhow!("Environment variable {name} is not set"))
+}
+
+// Gets access tokens using a client ID and secret read from the standard Azure environment variables:
+// - AZURE_TENANT_ID
+// - AZURE_CLIENT_ID
+// - AZURE_CLIENT_SECRET
+// - AZURE_AUTHORITY_HOST (optional, defaults to the subscription's cloud's authority host)
+// The variables are read each time a token is requested.
+pub struct EnvironmentCredential {
+    // The authority host used if AZURE_AUTHORITY_HOST isn't set
+    default_authority_host: String,
+}
+
+impl EnvironmentCredential {
+    // Creates a new environment credential
+    pub fn new(default_authority_host: String) -> Self {
+        EnvironmentCredential {
+            default_authority_host,
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenCredential for EnvironmentCredential {
+    async fn get_token(&self, scopes: &[String]) -> anyhow::Result<AccessToken> {
+        log::debug!("EnvironmentCredential.get_token - scopes = {scopes:?}");
+        // Read the variables
+        let tenant_id = required_env_var("AZURE_TENANT_ID")?;
+        let client_id = required_env_var("AZURE_CLIENT_ID")?;
+        let client_secret = required_env_var("AZURE_CLIENT_SECRET")?;
+        let authority_host = std::env::var("AZURE_AUTHORITY_HOST")
+            .unwrap_or_else(|_| self.default_authority_host.clone());
+        // Form the v2 token URL for the tenant
+        let token_url = format!(
+            "{}/{tenant_id}/oauth2/v2.0/token",
+            authority_host.trim_end_matches('/')
+        );
+        // Get the token as we would with a configured client secret
+        ClientSecretCredential::new(
+            token_url,
+            TokenEndpointVersion::V2,
+            client_id,
+            ResolvableSecret::literal(client_secret.into()),
+        )
+        .get_token(scopes)
+        .await
+    }
+
+    async fn client_secret_hint(&self) -> Option<String> {
+        let client_secret = SecretString::new(std::env::var("AZURE_CLIENT_SECRET").ok()?);
+        Some(
+            client_secret
+                .expose_secret()
+                .chars()
+                .take(CLIENT_SECRET_HINT_LENGTH)
+                .collect(),
+        )
+    }
+}
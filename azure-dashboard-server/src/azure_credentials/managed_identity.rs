@@ -0,0 +1,432 @@
+use crate::azure_credentials::{scopes_to_resource, TokenCredential};
+use crate::azure_token_cache::{AccessToken, NumberOrString, TokenRequestError};
+use crate::secrets::SecretString;
+use chrono::TimeZone;
+
+// The Azure Instance Metadata Service (IMDS) token endpoint, available on Azure VMs.
+pub const DEFAULT_IMDS_ENDPOINT: &str = "http://169.254.169.254/metadata/identity/oauth2/token";
+// The IMDS API version.
+const IMDS_API_VERSION: &str = "2018-02-01";
+// The App Service managed identity API version.
+const APP_SERVICE_API_VERSION: &str = "2019-08-01";
+
+// The response from a managed identity token request.
+// Unlike the OAuth2 token response, IMDS returns "expires_in" and "expires_on" as strings while App Service
+// returns "expires_on" as a number and may omit "expires_in".
+#[derive(Debug, serde::Deserialize)]
+struct ManagedIdentityTokenResponse {
+    // The access token
+    access_token: SecretString,
+    // The number of seconds in which the token expires
+    expires_in: Option<NumberOrString>,
+    // The date/time, in unix seconds since the Epoch, on which the token expires
+    expires_on: Option<NumberOrString>,
+}
+
+// Tries to create an access token from a managed identity token response.
+impl TryFrom<ManagedIdentityTokenResponse> for AccessToken {
+    type Error = anyhow::Error;
+
+    fn try_from(value: ManagedIdentityTokenResponse) -> Result<Self, Self::Error> {
+        // Prefer "expires_in" (which can be better than "expires_on" if the clocks are different)
+        let expiry_date =
+            if let Some(expires_in) = value.expires_in.as_ref().and_then(|v| v.as_i64()) {
+                chrono::Utc::now() + chrono::Duration::seconds(expires_in)
+            }
+            // Otherwise use "expires_on"
+            else if let Some(expires_on) = value.expires_on.as_ref().and_then(|v| v.as_i64()) {
+                chrono::Utc
+                    .timestamp_opt(expires_on, 0)
+                    .single()
+                    .ok_or_else(|| anyhow::anyhow!("Invalid expires_on {expires_on}"))?
+            } else {
+                return Err(anyhow::anyhow!(
+                    "The managed identity token response has no usable expiry"
+                ));
+            };
+        Ok(AccessToken::new(value.access_token, expiry_date))
+    }
+}
+
+// Which user-assigned managed identity to get tokens for.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ManagedIdentityId {
+    // The identity's client ID
+    Client(String),
+    // The identity's object (principal) ID
+    Object(String),
+    // The identity's ARM resource ID
+    Resource(String),
+}
+
+impl ManagedIdentityId {
+    // Gets the identity from the client, object and resource ID settings, of which at most one may be set.
+    // If none are, the system-assigned identity is used.
+    pub fn from_settings(
+        client_id: &Option<String>,
+        object_id: &Option<String>,
+        resource_id: &Option<String>,
+    ) -> anyhow::Result<Option<Self>> {
+        match (client_id, object_id, resource_id) {
+            (None, None, None) => Ok(None),
+            (Some(client_id), None, None) => Ok(Some(Self::Client(client_id.clone()))),
+            (None, Some(object_id), None) => Ok(Some(Self::Object(object_id.clone()))),
+            (None, None, Some(resource_id)) => Ok(Some(Self::Resource(resource_id.clone()))),
+            _ => Err(anyhow::anyhow!(
+                "Only one of the managed_identity_client_id, managed_identity_object_id and managed_identity_resource_id settings may be set"
+            )),
+        }
+    }
+
+    // The query parameter that selects the identity on IMDS.
+    fn imds_query(&self) -> (&'static str, String) {
+        match self {
+            Self::Client(id) => ("client_id", id.clone()),
+            Self::Object(id) => ("object_id", id.clone()),
+            Self::Resource(id) => ("msi_res_id", id.clone()),
+        }
+    }
+
+    // The query parameter that selects the identity on App Service, which names some of them differently.
+    fn app_service_query(&self) -> (&'static str, String) {
+        match self {
+            Self::Client(id) => ("client_id", id.clone()),
+            Self::Object(id) => ("principal_id", id.clone()),
+            Self::Resource(id) => ("mi_res_id", id.clone()),
+        }
+    }
+}
+
+// Gets access tokens for the managed identity of the Azure VM or App Service the server runs on.
+// - If an endpoint is configured, it's called as IMDS would be.  This allows a local stub to stand in.
+// - Otherwise, if the App Service IDENTITY_ENDPOINT and IDENTITY_HEADER environment variables are set, they're used.
+// - Otherwise IMDS is called at its well-known address.
+pub struct ManagedIdentityCredential {
+    // The configured token endpoint, if any
+    endpoint: Option<String>,
+    // The user-assigned identity.  If not set, the system-assigned identity is used.
+    identity: Option<ManagedIdentityId>,
+}
+
+impl ManagedIdentityCredential {
+    // Creates a new managed identity credential
+    pub fn new(endpoint: Option<String>, identity: Option<ManagedIdentityId>) -> Self {
+        ManagedIdentityCredential { endpoint, identity }
+    }
+
+    // Gets the App Service endpoint and header from the environment, if we're running in App Service.
+    fn app_service_environment() -> Option<(String, String)> {
+        match (
+            std::env::var("IDENTITY_ENDPOINT"),
+            std::env::var("IDENTITY_HEADER"),
+        ) {
+            (Ok(identity_endpoint), Ok(identity_header)) => {
+                Some((identity_endpoint, identity_header))
+            }
+            _ => None,
+        }
+    }
+
+    // Creates the token request for the environment we're running in.
+    // The App Service endpoint and header are given if we're running in App Service.
+    fn create_request(
+        &self,
+        client: &reqwest::Client,
+        resource: &str,
+        app_service: Option<(String, String)>,
+    ) -> reqwest::RequestBuilder {
+        // The query parameters common to both endpoints
+        let mut query = vec![("resource", resource.to_string())];
+        // If we're in App Service (and haven't been told to use a specific endpoint)...
+        if let (None, Some((identity_endpoint, identity_header))) = (&self.endpoint, app_service) {
+            log::debug!(" - using the App Service endpoint {identity_endpoint}");
+            query.extend(
+                self.identity
+                    .as_ref()
+                    .map(ManagedIdentityId::app_service_query),
+            );
+            query.push(("api-version", APP_SERVICE_API_VERSION.to_string()));
+            client
+                .get(identity_endpoint)
+                .header("X-IDENTITY-HEADER", identity_header)
+                .query(&query)
+        }
+        // Otherwise use IMDS
+        else {
+            let endpoint = self
+                .endpoint
+                .clone()
+                .unwrap_or_else(|| DEFAULT_IMDS_ENDPOINT.to_string());
+            log::debug!(" - using the IMDS endpoint {endpoint}");
+            query.extend(self.identity.as_ref().map(ManagedIdentityId::imds_query));
+            query.push(("api-version", IMDS_API_VERSION.to_string()));
+            client
+                .get(endpoint)
+                .header("Metadata", "true")
+                .query(&query)
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenCredential for ManagedIdentityCredential {
+    async fn get_token(&self, scopes: &[String]) -> anyhow::Result<AccessToken> {
+        log::debug!("ManagedIdentityCredential.get_token - scopes = {scopes:?}");
+        // Managed identity endpoints take a resource rather than scopes
+        let resource = scopes_to_resource(scopes)?;
+        // Create a client
+        let client = reqwest::Client::new();
+        // Make the request
+        let response = self
+            .create_request(&client, &resource, Self::app_service_environment())
+            .send()
+            .await?;
+        // If the response was successful...
+        if reqwest::StatusCode::OK == response.status() {
+            // Deserialize the JSON and create an access token from it
+            let token_response = response.json::<ManagedIdentityTokenResponse>().await?;
+            token_response.try_into()
+        }
+        // If the response was unsuccessful...
+        else {
+            // Return an error.
+            Err(TokenRequestError::from_response(response).await.into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{TestResponse, TestServer};
+
+    // The resource tokens are requested for
+    const RESOURCE: &str = "https://management.azure.com";
+    // The App Service endpoint and header, as the environment would give them
+    const APP_SERVICE_ENDPOINT: &str = "http://localhost:8081/msi/token";
+    const APP_SERVICE_HEADER: &str = "identity-header";
+
+    // Builds the token request for the given credential, inside or outside App Service.
+    fn build_request(
+        credential: &ManagedIdentityCredential,
+        in_app_service: bool,
+    ) -> reqwest::Request {
+        let app_service = in_app_service.then(|| {
+            (
+                APP_SERVICE_ENDPOINT.to_string(),
+                APP_SERVICE_HEADER.to_string(),
+            )
+        });
+        credential
+            .create_request(&reqwest::Client::new(), RESOURCE, app_service)
+            .build()
+            .unwrap()
+    }
+
+    // Gets a query parameter from a request, if it's there.
+    fn query(request: &reqwest::Request, name: &str) -> Option<String> {
+        request
+            .url()
+            .query_pairs()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.into_owned())
+    }
+
+    // Creates an access token from a token response.
+    fn parse_token(json: serde_json::Value) -> anyhow::Result<AccessToken> {
+        serde_json::from_value::<ManagedIdentityTokenResponse>(json)
+            .unwrap()
+            .try_into()
+    }
+
+    #[test]
+    fn imds_request_has_the_metadata_header() {
+        let request = build_request(&ManagedIdentityCredential::new(None, None), false);
+        assert!(request.url().as_str().starts_with(DEFAULT_IMDS_ENDPOINT));
+        assert_eq!("true", request.headers()["Metadata"]);
+        assert!(!request.headers().contains_key("X-IDENTITY-HEADER"));
+        assert_eq!(Some(RESOURCE.to_string()), query(&request, "resource"));
+        assert_eq!(
+            Some(IMDS_API_VERSION.to_string()),
+            query(&request, "api-version")
+        );
+    }
+
+    #[test]
+    fn app_service_request_has_the_identity_header() {
+        let request = build_request(&ManagedIdentityCredential::new(None, None), true);
+        assert!(request.url().as_str().starts_with(APP_SERVICE_ENDPOINT));
+        assert_eq!(APP_SERVICE_HEADER, request.headers()["X-IDENTITY-HEADER"]);
+        assert!(!request.headers().contains_key("Metadata"));
+        assert_eq!(Some(RESOURCE.to_string()), query(&request, "resource"));
+        assert_eq!(
+            Some(APP_SERVICE_API_VERSION.to_string()),
+            query(&request, "api-version")
+        );
+    }
+
+    #[test]
+    fn configured_endpoint_is_used_even_in_app_service() {
+        let credential =
+            ManagedIdentityCredential::new(Some("http://localhost:9999/token".to_string()), None);
+        let request = build_request(&credential, true);
+        assert!(request
+            .url()
+            .as_str()
+            .starts_with("http://localhost:9999/token"));
+        assert_eq!("true", request.headers()["Metadata"]);
+        assert!(!request.headers().contains_key("X-IDENTITY-HEADER"));
+    }
+
+    #[test]
+    fn user_assigned_identity_is_selected_by_each_endpoints_own_parameter() {
+        // (identity, IMDS parameter, App Service parameter)
+        let cases = [
+            (
+                ManagedIdentityId::Client("c".to_string()),
+                "client_id",
+                "client_id",
+            ),
+            (
+                ManagedIdentityId::Object("o".to_string()),
+                "object_id",
+                "principal_id",
+            ),
+            (
+                ManagedIdentityId::Resource("r".to_string()),
+                "msi_res_id",
+                "mi_res_id",
+            ),
+        ];
+        for (identity, imds_name, app_service_name) in cases {
+            let id = match &identity {
+                ManagedIdentityId::Client(id)
+                | ManagedIdentityId::Object(id)
+                | ManagedIdentityId::Resource(id) => id.clone(),
+            };
+            let credential = ManagedIdentityCredential::new(None, Some(identity));
+            let imds = build_request(&credential, false);
+            assert_eq!(Some(id.clone()), query(&imds, imds_name), "{imds_name}");
+            let app_service = build_request(&credential, true);
+            assert_eq!(
+                Some(id),
+                query(&app_service, app_service_name),
+                "{app_service_name}"
+            );
+        }
+        // The system-assigned identity is used if none is given
+        let imds = build_request(&ManagedIdentityCredential::new(None, None), false);
+        assert_eq!(
+            vec!["resource", "api-version"],
+            imds.url()
+                .query_pairs()
+                .map(|(name, _)| name.into_owned())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn only_one_identity_setting_may_be_given() {
+        let id = Some("id".to_string());
+        assert_eq!(
+            None,
+            ManagedIdentityId::from_settings(&None, &None, &None).unwrap()
+        );
+        assert_eq!(
+            Some(ManagedIdentityId::Object("id".to_string())),
+            ManagedIdentityId::from_settings(&None, &id, &None).unwrap()
+        );
+        assert_eq!(
+            Some(ManagedIdentityId::Resource("id".to_string())),
+            ManagedIdentityId::from_settings(&None, &None, &id).unwrap()
+        );
+        assert!(ManagedIdentityId::from_settings(&id, &id, &None).is_err());
+        assert!(ManagedIdentityId::from_settings(&id, &None, &id).is_err());
+    }
+
+    #[test]
+    fn imds_response_with_expiry_strings_is_parsed() {
+        let before = chrono::Utc::now();
+        let token = parse_token(serde_json::json!({
+            "access_token": "token",
+            "expires_in": "3599",
+            "expires_on": "1700000000",
+            "resource": RESOURCE,
+            "token_type": "Bearer",
+        }))
+        .unwrap();
+        assert_eq!("token", token.access_token().expose_secret());
+        // "expires_in" is preferred to "expires_on"
+        assert!(token.expiry_date() >= before + chrono::Duration::seconds(3599));
+        assert!(token.expiry_date() <= chrono::Utc::now() + chrono::Duration::seconds(3599));
+    }
+
+    #[test]
+    fn expires_on_is_read_as_a_string_or_a_number() {
+        for expires_on in [
+            serde_json::json!("1700000000"),
+            serde_json::json!(1700000000),
+        ] {
+            let token = parse_token(serde_json::json!({
+                "access_token": "token",
+                "expires_on": expires_on,
+                "resource": RESOURCE,
+                "token_type": "Bearer",
+                "client_id": "c",
+            }))
+            .unwrap();
+            assert_eq!(1700000000, token.expiry_date().timestamp(), "{expires_on}");
+        }
+    }
+
+    #[test]
+    fn response_without_a_usable_expiry_is_refused() {
+        assert!(parse_token(serde_json::json!({"access_token": "token"})).is_err());
+        assert!(parse_token(serde_json::json!({
+            "access_token": "token",
+            "expires_on": "soon",
+        }))
+        .is_err());
+    }
+
+    #[actix_web::test]
+    async fn configured_endpoint_is_called_as_imds() {
+        let server = TestServer::start(|request| {
+            if Some("true") == request.header("Metadata") {
+                TestResponse::json(
+                    200,
+                    serde_json::json!({
+                        "access_token": "token",
+                        "expires_in": "3599",
+                        "expires_on": "1700000000",
+                    }),
+                )
+            } else {
+                TestResponse::json(400, serde_json::json!({"error": "invalid_request"}))
+            }
+        });
+        let credential = ManagedIdentityCredential::new(
+            Some(format!("{}/metadata/identity/oauth2/token", server.url())),
+            Some(ManagedIdentityId::Client("c".to_string())),
+        );
+        let token = credential
+            .get_token(&[format!("{RESOURCE}/.default")])
+            .await
+            .unwrap();
+        assert_eq!("token", token.access_token().expose_secret());
+        let requests = server.requests();
+        assert_eq!(1, requests.len());
+        assert_eq!(Some(RESOURCE.to_string()), requests[0].query("resource"));
+        assert_eq!(Some("c".to_string()), requests[0].query("client_id"));
+        // A failure is a token request error
+        let server = TestServer::start(|_| {
+            TestResponse::json(400, serde_json::json!({"error": "invalid_request"}))
+        });
+        let credential = ManagedIdentityCredential::new(Some(server.url().to_string()), None);
+        let error = credential
+            .get_token(&[format!("{RESOURCE}/.default")])
+            .await
+            .unwrap_err();
+        assert!(error.downcast_ref::<TokenRequestError>().is_some());
+    }
+}
@@ -0,0 +1,143 @@
+use crate::azure_cloud::CloudProfile;
+use crate::azure_token_cache::AccessToken;
+use crate::secrets::{ResolvableSecret, SecretResolver};
+use crate::settings::{required_setting, CredentialKind, CredentialSettings, TokenEndpointVersion};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+pub mod azure_cli;
+pub mod certificate;
+pub mod chained;
+pub mod client_secret;
+pub mod environment;
+pub mod managed_identity;
+pub mod workload_identity;
+
+// A source of access tokens, e.g. a client ID and secret, or the Azure CLI's token cache.
+#[async_trait::async_trait]
+pub trait TokenCredential: Send + Sync {
+    // Gets a new access token for the given OAuth2 scopes, e.g. ["https://management.azure.com/.default"]
+    async fn get_token(&self, scopes: &[String]) -> anyhow::Result<AccessToken>;
+
+    // Resolves any secrets the credential uses, e.g. from Key Vault.
+    // Secrets are otherwise resolved when first needed.
+    async fn resolve_secrets(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    // Gets the first few characters of the client secret, if the credential uses one.
+    // Azure AD shows these as the secret's "hint", which tells it apart from the app's other secrets.
+    async fn client_secret_hint(&self) -> Option<String> {
+        None
+    }
+}
+
+// The number of characters of a client secret that Azure AD shows as its hint.
+pub const CLIENT_SECRET_HINT_LENGTH: usize = 3;
+
+// Gets the resource that a list of scopes is for, for endpoints that take a v1 "resource" rather than scopes,
+// e.g. ["https://management.azure.com/.default"] is for "https://management.azure.com".
+pub fn scopes_to_resource(scopes: &[String]) -> anyhow::Result<String> {
+    match scopes {
+        [scope] => Ok(scope.trim_end_matches("/.default").to_string()),
+        _ => Err(anyhow::anyhow!(
+            "Exactly one scope is needed to request a v1 token, but got {scopes:?}"
+        )),
+    }
+}
+
+// The client assertion type for a signed JWT.
+const JWT_BEARER_ASSERTION_TYPE: &str = "urn:ietf:params:oauth:client-assertion-type:jwt-bearer";
+
+// Creates the token request parameters for the client credentials flow using a client assertion instead of a secret.
+pub fn client_assertion_params(
+    client_id: &str,
+    client_assertion: String,
+) -> HashMap<&'static str, String> {
+    let mut params = HashMap::new();
+    params.insert("grant_type", "client_credentials".to_string());
+    params.insert("client_id", client_id.to_string());
+    params.insert(
+        "client_assertion_type",
+        JWT_BEARER_ASSERTION_TYPE.to_string(),
+    );
+    params.insert("client_assertion", client_assertion);
+    params
+}
+
+// Creates a single credential of the given kind for the given cloud.
+fn create_credential_of_kind(
+    kind: &CredentialKind,
+    settings: &CredentialSettings,
+    cloud: &CloudProfile,
+    secret_resolver: &Arc<SecretResolver>,
+) -> anyhow::Result<Box<dyn TokenCredential>> {
+    Ok(match kind {
+        CredentialKind::ClientSecret => Box::new(client_secret::ClientSecretCredential::new(
+            settings.token_url(cloud)?,
+            settings.token_version,
+            required_setting("client_id", &settings.client_id)?,
+            ResolvableSecret::new(
+                settings.client_secret.clone().ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "The client_secret setting is required by the configured credentials"
+                    )
+                })?,
+                secret_resolver.clone(),
+            ),
+        )),
+        CredentialKind::Environment => Box::new(environment::EnvironmentCredential::new(
+            cloud.authority_host.clone(),
+        )),
+        CredentialKind::AzureCli => Box::new(azure_cli::AzureCliCredential::new(required_setting(
+            "tenant_id",
+            &settings.tenant_id,
+        )?)),
+        CredentialKind::Certificate => Box::new(certificate::CertificateCredential::new(
+            settings.token_url(cloud)?,
+            settings.token_version,
+            required_setting("client_id", &settings.client_id)?,
+            required_setting("certificate_path", &settings.certificate_path)?,
+            settings.certificate_password.clone(),
+        )),
+        CredentialKind::ManagedIdentity => {
+            Box::new(managed_identity::ManagedIdentityCredential::new(
+                settings.managed_identity_endpoint.clone(),
+                managed_identity::ManagedIdentityId::from_settings(
+                    &settings.managed_identity_client_id,
+                    &settings.managed_identity_object_id,
+                    &settings.managed_identity_resource_id,
+                )?,
+            ))
+        }
+        CredentialKind::WorkloadIdentity => {
+            Box::new(workload_identity::WorkloadIdentityCredential::new(
+                settings.token_url(cloud)?,
+                settings.token_version,
+                required_setting("client_id", &settings.client_id)?,
+                settings.federated_token_file.clone(),
+            ))
+        }
+    })
+}
+
+// Creates the credential described by the given settings for the given cloud.
+// If more than one kind of credential is configured, they're tried in order until one succeeds.
+pub fn create_credential(
+    settings: &CredentialSettings,
+    cloud: &CloudProfile,
+    secret_resolver: &Arc<SecretResolver>,
+) -> anyhow::Result<Box<dyn TokenCredential>> {
+    // Create a credential for each configured kind
+    let mut credentials = settings
+        .credentials
+        .iter()
+        .map(|kind| create_credential_of_kind(kind, settings, cloud, secret_resolver))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    // If there's exactly one, use it as-is.  Otherwise chain them.
+    if 1 == credentials.len() {
+        Ok(credentials.remove(0))
+    } else {
+        Ok(Box::new(chained::ChainedTokenCredential::new(credentials)))
+    }
+}
@@ -0,0 +1,183 @@
+use crate::azure_credentials::{client_assertion_params, TokenCredential};
+use crate::azure_token_cache::{get_access_token, AccessToken};
+use crate::settings::TokenEndpointVersion;
+
+// The environment variable the workload identity webhook sets to the path of the projected token file.
+pub const FEDERATED_TOKEN_FILE_VARIABLE: &str = "AZURE_FEDERATED_TOKEN_FILE";
+
+// Gets access tokens by exchanging a federated token (e.g. a Kubernetes service account token projected into the pod)
+// for an Azure AD token, using the federated token as a client assertion.
+pub struct WorkloadIdentityCredential {
+    // The Azure OAuth2 token URL, e.g. https://login.microsoftonline.com/TENANT_ID/oauth2/token
+    token_url: String,
+    // The token endpoint version token_url refers to
+    token_version: TokenEndpointVersion,
+    // The Azure AD App Registration client ID (a GUID)
+    client_id: String,
+    // The path of the federated token file.  If not set, AZURE_FEDERATED_TOKEN_FILE is used.
+    federated_token_file: Option<String>,
+}
+
+impl WorkloadIdentityCredential {
+    // Creates a new workload identity credential
+    pub fn new(
+        token_url: String,
+        token_version: TokenEndpointVersion,
+        client_id: String,
+        federated_token_file: Option<String>,
+    ) -> Self {
+        WorkloadIdentityCredential {
+            token_url,
+            token_version,
+            client_id,
+            federated_token_file,
+        }
+    }
+
+    // Gets the path of the federated token file from the settings or, failing that, the given
+    // AZURE_FEDERATED_TOKEN_FILE environment variable.
+    fn federated_token_path(&self, environment_path: Option<String>) -> anyhow::Result<String> {
+        match (&self.federated_token_file, environment_path) {
+            (Some(path), _) => Ok(path.clone()),
+            (None, Some(path)) => Ok(path),
+            (None, None) => Err(anyhow::anyhow!(
+                "Environment variable {FEDERATED_TOKEN_FILE_VARIABLE} is not set"
+            )),
+        }
+    }
+
+    // Reads the current federated token.
+    // The file is re-read for each request because the kubelet rotates it periodically.
+    fn read_federated_token(&self) -> anyhow::Result<String> {
+        // Get the path from the settings or the environment
+        let path = self.federated_token_path(std::env::var(FEDERATED_TOKEN_FILE_VARIABLE).ok())?;
+        log::debug!(" - reading federated token from {path}");
+        // Read the token
+        let token = std::fs::read_to_string(&path).map_err(|e| {
+            anyhow::anyhow!("Could not read the federated token file {path:?}: {e}")
+        })?;
+        let token = token.trim();
+        if token.is_empty() {
+            return Err(anyhow::anyhow!(
+                "The federated token file {path:?} is empty"
+            ));
+        }
+        Ok(token.to_string())
+    }
+}
+
+#[async_trait::async_trait]
+impl TokenCredential for WorkloadIdentityCredential {
+    async fn get_token(&self, scopes: &[String]) -> anyhow::Result<AccessToken> {
+        log::debug!("WorkloadIdentityCredential.get_token - scopes = {scopes:?}");
+        // Read the federated token
+        let assertion = self.read_federated_token()?;
+        // Exchange it for an access token
+        let params = client_assertion_params(&self.client_id, assertion);
+        get_access_token(self.token_url.clone(), self.token_version, scopes, params).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{TestResponse, TestServer};
+
+    // Gets a path for a federated token file that's unique to the test.
+    fn federated_token_file(name: &str) -> String {
+        std::env::temp_dir()
+            .join(format!("federated-token-{}-{name}", std::process::id()))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    // Creates a credential that reads the given file, with a token endpoint on the given server.
+    fn credential(server: &TestServer, path: Option<&str>) -> WorkloadIdentityCredential {
+        WorkloadIdentityCredential::new(
+            format!("{}/tenant/oauth2/v2.0/token", server.url()),
+            TokenEndpointVersion::V2,
+            "client".to_string(),
+            path.map(str::to_string),
+        )
+    }
+
+    #[actix_web::test]
+    async fn federated_token_file_is_reread_for_each_token() {
+        let server = TestServer::start(|_| TestResponse::token("token"));
+        let path = federated_token_file("reread");
+        let credential = credential(&server, Some(&path));
+        let scopes = ["https://management.azure.com/.default".to_string()];
+        // The kubelet rotates the file between the two requests
+        std::fs::write(&path, "federated-1\n").unwrap();
+        credential.get_token(&scopes).await.unwrap();
+        std::fs::write(&path, "federated-2\n").unwrap();
+        credential.get_token(&scopes).await.unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // Each request's assertion was the file's content at the time
+        let forms = server
+            .requests()
+            .iter()
+            .map(|request| request.form())
+            .collect::<Vec<_>>();
+        assert_eq!(2, forms.len());
+        assert_eq!("federated-1", forms[0]["client_assertion"]);
+        assert_eq!("federated-2", forms[1]["client_assertion"]);
+        assert_eq!(
+            "urn:ietf:params:oauth:client-assertion-type:jwt-bearer",
+            forms[0]["client_assertion_type"]
+        );
+        assert_eq!(scopes[0], forms[0]["scope"]);
+        assert!(!forms[0].contains_key("client_secret"));
+    }
+
+    #[actix_web::test]
+    async fn missing_or_empty_federated_token_file_is_an_error() {
+        let server = TestServer::start(|_| TestResponse::token("token"));
+        let scopes = ["https://management.azure.com/.default".to_string()];
+        // The file doesn't exist
+        let path = federated_token_file("missing");
+        let error = credential(&server, Some(&path))
+            .get_token(&scopes)
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("Could not read"), "{error}");
+        // The file is empty
+        let path = federated_token_file("empty");
+        std::fs::write(&path, "\n").unwrap();
+        let error = credential(&server, Some(&path))
+            .get_token(&scopes)
+            .await
+            .unwrap_err();
+        std::fs::remove_file(&path).unwrap();
+        assert!(error.to_string().contains("is empty"), "{error}");
+        // Neither asked for a token
+        assert!(server.requests().is_empty());
+    }
+
+    #[test]
+    fn federated_token_path_comes_from_the_settings_or_the_environment() {
+        let server = TestServer::start(|_| TestResponse::token("token"));
+        // The setting is preferred
+        assert_eq!(
+            "/configured",
+            credential(&server, Some("/configured"))
+                .federated_token_path(Some("/environment".to_string()))
+                .unwrap()
+        );
+        // Otherwise AZURE_FEDERATED_TOKEN_FILE is used
+        assert_eq!(
+            "/environment",
+            credential(&server, None)
+                .federated_token_path(Some("/environment".to_string()))
+                .unwrap()
+        );
+        // If neither is set, the error names the variable
+        let error = credential(&server, None)
+            .federated_token_path(None)
+            .unwrap_err();
+        assert!(
+            error.to_string().contains(FEDERATED_TOKEN_FILE_VARIABLE),
+            "{error}"
+        );
+    }
+}
@@ -1,63 +1,85 @@
-use crate::settings::SubscriptionSettings;
+use crate::azure_cloud::CloudProfile;
+use crate::azure_credentials::scopes_to_resource;
+use crate::azure_credentials::{create_credential, TokenCredential};
+use crate::secrets::{SecretResolver, SecretString};
+use crate::settings::{CredentialSettings, DashboardSettings, TokenEndpointVersion};
 use chrono::TimeZone;
 use std::collections::HashMap;
 use std::ops::{Deref, Sub};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex, RwLock};
 
+// A value that token endpoints return as either a number or a string, depending on the endpoint.
+#[derive(Debug, serde::Deserialize)]
+#[serde(untagged)]
+pub enum NumberOrString {
+    Number(i64),
+    String(String),
+}
+impl NumberOrString {
+    // Tries to get the value as a number.
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            NumberOrString::Number(n) => Some(*n),
+            NumberOrString::String(s) => s.parse::<i64>().ok(),
+        }
+    }
+}
+
 // The response from an access token request.
+// The v1 endpoint returns "expires_in" and "expires_on" as strings.
+// The v2 endpoint returns "expires_in" as a number and doesn't return "expires_on".
 #[derive(Debug, serde::Deserialize)]
 struct TokenResponse {
     // The token type (should be "Bearer")
     token_type: String,
     // The number of seconds in which the token expires
-    expires_in: String,
-    // The date/time, in unix seconds since the Epoch, on which the token expires
-    expires_on: String,
+    expires_in: NumberOrString,
+    // The date/time, in unix seconds since the Epoch, on which the token expires (v1 only)
+    expires_on: Option<NumberOrString>,
     // The access token
-    access_token: String,
+    access_token: SecretString,
 }
 impl TokenResponse {
     pub fn token_type(&self) -> String {
         self.token_type.clone()
     }
-    pub fn expires_in(&self) -> String {
-        self.expires_in.clone()
-    }
-    pub fn expires_on(&self) -> String {
-        self.expires_on.clone()
-    }
-    pub fn access_token(&self) -> String {
+    pub fn access_token(&self) -> SecretString {
         self.access_token.clone()
     }
 }
 
 // An access token
 #[derive(Debug)]
-struct AccessToken {
-    access_token: String,
+pub struct AccessToken {
+    access_token: SecretString,
     expiry_date: chrono::DateTime<chrono::Utc>,
 }
 impl AccessToken {
     // Creates a new access token
-    pub fn new(access_token: String, expiry_date: chrono::DateTime<chrono::Utc>) -> Self {
+    pub fn new(access_token: SecretString, expiry_date: chrono::DateTime<chrono::Utc>) -> Self {
         AccessToken {
             access_token,
             expiry_date,
         }
     }
     // The access token.
-    pub fn access_token(&self) -> String {
+    pub fn access_token(&self) -> SecretString {
         self.access_token.clone()
     }
     // The expiry date.
     pub fn expiry_date(&self) -> chrono::DateTime<chrono::Utc> {
-        self.expiry_date.clone()
+        self.expiry_date
     }
     // Whether the token has expired.
     pub fn is_expired(&self) -> bool {
         // The token is expired if the expiry date is earlier than now
         self.expiry_date < chrono::offset::Utc::now()
     }
+    // Whether the token is due to be refreshed, i.e. will expire within the given skew.
+    pub fn needs_refresh(&self, refresh_skew: chrono::Duration) -> bool {
+        self.expiry_date - refresh_skew < chrono::offset::Utc::now()
+    }
 }
 
 // Tries to create an access token from a token response.
@@ -69,8 +91,8 @@ impl TryFrom<TokenResponse> for AccessToken {
         // Get the "expires_in" in seconds (which can be better than "expires_on" if the clocks are different
         let expires_in = value
             .expires_in
-            .parse::<i64>()
-            .map_err(anyhow::Error::from)?;
+            .as_i64()
+            .ok_or_else(|| anyhow::anyhow!("Invalid expires_in {:?}", value.expires_in))?;
         // Add it to "now" to get the expiry date
         let expiry_date = chrono::Utc::now() + chrono::Duration::seconds(expires_in);
         // Create the access token
@@ -80,26 +102,99 @@ impl TryFrom<TokenResponse> for AccessToken {
     }
 }
 
-// Gets a new access token by making a request to the token URL and parsing the response.
-async fn get_access_token(
+// The error response from a token request, e.g.
+// ```json
+// {
+//   "error": "invalid_client",
+//   "error_description": "AADSTS7000215: Invalid client secret provided. ...",
+//   "error_codes": [7000215]
+// }
+// ```
+#[derive(Debug, serde::Deserialize)]
+struct TokenErrorResponse {
+    // The OAuth2 error, e.g. "invalid_client"
+    error: String,
+    // The Azure AD error codes, e.g. [7000215]
+    #[serde(default)]
+    error_codes: Vec<i64>,
+}
+
+// The Azure AD error codes for a client secret that's wrong or has expired.
+const INVALID_CLIENT_SECRET_ERROR_CODES: [i64; 2] = [7000215, 7000222];
+
+// A failed token request.
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to get access token.  Error response: {status:?} = {body:?}")]
+pub struct TokenRequestError {
+    // The HTTP status
+    pub status: reqwest::StatusCode,
+    // The OAuth2 error, e.g. "invalid_client", if the response had one
+    pub error: Option<String>,
+    // The Azure AD error codes, e.g. [7000215]
+    pub error_codes: Vec<i64>,
+    // The response body
+    pub body: String,
+}
+impl TokenRequestError {
+    // Creates the error from a token response
+    pub async fn from_response(response: reqwest::Response) -> Self {
+        let status = response.status();
+        let body = response.text().await.unwrap_or_default();
+        // Try to read the error details from the body
+        let error_response = serde_json::from_str::<TokenErrorResponse>(&body).ok();
+        TokenRequestError {
+            status,
+            error: error_response.as_ref().map(|r| r.error.clone()),
+            error_codes: error_response.map(|r| r.error_codes).unwrap_or_default(),
+            body,
+        }
+    }
+    // The most specific error code, e.g. "AADSTS7000222", or the OAuth2 error, e.g. "invalid_client".
+    pub fn error_code(&self) -> String {
+        match self.error_codes.first() {
+            Some(code) => format!("AADSTS{code}"),
+            None => self
+                .error
+                .clone()
+                .unwrap_or_else(|| self.status.as_u16().to_string()),
+        }
+    }
+    // Whether the request failed because the client's credentials (e.g. its secret) were rejected.
+    pub fn is_invalid_client(&self) -> bool {
+        self.error.as_deref() == Some("invalid_client")
+            || self
+                .error_codes
+                .iter()
+                .any(|code| INVALID_CLIENT_SECRET_ERROR_CODES.contains(code))
+    }
+}
+
+// Whether getting a token failed because the token endpoint couldn't be reached or refused the request, rather
+// than because of how the credential is set up, e.g. a secret that couldn't be resolved.
+pub fn is_token_request_failure(error: &anyhow::Error) -> bool {
+    error
+        .chain()
+        .any(|cause| cause.is::<TokenRequestError>() || cause.is::<reqwest::Error>())
+}
+
+// Gets a new access token by posting the given form parameters to the token URL and parsing the response.
+// What's being requested is added to the parameters in the form the endpoint version expects:
+// - v1 takes the "resource" the scopes are for
+// - v2 takes the space-separated "scope" list
+pub async fn get_access_token(
     token_url: String,
-    client_id: String,
-    client_secret: String,
-    resource: String,
+    token_version: TokenEndpointVersion,
+    scopes: &[String],
+    mut params: HashMap<&'static str, String>,
 ) -> anyhow::Result<AccessToken> {
-    log::debug!("get_access_token - token_url = {token_url}");
+    log::debug!("get_access_token - token_url = {token_url}, scopes = {scopes:?}");
     // Create a client
     let client = reqwest::Client::new();
-    // Create our parameters
-    let mut params = HashMap::new();
-    params.insert("grant_type", "client_credentials");
-    params.insert("client_id", client_id.as_str());
-    params.insert("client_secret", client_secret.as_str());
-    params.insert("resource", resource.as_str());
-    params.insert(
-        "scope",
-        "subscriptions/72c42748-5070-4db3-bd42-e250884dbdd5",
-    );
+    // Add what we're requesting
+    match token_version {
+        TokenEndpointVersion::V1 => params.insert("resource", scopes_to_resource(scopes)?),
+        TokenEndpointVersion::V2 => params.insert("scope", scopes.join(" ")),
+    };
     // Post the request
     let response = client.post(token_url).form(&params).send().await?;
     // If the response was successful...
@@ -116,115 +211,678 @@ async fn get_access_token(
     // If the response was unsuccessful...
     else {
         // Return an error.
-        Err(anyhow::anyhow!(
-            "Failed to get access token.  Error response: {:?} = {:?}",
-            response.status(),
-            response.text().await?
-        ))
+        Err(TokenRequestError::from_response(response).await.into())
+    }
+}
+
+// The shortest time between background refreshes, so a token that lives shorter than the refresh skew
+// doesn't cause a refresh loop.
+const MIN_BACKGROUND_REFRESH_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+// How long to wait after a failed background refresh before trying again.
+const BACKGROUND_REFRESH_RETRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(30);
+
+// The result of an attempt to get a new access token.
+#[derive(Clone, Debug)]
+pub struct TokenAcquisition {
+    // When the attempt was made
+    pub attempted_at: chrono::DateTime<chrono::Utc>,
+    // Whether a token was returned
+    pub succeeded: bool,
+    // The error code, e.g. "AADSTS7000222", if the token endpoint returned one
+    pub error_code: Option<String>,
+    // The error message, if the attempt failed
+    pub error_message: Option<String>,
+}
+
+impl TokenAcquisition {
+    // Creates the result of an attempt made now.
+    fn from_result(result: &anyhow::Result<AccessToken>) -> Self {
+        let error = result.as_ref().err();
+        TokenAcquisition {
+            attempted_at: chrono::Utc::now(),
+            succeeded: error.is_none(),
+            error_code: error
+                .and_then(|e| e.downcast_ref::<TokenRequestError>())
+                .map(|e| e.error_code()),
+            error_message: error.map(|e| e.to_string()),
+        }
     }
 }
 
+// The state of a token cache, for health reporting.
+#[derive(Clone, Debug)]
+pub struct TokenHealth {
+    // The result of the most recent attempt to get a token, if one has been made
+    pub last_acquisition: Option<TokenAcquisition>,
+    // When the cached token expires, if there is one
+    pub token_expiry: Option<chrono::DateTime<chrono::Utc>>,
+}
+
 // An access token cache for a particular subscription.
 // When asked for a token:
 // - If no token been fetched, fetches a new token.
 // - If a token has been fetched previously and the token has not expired, returns the token.
 // - If a token has been fetched previously and the token has expired, fetches a new token and caches it for future requests.
+// Only one token request is made at a time.  Callers that need a token while a request is in flight wait for it.
+// Tokens are considered due for refresh "refresh_skew" before they expire.  If background refresh is running,
+// tokens are renewed then, so callers only wait for a token on startup or if a background refresh has failed.
+// Otherwise, the first caller after a token is due renews it.
 pub struct AccessTokenCache {
-    // The subscription info
-    subscription_settings: SubscriptionSettings,
+    // The credential used to get new tokens
+    credential: Arc<dyn TokenCredential>,
+    // The OAuth2 scopes the tokens are for, e.g. ["https://management.azure.com/.default"]
+    scopes: Vec<String>,
+    // How long before expiry a token is due to be refreshed
+    refresh_skew: chrono::Duration,
     // The cached access token, guarded against multiple async calls.
     cached_token: Arc<RwLock<Option<AccessToken>>>,
+    // Held while a token request is in flight, so only one request is made at a time.
+    refresh_lock: futures::lock::Mutex<()>,
+    // The result of the most recent attempt to get a token
+    last_acquisition: RwLock<Option<TokenAcquisition>>,
+    // Whether the token is being refreshed in the background
+    refreshing_in_background: AtomicBool,
 }
 
 impl AccessTokenCache {
-    // Creates a new token cache for the given subscription
-    pub fn new(subscription_settings: SubscriptionSettings) -> Self {
+    // Creates a new token cache that uses the given credential to get tokens for the given scopes
+    pub fn new(
+        credential: Arc<dyn TokenCredential>,
+        scopes: Vec<String>,
+        refresh_skew: chrono::Duration,
+    ) -> Self {
         AccessTokenCache {
-            // Use the given subscription settings
-            subscription_settings,
+            // Use the given credential and scopes
+            credential,
+            scopes,
+            refresh_skew,
             // Start without any cached token
             cached_token: Arc::new(RwLock::new(None)),
+            refresh_lock: futures::lock::Mutex::new(()),
+            last_acquisition: RwLock::new(None),
+            refreshing_in_background: AtomicBool::new(false),
         }
     }
 
-    // Tries to get an access token
-    pub async fn access_token(&self) -> anyhow::Result<String> {
-        log::debug!("access_token()");
-        {
-            // Get exclusive access to the cached token
-            let arc = self.cached_token.clone();
-            // Get a read-only lock
-            let read_lock = arc.read().unwrap();
-            // If we have a cached token...
-            if let Some(cached_token) = read_lock.deref() {
-                log::debug!(" - got cached token");
-                // If it has not expired...
-                if !cached_token.is_expired() {
-                    log::debug!(" - cached token has not expired");
-                    // Return the token's access token
-                    return Ok(cached_token.access_token.clone());
-                }
+    // Returns the cached token if it matches the given condition.
+    fn cached_token_if(&self, condition: impl Fn(&AccessToken) -> bool) -> Option<SecretString> {
+        // Get a read-only lock
+        let read_lock = self.cached_token.read().unwrap();
+        // Return the token's access token if we have one and it matches
+        read_lock
+            .deref()
+            .as_ref()
+            .filter(|cached_token| condition(cached_token))
+            .map(|cached_token| cached_token.access_token.clone())
+    }
+
+    // Returns how long until the cached token is due to be refreshed, or zero if there's no cached token.
+    fn time_until_refresh(&self) -> std::time::Duration {
+        // Get a read-only lock
+        let read_lock = self.cached_token.read().unwrap();
+        match read_lock.deref() {
+            // If we have a token, it's due "refresh_skew" before it expires
+            Some(cached_token) => {
+                (cached_token.expiry_date - self.refresh_skew - chrono::Utc::now())
+                    .to_std()
+                    .unwrap_or_default()
+                    .max(MIN_BACKGROUND_REFRESH_INTERVAL)
             }
+            // If we don't have a token, one is due now
+            None => std::time::Duration::ZERO,
         }
-        // Either we don't have a token or it has expired.
-        // Get an access token using the settings in the subscription
-        let new_token = get_access_token(
-            self.subscription_settings.token_url.clone(),
-            self.subscription_settings.client_id.clone(),
-            self.subscription_settings.client_secret.clone(),
-            self.subscription_settings.resource.clone(),
-        )
-        .await?;
-        // Get exclusive access to the cached token
-        let arc = self.cached_token.clone();
+    }
+
+    // Gets a new token from the credential and caches it, unless another caller already did so while we waited.
+    async fn refresh(&self) -> anyhow::Result<SecretString> {
+        // Wait for any in-flight request to finish
+        let _refresh_guard = self.refresh_lock.lock().await;
+        // If the in-flight request got a token that isn't due for refresh, use it
+        if let Some(access_token) = self.cached_token_if(|t| !t.needs_refresh(self.refresh_skew)) {
+            log::debug!(" - token was refreshed while waiting");
+            return Ok(access_token);
+        }
+        // Get an access token from the credential
+        log::debug!(" - requesting new token for {:?}", self.scopes);
+        let result = self.credential.get_token(&self.scopes).await;
+        // Remember how it went, for health reporting
+        *self.last_acquisition.write().unwrap() = Some(TokenAcquisition::from_result(&result));
+        let new_token = result?;
         // Get a write lock
-        let mut write_lock = arc.write().unwrap();
+        let mut write_lock = self.cached_token.write().unwrap();
         // Insert the new access token into the cache
         let inserted_token = write_lock.insert(new_token);
         // Return the new access token
         Ok(inserted_token.access_token.clone())
     }
+
+    // Tries to get an access token
+    pub async fn access_token(&self) -> anyhow::Result<SecretString> {
+        log::debug!("access_token()");
+        // If we have a cached token that's still good, return it.  If it's refreshed in the background, it's good
+        // until it expires, as the background refresh will renew it when it's due.  Otherwise, it's good until
+        // it's due.
+        let refreshing_in_background = self.refreshing_in_background.load(Ordering::SeqCst);
+        if let Some(access_token) = self.cached_token_if(|t| {
+            if refreshing_in_background {
+                !t.is_expired()
+            } else {
+                !t.needs_refresh(self.refresh_skew)
+            }
+        }) {
+            log::debug!(" - cached token is still good");
+            return Ok(access_token);
+        }
+        // Either we don't have a token or it's no longer good.
+        self.refresh().await
+    }
+
+    // Gets the state of the cache, for health reporting.
+    pub fn health(&self) -> TokenHealth {
+        TokenHealth {
+            last_acquisition: self.last_acquisition.read().unwrap().clone(),
+            token_expiry: self
+                .cached_token
+                .read()
+                .unwrap()
+                .as_ref()
+                .map(|t| t.expiry_date),
+        }
+    }
+
+    // Refreshes the token whenever it's due, forever.
+    async fn refresh_in_background(self: Arc<Self>) {
+        self.refreshing_in_background.store(true, Ordering::SeqCst);
+        loop {
+            // Wait until the token is due
+            actix_web::rt::time::sleep(self.time_until_refresh()).await;
+            // Refresh it
+            if let Err(e) = self.refresh().await {
+                log::warn!(
+                    "Background refresh of token for {:?} failed: {e}",
+                    self.scopes
+                );
+                // Wait a while before trying again
+                actix_web::rt::time::sleep(BACKGROUND_REFRESH_RETRY_INTERVAL).await;
+            }
+        }
+    }
 }
 
-// A map of access token caches by subscription ID
+// A credential shared by all the subscriptions that authenticate as the same principal.
+struct SharedCredential {
+    // The credential
+    credential: Arc<dyn TokenCredential>,
+    // The configured resource, e.g. "https://management.azure.com"
+    resource: String,
+    // The scopes to request for the configured resource
+    scopes: Vec<String>,
+}
+impl SharedCredential {
+    // The scopes to request for a token for the given audience.
+    // The configured resource uses the configured scopes.  Other audiences use their ".default" scope.
+    fn scopes_for(&self, audience: &str) -> Vec<String> {
+        let audience = audience.trim_end_matches('/');
+        if audience == self.resource.trim_end_matches('/') {
+            self.scopes.clone()
+        } else {
+            vec![format!("{audience}/.default")]
+        }
+    }
+}
+
+// A map of access token caches by credential and audience.
+// Subscriptions that use the same named credential in the same cloud share it, and so share tokens, as do
+// subscriptions that give the same credential settings directly.
+// Caches for audiences other than a credential's configured resource are created when first asked for.
 pub struct AccessTokenCacheMap {
-    // The access token caches by subscription ID.
-    access_token_caches: HashMap<String, AccessTokenCache>,
+    // The credentials by credential key, e.g. "CLOUD/credentials/NAME" or "CLOUD/clients/TENANT_ID/CLIENT_ID/KINDS"
+    credentials: HashMap<String, SharedCredential>,
+    // The credential key for each subscription ID
+    subscription_credentials: HashMap<String, String>,
+    // The cloud each subscription ID is in
+    subscription_clouds: HashMap<String, Arc<CloudProfile>>,
+    // The access token caches by credential key and audience.
+    access_token_caches: RwLock<HashMap<(String, String), Arc<AccessTokenCache>>>,
+    // How long before expiry a token is due to be refreshed
+    refresh_skew: chrono::Duration,
+    // Whether new caches should be refreshed in the background
+    background_refresh: AtomicBool,
 }
 impl AccessTokenCacheMap {
-    // Create a new cache map from the list of subscriptions.
-    pub fn new(subscriptions: &Vec<SubscriptionSettings>) -> Self {
-        // Create the map of caches
+    // Create a new cache map from the list of subscriptions, using the given resolver for their secrets.
+    pub fn new(
+        settings: &DashboardSettings,
+        secret_resolver: &Arc<SecretResolver>,
+    ) -> anyhow::Result<Self> {
+        // Get the refresh skew
+        let refresh_skew = chrono::Duration::seconds(settings.token_refresh_skew_seconds);
+        // Create the maps of credentials and caches
+        let mut credentials = HashMap::new();
+        let mut subscription_credentials = HashMap::new();
+        let mut subscription_clouds = HashMap::new();
         let mut caches = HashMap::new();
+        // The cloud each credential was created for
+        let mut credential_clouds: HashMap<String, CloudProfile> = HashMap::new();
+        // The first subscription that uses each credential, and the settings it was created with
+        let mut credential_subscriptions: HashMap<String, (&str, &CredentialSettings)> =
+            HashMap::new();
         // For each subscription...
-        for subscription in subscriptions {
-            // Add a new cache
-            caches.insert(
+        for subscription in &settings.subscriptions {
+            // Get the cloud the subscription is in
+            let cloud = subscription.cloud.profile();
+            // Get the credential settings the subscription uses
+            let credential_settings = settings.credential_settings(subscription)?;
+            let key = subscription.credential_key(&cloud);
+            log::debug!(
+                " - subscription {} uses credential {key}",
+                subscription.subscription_id
+            );
+            subscription_credentials.insert(subscription.subscription_id.clone(), key.clone());
+            subscription_clouds.insert(
                 subscription.subscription_id.clone(),
-                AccessTokenCache::new(subscription.clone()),
+                Arc::new(cloud.clone()),
+            );
+            // If we already have the credential, share it.  Custom clouds are only told apart by name, so make
+            // sure this isn't a different cloud with the same name.
+            if let Some(credential_cloud) = credential_clouds.get(&key) {
+                if *credential_cloud != cloud {
+                    return Err(anyhow::anyhow!(
+                        "Subscription {} is in a different cloud named {:?} to the other subscriptions that use its credential",
+                        subscription.subscription_id,
+                        cloud.name
+                    ));
+                }
+                // Subscriptions that give their own settings share a credential when they give the same tenant,
+                // client and kinds.  Anything else that differs (e.g. the secret) would be a different credential,
+                // so those must be named.
+                let (first_subscription_id, first_settings) = credential_subscriptions[&key];
+                if *first_settings != *credential_settings {
+                    return Err(anyhow::anyhow!(
+                        "Subscriptions {first_subscription_id} and {} use the same tenant, client and credentials with different settings.  Define each in \"credentials\" and refer to it by name.",
+                        subscription.subscription_id
+                    ));
+                }
+                continue;
+            }
+            credential_clouds.insert(key.clone(), cloud.clone());
+            credential_subscriptions.insert(
+                key.clone(),
+                (subscription.subscription_id.as_str(), credential_settings),
+            );
+            // Create the credential
+            let credential: Arc<dyn TokenCredential> = Arc::from(
+                create_credential(credential_settings, &cloud, secret_resolver).map_err(|e| {
+                    anyhow::anyhow!(
+                        "Could not create the credential for subscription {}: {e}",
+                        subscription.subscription_id
+                    )
+                })?,
+            );
+            // Add a cache for the configured resource, so it's ready to be refreshed in the background
+            caches.insert(
+                (
+                    key.clone(),
+                    credential_settings
+                        .token_resource(&cloud)
+                        .trim_end_matches('/')
+                        .to_string(),
+                ),
+                Arc::new(AccessTokenCache::new(
+                    credential.clone(),
+                    credential_settings.token_scopes(&cloud),
+                    refresh_skew,
+                )),
+            );
+            // Add the credential
+            credentials.insert(
+                key,
+                SharedCredential {
+                    credential,
+                    resource: credential_settings.token_resource(&cloud),
+                    scopes: credential_settings.token_scopes(&cloud),
+                },
             );
         }
         // Return the map
-        AccessTokenCacheMap {
-            access_token_caches: caches,
-        }
-    }
-    // Gets an access token for the given subscription.
-    pub async fn access_token(&self, subscription_id: String) -> anyhow::Result<String> {
-        // If we have an access token cache for this subscription...
-        if let Some(access_token_cache) = self.access_token_caches.get(&subscription_id) {
-            // Try to get an access token
-            let access_token = access_token_cache.access_token().await?;
-            // Return the token
-            Ok(access_token)
-        }
-        // If we don't have an access token cache for this subscription...
-        else {
-            // Return an error.  The token caches should have been initialized on startup.
-            Err(anyhow::anyhow!(
-                "There is no token cache for subscription ID {:?}",
-                subscription_id
+        Ok(AccessTokenCacheMap {
+            credentials,
+            subscription_credentials,
+            subscription_clouds,
+            access_token_caches: RwLock::new(caches),
+            refresh_skew,
+            background_refresh: AtomicBool::new(false),
+        })
+    }
+    // Resolves the secrets the credentials use, so any problems are found on startup.
+    pub async fn resolve_secrets(&self) -> anyhow::Result<()> {
+        for (key, credential) in &self.credentials {
+            log::debug!(" - resolving secrets for credential {key}");
+            credential.credential.resolve_secrets().await.map_err(|e| {
+                anyhow::anyhow!("Could not resolve the secrets for credential {key}: {e}")
+            })?;
+        }
+        Ok(())
+    }
+    // Starts refreshing each cache's token in the background, including caches created later.
+    // Must be called from within the Actix runtime.
+    pub fn start_background_refresh(&self) {
+        self.background_refresh.store(true, Ordering::SeqCst);
+        for access_token_cache in self.access_token_caches.read().unwrap().values() {
+            actix_web::rt::spawn(access_token_cache.clone().refresh_in_background());
+        }
+    }
+    // Gets the cache for the given subscription's credential and audience, creating it if necessary.
+    fn access_token_cache(
+        &self,
+        subscription_id: &str,
+        audience: &str,
+    ) -> anyhow::Result<Arc<AccessTokenCache>> {
+        // Get the subscription's credential key.  The credentials should have been initialized on startup.
+        let credential_key = self
+            .subscription_credentials
+            .get(subscription_id)
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "There is no token cache for subscription ID {:?}",
+                    subscription_id
+                )
+            })?;
+        let key = (
+            credential_key.clone(),
+            audience.trim_end_matches('/').to_string(),
+        );
+        // If we already have a cache, return it
+        if let Some(access_token_cache) = self.access_token_caches.read().unwrap().get(&key) {
+            return Ok(access_token_cache.clone());
+        }
+        // Get the credential
+        let credential = &self.credentials[credential_key];
+        // Get a write lock
+        let mut write_lock = self.access_token_caches.write().unwrap();
+        // Add a cache, unless another caller did so while we waited for the lock
+        let access_token_cache = write_lock.entry(key).or_insert_with(|| {
+            log::debug!(" - creating token cache for {credential_key} and {audience}");
+            let access_token_cache = Arc::new(AccessTokenCache::new(
+                credential.credential.clone(),
+                credential.scopes_for(audience),
+                self.refresh_skew,
+            ));
+            // Keep it fresh, if required
+            if self.background_refresh.load(Ordering::SeqCst) {
+                actix_web::rt::spawn(access_token_cache.clone().refresh_in_background());
+            }
+            access_token_cache
+        });
+        Ok(access_token_cache.clone())
+    }
+    // Gets the cloud the given subscription is in, which has the endpoints and audiences to use for it.
+    pub fn cloud_profile(&self, subscription_id: &str) -> anyhow::Result<Arc<CloudProfile>> {
+        self.subscription_clouds
+            .get(subscription_id)
+            .cloned()
+            .ok_or_else(|| anyhow::anyhow!("Unknown subscription ID {:?}", subscription_id))
+    }
+    // Gets the credential key, e.g. "CLOUD/credentials/NAME", the given subscription uses.
+    pub fn credential_key(&self, subscription_id: &str) -> anyhow::Result<&str> {
+        self.subscription_credentials
+            .get(subscription_id)
+            .map(String::as_str)
+            .ok_or_else(|| anyhow::anyhow!("Unknown subscription ID {:?}", subscription_id))
+    }
+    // Gets the state of the given subscription's token cache for its configured resource.
+    pub fn token_health(&self, subscription_id: &str) -> anyhow::Result<TokenHealth> {
+        let credential = &self.credentials[self.credential_key(subscription_id)?];
+        Ok(self
+            .access_token_cache(subscription_id, &credential.resource)?
+            .health())
+    }
+    // Gets the hint (first few characters) of the client secret the given subscription's credential uses, if any.
+    pub async fn client_secret_hint(
+        &self,
+        subscription_id: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let credential = &self.credentials[self.credential_key(subscription_id)?];
+        Ok(credential.credential.client_secret_hint().await)
+    }
+    // Gets an access token for the given subscription and audience, e.g. its cloud's resource_manager_audience.
+    pub async fn access_token(
+        &self,
+        subscription_id: String,
+        audience: &str,
+    ) -> anyhow::Result<SecretString> {
+        // Get the cache for this subscription and audience
+        let access_token_cache = self.access_token_cache(&subscription_id, audience)?;
+        // Try to get an access token
+        let access_token = access_token_cache.access_token().await?;
+        // Return the token
+        Ok(access_token)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    // A credential that counts its calls, and gives tokens that expire after the given time.
+    struct CountingCredential {
+        // How long the tokens last
+        lifetime: chrono::Duration,
+        // The number of tokens given
+        calls: AtomicUsize,
+    }
+
+    #[async_trait::async_trait]
+    impl TokenCredential for CountingCredential {
+        async fn get_token(&self, _scopes: &[String]) -> anyhow::Result<AccessToken> {
+            let call = self.calls.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok(AccessToken::new(
+                format!("token-{call}").into(),
+                chrono::Utc::now() + self.lifetime,
             ))
         }
     }
+
+    // Creates a cache whose tokens last 2 minutes, and are due 5 minutes before they expire.
+    fn cache_of_short_lived_tokens() -> (Arc<CountingCredential>, AccessTokenCache) {
+        let credential = Arc::new(CountingCredential {
+            lifetime: chrono::Duration::minutes(2),
+            calls: AtomicUsize::new(0),
+        });
+        let cache = AccessTokenCache::new(
+            credential.clone(),
+            vec!["https://management.azure.com/.default".to_string()],
+            chrono::Duration::minutes(5),
+        );
+        (credential, cache)
+    }
+
+    #[actix_web::test]
+    async fn due_token_is_renewed_early_without_background_refresh() {
+        let (credential, cache) = cache_of_short_lived_tokens();
+        assert_eq!(
+            "token-1",
+            cache.access_token().await.unwrap().expose_secret()
+        );
+        // The token hasn't expired, but it's due, and nothing else will renew it
+        assert_eq!(
+            "token-2",
+            cache.access_token().await.unwrap().expose_secret()
+        );
+        assert_eq!(2, credential.calls.load(Ordering::SeqCst));
+    }
+
+    #[actix_web::test]
+    async fn due_token_is_used_until_it_expires_with_background_refresh() {
+        let (credential, cache) = cache_of_short_lived_tokens();
+        assert_eq!(
+            "token-1",
+            cache.access_token().await.unwrap().expose_secret()
+        );
+        // The background refresh will renew it, so callers don't wait
+        cache.refreshing_in_background.store(true, Ordering::SeqCst);
+        assert_eq!(
+            "token-1",
+            cache.access_token().await.unwrap().expose_secret()
+        );
+        assert_eq!(1, credential.calls.load(Ordering::SeqCst));
+    }
+
+    // Creates the cache map for the given settings.
+    fn cache_map(settings: serde_json::Value) -> anyhow::Result<AccessTokenCacheMap> {
+        let settings = serde_json::from_value::<DashboardSettings>(settings).unwrap();
+        AccessTokenCacheMap::new(&settings, &Arc::new(SecretResolver::default()))
+    }
+
+    // Creates the settings of a subscription, with the given credential settings.
+    fn subscription(
+        subscription_id: &str,
+        credential_settings: serde_json::Value,
+    ) -> serde_json::Value {
+        let mut subscription = serde_json::json!({
+            "display_name": subscription_id,
+            "subscription_id": subscription_id,
+            "resource_groups": [],
+        });
+        subscription
+            .as_object_mut()
+            .unwrap()
+            .extend(credential_settings.as_object().unwrap().clone());
+        subscription
+    }
+
+    #[test]
+    fn subscriptions_share_named_credentials() {
+        let cache_map = cache_map(serde_json::json!({
+            "host": "127.0.0.1",
+            "port": 8080,
+            "credentials": {
+                "shared": { "tenant_id": "t", "client_id": "c", "client_secret": "s" },
+                "other": { "tenant_id": "t", "client_id": "c", "client_secret": "s", "credentials": ["certificate"], "certificate_path": "x.pem" },
+            },
+            "subscriptions": [
+                subscription("s1", serde_json::json!({ "credential": "shared" })),
+                subscription("s2", serde_json::json!({ "credential": "shared" })),
+                subscription("s3", serde_json::json!({ "credential": "other" })),
+            ],
+        }))
+        .unwrap();
+        assert_eq!(
+            "public/credentials/shared",
+            cache_map.credential_key("s1").unwrap()
+        );
+        assert_eq!(
+            "public/credentials/shared",
+            cache_map.credential_key("s2").unwrap()
+        );
+        // The same principal, authenticating differently, has its own credential
+        assert_eq!(
+            "public/credentials/other",
+            cache_map.credential_key("s3").unwrap()
+        );
+        assert_eq!(2, cache_map.credentials.len());
+    }
+
+    #[test]
+    fn subscriptions_with_the_same_own_settings_share_credentials() {
+        let cache_map = cache_map(serde_json::json!({
+            "host": "127.0.0.1",
+            "port": 8080,
+            "subscriptions": [
+                // The same principal, given directly in each subscription
+                subscription("s1", serde_json::json!({ "tenant_id": "t", "client_id": "c", "client_secret": "s" })),
+                subscription("s2", serde_json::json!({ "tenant_id": "t", "client_id": "c", "client_secret": "s" })),
+                // The same principal, authenticating differently
+                subscription("s3", serde_json::json!({ "tenant_id": "t", "client_id": "c", "credentials": ["certificate"], "certificate_path": "x.pem" })),
+                // System-assigned managed identities, with no tenant or client ID
+                subscription("s4", serde_json::json!({ "credentials": ["managed_identity"] })),
+                subscription("s5", serde_json::json!({ "credentials": ["managed_identity"] })),
+                // A user-assigned managed identity
+                subscription("s6", serde_json::json!({ "credentials": ["managed_identity"], "managed_identity_client_id": "m" })),
+            ],
+        }))
+        .unwrap();
+        assert_eq!(
+            "public/clients/t/c/client_secret",
+            cache_map.credential_key("s1").unwrap()
+        );
+        assert_eq!(
+            "public/clients/t/c/client_secret",
+            cache_map.credential_key("s2").unwrap()
+        );
+        assert_eq!(
+            "public/clients/t/c/certificate",
+            cache_map.credential_key("s3").unwrap()
+        );
+        assert_eq!(
+            "public/clients/-/-/managed_identity",
+            cache_map.credential_key("s4").unwrap()
+        );
+        assert_eq!(
+            "public/clients/-/-/managed_identity",
+            cache_map.credential_key("s5").unwrap()
+        );
+        assert_eq!(
+            "public/clients/-/m/managed_identity",
+            cache_map.credential_key("s6").unwrap()
+        );
+        assert_eq!(4, cache_map.credentials.len());
+    }
+
+    #[test]
+    fn subscriptions_with_the_same_principal_and_different_own_settings_are_refused() {
+        let result = cache_map(serde_json::json!({
+            "host": "127.0.0.1",
+            "port": 8080,
+            "subscriptions": [
+                // The same principal, with a different resource
+                subscription("s1", serde_json::json!({ "tenant_id": "t", "client_id": "c", "client_secret": "s" })),
+                subscription("s2", serde_json::json!({ "tenant_id": "t", "client_id": "c", "client_secret": "s", "resource": "https://example.com" })),
+            ],
+        }));
+        let error = result.err().unwrap().to_string();
+        assert!(error.contains("s1 and s2"), "{error}");
+    }
+
+    #[test]
+    fn named_credential_cant_be_shared_by_different_clouds_with_the_same_name() {
+        let custom_cloud = |resource_manager_endpoint: &str| {
+            serde_json::json!({ "custom": {
+                "name": "stack",
+                "authority_host": "https://login.stack",
+                "resource_manager_endpoint": resource_manager_endpoint,
+                "resource_manager_audience": "https://management.stack",
+                "graph_endpoint": "https://graph.stack",
+                "graph_audience": "https://graph.stack",
+                "log_analytics_audience": "https://logs.stack",
+                "monitor_audience": "https://monitor.stack",
+                "key_vault_audience": "https://vault.stack",
+            }})
+        };
+        let result = cache_map(serde_json::json!({
+            "host": "127.0.0.1",
+            "port": 8080,
+            "credentials": {
+                "shared": { "tenant_id": "t", "client_id": "c", "client_secret": "s" },
+            },
+            "subscriptions": [
+                subscription("s1", serde_json::json!({ "credential": "shared", "cloud": custom_cloud("https://a") })),
+                subscription("s2", serde_json::json!({ "credential": "shared", "cloud": custom_cloud("https://b") })),
+            ],
+        }));
+        assert!(result.is_err());
+    }
+
+    #[actix_web::test]
+    async fn token_that_is_not_due_is_reused() {
+        let credential = Arc::new(CountingCredential {
+            lifetime: chrono::Duration::hours(1),
+            calls: AtomicUsize::new(0),
+        });
+        let cache = AccessTokenCache::new(credential.clone(), vec![], chrono::Duration::minutes(5));
+        cache.access_token().await.unwrap();
+        cache.access_token().await.unwrap();
+        assert_eq!(1, credential.calls.load(Ordering::SeqCst));
+    }
 }
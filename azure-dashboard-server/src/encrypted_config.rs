@@ -0,0 +1,510 @@
+use crate::secrets::SecretString;
+use aes_gcm::aead::consts::U32;
+use aes_gcm::aead::{Aead, KeyInit, Payload};
+use aes_gcm::aes::Aes256;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use sha2::{Digest, Sha512};
+use std::io::{Read, Write};
+use std::path::Path;
+use zeroize::Zeroizing;
+
+// Configuration files can be stored encrypted in either of two forms:
+// - a whole file encrypted with age (https://age-encryption.org), in binary or ASCII-armored form, e.g. as
+//   produced by the "encrypt-config" command
+// - a SOPS (https://github.com/getsops/sops) JSON file with age recipients, in which each value is encrypted
+//   and the "sops" key holds the metadata
+// Both are decrypted with the age identities (private keys) in the SOPS_AGE_KEY environment variable or the
+// file named by the SOPS_AGE_KEY_FILE environment variable.
+
+// The environment variable holding the age identities.
+const AGE_KEY_ENV_VAR: &str = "SOPS_AGE_KEY";
+// The environment variable holding the path of a file of age identities.
+const AGE_KEY_FILE_ENV_VAR: &str = "SOPS_AGE_KEY_FILE";
+
+// The start of a binary age file.
+const AGE_BINARY_HEADER: &[u8] = b"age-encryption.org/";
+// The start of an ASCII-armored age file.
+const AGE_ARMOR_HEADER: &[u8] = b"-----BEGIN AGE ENCRYPTED FILE-----";
+
+// The key SOPS keeps its metadata under.
+const SOPS_METADATA_KEY: &str = "sops";
+
+// The cipher SOPS encrypts values with: AES-256-GCM with a 32-byte nonce.
+type SopsCipher = aes_gcm::AesGcm<Aes256, U32>;
+
+// An encrypted SOPS value, e.g. "ENC[AES256_GCM,data:...,iv:...,tag:...,type:str]".
+static SOPS_VALUE_REGEX: once_cell::sync::Lazy<regex::Regex> = once_cell::sync::Lazy::new(|| {
+    regex::Regex::new(r"^ENC\[AES256_GCM,data:(.+),iv:(.+),tag:(.+),type:(.+)\]").unwrap()
+});
+
+// An age recipient the SOPS data key is encrypted to.
+#[derive(Debug, serde::Deserialize)]
+struct SopsAgeRecipient {
+    // The recipient's public key, e.g. "age1..."
+    recipient: String,
+    // The data key, as an ASCII-armored age file
+    enc: String,
+}
+
+// The SOPS metadata we need to decrypt a file.
+#[derive(Debug, serde::Deserialize)]
+struct SopsMetadata {
+    // The age recipients the data key is encrypted to
+    #[serde(default)]
+    age: Vec<SopsAgeRecipient>,
+    // When the file was last modified, which authenticates the MAC
+    lastmodified: String,
+    // The encrypted MAC of the values
+    mac: String,
+    // Keys with this suffix are not encrypted
+    #[serde(default)]
+    unencrypted_suffix: Option<String>,
+    // Only keys with this suffix are encrypted
+    #[serde(default)]
+    encrypted_suffix: Option<String>,
+    // Keys that match this regex are not encrypted
+    #[serde(default)]
+    unencrypted_regex: Option<String>,
+    // Only keys that match this regex are encrypted
+    #[serde(default)]
+    encrypted_regex: Option<String>,
+    // Whether only the encrypted values are included in the MAC
+    #[serde(default)]
+    mac_only_encrypted: bool,
+}
+
+impl SopsMetadata {
+    // Whether the value at the given path of keys is encrypted.
+    fn is_encrypted(&self, path: &[String]) -> anyhow::Result<bool> {
+        let mut encrypted = true;
+        if let Some(suffix) = self.unencrypted_suffix.as_deref().filter(|s| !s.is_empty()) {
+            encrypted = !path.iter().any(|key| key.ends_with(suffix));
+        }
+        if let Some(suffix) = self.encrypted_suffix.as_deref().filter(|s| !s.is_empty()) {
+            encrypted = path.iter().any(|key| key.ends_with(suffix));
+        }
+        if let Some(regex) = self.unencrypted_regex.as_deref().filter(|s| !s.is_empty()) {
+            let regex = regex::Regex::new(regex)?;
+            if path.iter().any(|key| regex.is_match(key)) {
+                encrypted = false;
+            }
+        }
+        if let Some(regex) = self.encrypted_regex.as_deref().filter(|s| !s.is_empty()) {
+            let regex = regex::Regex::new(regex)?;
+            encrypted = path.iter().any(|key| regex.is_match(key));
+        }
+        Ok(encrypted)
+    }
+}
+
+// Loads the age identities from SOPS_AGE_KEY or the file named by SOPS_AGE_KEY_FILE.
+fn load_identities() -> anyhow::Result<Vec<Box<dyn age::Identity + Send + Sync>>> {
+    let identity_file = if let Ok(key) = std::env::var(AGE_KEY_ENV_VAR) {
+        let key = SecretString::new(key);
+        age::IdentityFile::from_buffer(key.expose_secret().as_bytes())?
+    } else if let Ok(path) = std::env::var(AGE_KEY_FILE_ENV_VAR) {
+        age::IdentityFile::from_file(path.clone())
+            .map_err(|e| anyhow::anyhow!("Could not read the age key file {path:?}: {e}"))?
+    } else {
+        return Err(anyhow::anyhow!(
+            "An age key is needed to decrypt the configuration.  Set {AGE_KEY_ENV_VAR} or {AGE_KEY_FILE_ENV_VAR}."
+        ));
+    };
+    Ok(identity_file.into_identities()?)
+}
+
+// Whether the contents are an age-encrypted file.
+fn is_age_encrypted(contents: &[u8]) -> bool {
+    let contents = contents.trim_ascii_start();
+    contents.starts_with(AGE_BINARY_HEADER) || contents.starts_with(AGE_ARMOR_HEADER)
+}
+
+// Decrypts an age-encrypted file, in binary or ASCII-armored form.
+fn decrypt_age(
+    contents: &[u8],
+    identities: &[Box<dyn age::Identity + Send + Sync>],
+) -> anyhow::Result<Zeroizing<Vec<u8>>> {
+    let decryptor =
+        age::Decryptor::new(age::armor::ArmoredReader::new(contents.trim_ascii_start()))?;
+    let mut reader =
+        decryptor.decrypt(identities.iter().map(|i| i.as_ref() as &dyn age::Identity))?;
+    let mut plaintext = Zeroizing::new(Vec::new());
+    reader.read_to_end(&mut plaintext)?;
+    Ok(plaintext)
+}
+
+// Decrypts a value SOPS encrypted with the given data key, e.g. "ENC[AES256_GCM,data:...,iv:...,tag:...,type:str]".
+// The additional data is the path of keys to the value, e.g. "subscriptions:client_secret:".
+fn decrypt_sops_value(
+    encrypted_value: &str,
+    data_key: &[u8],
+    additional_data: &str,
+) -> anyhow::Result<serde_json::Value> {
+    // SOPS leaves empty values as they are
+    if encrypted_value.is_empty() {
+        return Ok(serde_json::Value::String(String::new()));
+    }
+    // Split the value into its parts
+    let captures = SOPS_VALUE_REGEX
+        .captures(encrypted_value)
+        .ok_or_else(|| anyhow::anyhow!("Invalid SOPS value at {additional_data:?}"))?;
+    let data = STANDARD.decode(&captures[1])?;
+    let iv = STANDARD.decode(&captures[2])?;
+    let tag = STANDARD.decode(&captures[3])?;
+    if iv.len() != 32 {
+        return Err(anyhow::anyhow!(
+            "Invalid SOPS value IV length at {additional_data:?}"
+        ));
+    }
+    // Decrypt the data, which is authenticated by the tag and the path
+    let cipher = SopsCipher::new_from_slice(data_key)
+        .map_err(|_| anyhow::anyhow!("Invalid SOPS data key length"))?;
+    let plaintext = cipher
+        .decrypt(
+            aes_gcm::Nonce::<U32>::from_slice(&iv),
+            Payload {
+                msg: &[data, tag].concat(),
+                aad: additional_data.as_bytes(),
+            },
+        )
+        .map_err(|_| anyhow::anyhow!("Could not decrypt the SOPS value at {additional_data:?}"))?;
+    let plaintext = String::from_utf8(plaintext)?;
+    // Convert it back to its original type
+    let invalid = |e: &dyn std::fmt::Display| {
+        anyhow::anyhow!(
+            "Invalid SOPS {} value at {additional_data:?}: {e}",
+            &captures[4]
+        )
+    };
+    Ok(match &captures[4] {
+        "str" | "bytes" => serde_json::Value::String(plaintext),
+        "int" => plaintext.parse::<i64>().map_err(|e| invalid(&e))?.into(),
+        "float" => plaintext.parse::<f64>().map_err(|e| invalid(&e))?.into(),
+        "bool" => plaintext
+            .to_ascii_lowercase()
+            .parse::<bool>()
+            .map_err(|e| invalid(&e))?
+            .into(),
+        other => return Err(anyhow::anyhow!("Unsupported SOPS value type {other:?}")),
+    })
+}
+
+// Gets the bytes SOPS adds to the MAC for a value.
+fn sops_mac_bytes(value: &serde_json::Value) -> Vec<u8> {
+    match value {
+        serde_json::Value::String(s) => s.as_bytes().to_vec(),
+        serde_json::Value::Bool(true) => b"True".to_vec(),
+        serde_json::Value::Bool(false) => b"False".to_vec(),
+        other => other.to_string().into_bytes(),
+    }
+}
+
+// Decrypts the values of a SOPS tree in place, adding them to the MAC in document order.
+// Values in arrays have the same path as the array itself.
+fn decrypt_sops_tree(
+    value: &mut serde_json::Value,
+    path: &mut Vec<String>,
+    metadata: &SopsMetadata,
+    data_key: &[u8],
+    mac: &mut Sha512,
+) -> anyhow::Result<()> {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, child) in map.iter_mut() {
+                path.push(key.clone());
+                decrypt_sops_tree(child, path, metadata, data_key, mac)?;
+                path.pop();
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for item in items.iter_mut() {
+                decrypt_sops_tree(item, path, metadata, data_key, mac)?;
+            }
+        }
+        serde_json::Value::Null => {}
+        leaf => {
+            let encrypted = metadata.is_encrypted(path)?;
+            if encrypted {
+                let encrypted_value = leaf
+                    .as_str()
+                    .ok_or_else(|| anyhow::anyhow!("Unencrypted SOPS value at {path:?}"))?;
+                *leaf =
+                    decrypt_sops_value(encrypted_value, data_key, &format!("{}:", path.join(":")))?;
+            }
+            if encrypted || !metadata.mac_only_encrypted {
+                mac.update(sops_mac_bytes(leaf));
+            }
+        }
+    }
+    Ok(())
+}
+
+// Decrypts a SOPS JSON document, verifying its MAC, and returns it without the metadata.
+fn decrypt_sops(
+    mut document: serde_json::Map<String, serde_json::Value>,
+    identities: &[Box<dyn age::Identity + Send + Sync>],
+) -> anyhow::Result<SecretString> {
+    // Take the metadata out of the document
+    let metadata: SopsMetadata = serde_json::from_value(
+        document
+            .remove(SOPS_METADATA_KEY)
+            .ok_or_else(|| anyhow::anyhow!("The SOPS metadata is missing"))?,
+    )?;
+    // Get the data key from the first age recipient we have an identity for
+    let data_key = metadata
+        .age
+        .iter()
+        .find_map(|recipient| {
+            let data_key = decrypt_age(recipient.enc.as_bytes(), identities).ok();
+            log::debug!(
+                " - SOPS recipient {} decrypted = {}",
+                recipient.recipient,
+                data_key.is_some()
+            );
+            data_key
+        })
+        .ok_or_else(|| anyhow::anyhow!("None of the age keys can decrypt the SOPS data key"))?;
+    // Decrypt the values
+    let mut document = serde_json::Value::Object(document);
+    let mut mac = Sha512::new();
+    decrypt_sops_tree(
+        &mut document,
+        &mut Vec::new(),
+        &metadata,
+        &data_key,
+        &mut mac,
+    )?;
+    // Check the MAC, so values can't have been removed, reordered or changed
+    let expected_mac = decrypt_sops_value(&metadata.mac, &data_key, &metadata.lastmodified)?;
+    let actual_mac = mac
+        .finalize()
+        .iter()
+        .map(|b| format!("{b:02X}"))
+        .collect::<String>();
+    if expected_mac.as_str() != Some(actual_mac.as_str()) {
+        return Err(anyhow::anyhow!("The SOPS MAC does not match the values"));
+    }
+    Ok(SecretString::new(document.to_string()))
+}
+
+// Reads a JSON configuration file, decrypting it if it's age- or SOPS-encrypted.
+pub fn read_config_file(path: &Path) -> anyhow::Result<SecretString> {
+    log::debug!("read_config_file - path = {path:?}");
+    let contents = std::fs::read(path)?;
+    // If the whole file is encrypted, decrypt it
+    if is_age_encrypted(&contents) {
+        log::debug!(" - decrypting age-encrypted file");
+        let plaintext = decrypt_age(&contents, &load_identities()?)?;
+        return Ok(SecretString::new(String::from_utf8(plaintext.to_vec())?));
+    }
+    let contents = SecretString::new(String::from_utf8(contents)?);
+    // If it's a SOPS document, decrypt its values
+    if let Ok(serde_json::Value::Object(document)) =
+        serde_json::from_str::<serde_json::Value>(contents.expose_secret())
+    {
+        if document.contains_key(SOPS_METADATA_KEY) {
+            log::debug!(" - decrypting SOPS file");
+            return decrypt_sops(document, &load_identities()?);
+        }
+    }
+    // Otherwise it's plain text
+    Ok(contents)
+}
+
+// Encrypts a plaintext JSON configuration file with age, for the "encrypt-config" command:
+//   azure-dashboard-server encrypt-config INPUT [OUTPUT] [--recipient age1...]...
+// The output is ASCII-armored, and written to stdout if no output file is given.
+// If no recipients are given, it's encrypted to the identities in SOPS_AGE_KEY or SOPS_AGE_KEY_FILE.
+pub fn encrypt_config_command(args: &[String]) -> anyhow::Result<()> {
+    const USAGE: &str =
+        "Usage: azure-dashboard-server encrypt-config INPUT [OUTPUT] [--recipient age1...]...";
+    // Read the arguments
+    let mut files = Vec::new();
+    let mut recipients: Vec<Box<dyn age::Recipient + Send>> = Vec::new();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--recipient" || arg == "-r" {
+            let recipient = args
+                .next()
+                .ok_or_else(|| anyhow::anyhow!("{arg} needs a value.  {USAGE}"))?;
+            recipients.push(Box::new(
+                recipient
+                    .parse::<age::x25519::Recipient>()
+                    .map_err(|e| anyhow::anyhow!("Invalid recipient {recipient:?}: {e}"))?,
+            ));
+        } else {
+            files.push(arg);
+        }
+    }
+    let (input, output) = match files.as_slice() {
+        [input] => (input, None),
+        [input, output] => (input, Some(output)),
+        _ => return Err(anyhow::anyhow!(USAGE)),
+    };
+    // Default to the recipients of our own identities
+    if recipients.is_empty() {
+        recipients = match std::env::var(AGE_KEY_ENV_VAR) {
+            Ok(key) => age::IdentityFile::from_buffer(SecretString::new(key).expose_secret().as_bytes())?,
+            Err(_) => match std::env::var(AGE_KEY_FILE_ENV_VAR) {
+                Ok(path) => age::IdentityFile::from_file(path)?,
+                Err(_) => {
+                    return Err(anyhow::anyhow!(
+                        "No recipients given, and neither {AGE_KEY_ENV_VAR} nor {AGE_KEY_FILE_ENV_VAR} is set.  {USAGE}"
+                    ))
+                }
+            },
+        }
+        .to_recipients()?;
+    }
+    // Read the plaintext, which must be valid, unencrypted JSON
+    let plaintext = std::fs::read(input)?;
+    if is_age_encrypted(&plaintext) {
+        return Err(anyhow::anyhow!("{input} is already encrypted"));
+    }
+    serde_json::from_slice::<serde_json::Value>(&plaintext)
+        .map_err(|e| anyhow::anyhow!("{input} is not valid JSON: {e}"))?;
+    // Encrypt it
+    let encryptor = age::Encryptor::with_recipients(
+        recipients.iter().map(|r| r.as_ref() as &dyn age::Recipient),
+    )?;
+    let mut ciphertext = Vec::new();
+    let mut writer = encryptor.wrap_output(age::armor::ArmoredWriter::wrap_output(
+        &mut ciphertext,
+        age::armor::Format::AsciiArmor,
+    )?)?;
+    writer.write_all(&plaintext)?;
+    writer.finish()?.finish()?;
+    // Write it out
+    match output {
+        Some(output) => std::fs::write(output, ciphertext)?,
+        None => std::io::stdout().write_all(&ciphertext)?,
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A test-only age identity
+    const AGE_KEY: &str = include_str!("../test_fixtures/age_key.txt");
+    // A SOPS document encrypted to the identity, with a "_unencrypted" suffix
+    const SOPS_CONFIG: &str = include_str!("../test_fixtures/sops_config.json");
+    // The document's values
+    const SOPS_CONFIG_PLAIN: &str = include_str!("../test_fixtures/sops_config.plain.json");
+
+    // Reads the test identity.
+    fn identities() -> Vec<Box<dyn age::Identity + Send + Sync>> {
+        age::IdentityFile::from_buffer(AGE_KEY.as_bytes())
+            .unwrap()
+            .into_identities()
+            .unwrap()
+    }
+
+    // Reads the SOPS document, changed as given, and decrypts it.
+    fn decrypt_sops_config(
+        change: impl Fn(&mut serde_json::Value),
+    ) -> anyhow::Result<serde_json::Value> {
+        let mut document = serde_json::from_str::<serde_json::Value>(SOPS_CONFIG).unwrap();
+        change(&mut document);
+        let serde_json::Value::Object(document) = document else {
+            unreachable!()
+        };
+        let plaintext = decrypt_sops(document, &identities())?;
+        Ok(serde_json::from_str(plaintext.expose_secret()).unwrap())
+    }
+
+    #[test]
+    fn sops_document_is_decrypted() {
+        let expected = serde_json::from_str::<serde_json::Value>(SOPS_CONFIG_PLAIN).unwrap();
+        assert_eq!(expected, decrypt_sops_config(|_| {}).unwrap());
+    }
+
+    #[test]
+    fn sops_document_with_a_tampered_mac_is_rejected() {
+        let error = decrypt_sops_config(|document| {
+            // Change the first character of the encrypted MAC's data
+            let mac = document["sops"]["mac"].as_str().unwrap();
+            let data_start = mac.find("data:").unwrap() + "data:".len();
+            let mut tampered = mac.to_string();
+            let replacement = if mac[data_start..].starts_with('A') {
+                "B"
+            } else {
+                "A"
+            };
+            tampered.replace_range(data_start..data_start + 1, replacement);
+            document["sops"]["mac"] = tampered.into();
+        })
+        .unwrap_err();
+        assert!(error.to_string().contains("Could not decrypt"), "{error}");
+    }
+
+    #[test]
+    fn sops_document_with_a_changed_unencrypted_value_is_rejected() {
+        let error = decrypt_sops_config(|document| {
+            document["comment_unencrypted"] = "changed".into();
+        })
+        .unwrap_err();
+        assert!(error.to_string().contains("MAC does not match"), "{error}");
+    }
+
+    #[test]
+    fn sops_document_with_moved_values_is_rejected() {
+        // Each value is authenticated with its path, so can't be moved to another key
+        assert!(decrypt_sops_config(|document| {
+            let subscription = &mut document["subscriptions"][0];
+            let tenant_id = subscription["tenant_id"].clone();
+            subscription["client_id"] = tenant_id;
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn sops_document_needs_a_matching_identity() {
+        let document = serde_json::from_str::<serde_json::Value>(SOPS_CONFIG).unwrap();
+        let serde_json::Value::Object(document) = document else {
+            unreachable!()
+        };
+        let other_identity: Vec<Box<dyn age::Identity + Send + Sync>> =
+            vec![Box::new(age::x25519::Identity::generate())];
+        assert!(decrypt_sops(document, &other_identity).is_err());
+    }
+
+    #[test]
+    fn sops_value_needs_a_32_byte_nonce() {
+        let data_key = [0u8; 32];
+        let value =
+            "ENC[AES256_GCM,data:AAAA,iv:AAAAAAAAAAAAAAAA,tag:AAAAAAAAAAAAAAAAAAAAAA==,type:str]";
+        let error = decrypt_sops_value(value, &data_key, "host:").unwrap_err();
+        assert!(error.to_string().contains("IV length"), "{error}");
+    }
+
+    #[test]
+    fn age_file_is_decrypted() {
+        // Encrypt to the test identity, as the "encrypt-config" command does
+        let recipients = age::IdentityFile::from_buffer(AGE_KEY.as_bytes())
+            .unwrap()
+            .to_recipients()
+            .unwrap();
+        let encryptor = age::Encryptor::with_recipients(
+            recipients.iter().map(|r| r.as_ref() as &dyn age::Recipient),
+        )
+        .unwrap();
+        let mut ciphertext = Vec::new();
+        let mut writer = encryptor
+            .wrap_output(
+                age::armor::ArmoredWriter::wrap_output(
+                    &mut ciphertext,
+                    age::armor::Format::AsciiArmor,
+                )
+                .unwrap(),
+            )
+            .unwrap();
+        writer.write_all(SOPS_CONFIG_PLAIN.as_bytes()).unwrap();
+        writer.finish().unwrap().finish().unwrap();
+        assert!(is_age_encrypted(&ciphertext));
+        let plaintext = decrypt_age(&ciphertext, &identities()).unwrap();
+        assert_eq!(SOPS_CONFIG_PLAIN.as_bytes(), plaintext.as_slice());
+    }
+}
@@ -1,3 +1,4 @@
+use crate::azure_apis::error::AzureError;
 use actix_web::body::BoxBody;
 use actix_web::{
     error, get,
@@ -11,9 +12,181 @@ use std::fmt::{Display, Formatter};
 #[derive(Debug, thiserror::Error)]
 pub enum AzureDashboardError {
     #[error("There was an error calling the Azure API: {0}")]
-    AzureApiError(String),
+    AzureApiError(AzureError),
+    #[error("{0} is not configured")]
+    NotConfigured(String),
     #[error("Internal error")]
     InternalError,
 }
 
-impl error::ResponseError for AzureDashboardError {}
+// Converts the error from a call to Azure.  Failures to reach Azure or get an access token are already AzureErrors;
+// anything else, e.g. an unknown subscription or resource type, is a problem with our configuration.
+impl From<anyhow::Error> for AzureDashboardError {
+    fn from(value: anyhow::Error) -> Self {
+        match value.downcast::<AzureError>() {
+            Ok(azure_error) => AzureDashboardError::AzureApiError(azure_error),
+            Err(e) => {
+                log::error!("Internal error: {e}");
+                AzureDashboardError::InternalError
+            }
+        }
+    }
+}
+
+// An RFC 7807 problem details body, e.g.
+// ```json
+// {
+//   "type": "about:blank",
+//   "title": "Not Found",
+//   "status": 404,
+//   "detail": "ResourceNotFound: The Resource '...' under resource group '...' was not found.",
+//   "code": "ResourceNotFound",
+//   "requestId": "...",
+//   "upstreamStatus": 404
+// }
+// ```
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ProblemDetails {
+    // The problem type.  "about:blank" means the problem is described by the status alone.
+    #[serde(rename = "type")]
+    problem_type: &'static str,
+    // The status's reason phrase
+    title: String,
+    // The status we're returning
+    status: u16,
+    // What went wrong
+    detail: String,
+    // The Azure error code, if there is one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+    // The part of the request the Azure error is about, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+    // The ID Azure gave the failed request, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    request_id: Option<String>,
+    // The status Azure returned, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    upstream_status: Option<u16>,
+}
+
+impl error::ResponseError for AzureDashboardError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            AzureDashboardError::AzureApiError(AzureError::NotFound(_)) => StatusCode::NOT_FOUND,
+            AzureDashboardError::AzureApiError(AzureError::Forbidden(_)) => StatusCode::FORBIDDEN,
+            AzureDashboardError::AzureApiError(AzureError::Throttled(_)) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            AzureDashboardError::AzureApiError(AzureError::Upstream(_)) => StatusCode::BAD_GATEWAY,
+            AzureDashboardError::NotConfigured(_) => StatusCode::NOT_FOUND,
+            AzureDashboardError::InternalError => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse<BoxBody> {
+        HttpResponse::build(self.status_code())
+            .content_type("application/problem+json")
+            .json(self.problem_details())
+    }
+}
+
+impl AzureDashboardError {
+    // Describes the problem, with the Azure error's details if there are any.
+    pub fn problem_details(&self) -> ProblemDetails {
+        let status = error::ResponseError::status_code(self);
+        let mut problem = ProblemDetails {
+            problem_type: "about:blank",
+            title: status.canonical_reason().unwrap_or_default().to_string(),
+            status: status.as_u16(),
+            detail: self.to_string(),
+            code: None,
+            target: None,
+            request_id: None,
+            upstream_status: None,
+        };
+        if let AzureDashboardError::AzureApiError(azure_error) = self {
+            let details = azure_error.details();
+            problem.code = details.code.clone();
+            problem.target = details.target.clone();
+            problem.request_id = details.request_id.clone();
+            problem.upstream_status = details.status.map(|s| s.as_u16());
+        }
+        problem
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::azure_apis::error::AzureErrorDetails;
+    use actix_web::ResponseError;
+
+    // Gets the status the route returns for an error from a call to Azure.
+    fn status_code(error: anyhow::Error) -> StatusCode {
+        AzureDashboardError::from(error).status_code()
+    }
+
+    #[test]
+    fn azure_errors_keep_their_meaning() {
+        let azure_error = |status: StatusCode| {
+            anyhow::Error::from(AzureError::new(AzureErrorDetails {
+                status: Some(status),
+                ..Default::default()
+            }))
+        };
+        assert_eq!(
+            StatusCode::NOT_FOUND,
+            status_code(azure_error(StatusCode::NOT_FOUND))
+        );
+        assert_eq!(
+            StatusCode::FORBIDDEN,
+            status_code(azure_error(StatusCode::FORBIDDEN))
+        );
+        assert_eq!(
+            StatusCode::SERVICE_UNAVAILABLE,
+            status_code(azure_error(StatusCode::TOO_MANY_REQUESTS))
+        );
+        assert_eq!(
+            StatusCode::BAD_GATEWAY,
+            status_code(azure_error(StatusCode::INTERNAL_SERVER_ERROR))
+        );
+        assert_eq!(
+            StatusCode::BAD_GATEWAY,
+            status_code(AzureError::unreachable("connection refused").into())
+        );
+    }
+
+    #[test]
+    fn configuration_errors_are_internal_errors() {
+        for error in [
+            anyhow::anyhow!("Unknown subscription ID \"s\""),
+            anyhow::anyhow!("No API version is known for Microsoft.Sql/servers/elasticPools"),
+            anyhow::anyhow!("Could not resolve a secret: Environment variable SECRET is not set"),
+        ] {
+            assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, status_code(error));
+        }
+    }
+
+    #[test]
+    fn problem_details_describe_the_azure_error() {
+        let error = AzureDashboardError::from(anyhow::Error::from(AzureError::from_body(
+            StatusCode::NOT_FOUND,
+            Some("request".to_string()),
+            r#"{"error":{"code":"ResourceNotFound","message":"Not found."}}"#,
+        )));
+        let problem = error.problem_details();
+        assert_eq!(404, problem.status);
+        assert_eq!("Not Found", problem.title);
+        assert_eq!(Some("ResourceNotFound"), problem.code.as_deref());
+        assert_eq!(Some("request"), problem.request_id.as_deref());
+        assert_eq!(Some(404), problem.upstream_status);
+        // Internal errors don't say more than that
+        let problem = AzureDashboardError::from(anyhow::anyhow!("Unknown subscription ID \"s\""))
+            .problem_details();
+        assert_eq!(500, problem.status);
+        assert_eq!("Internal error", problem.detail);
+        assert_eq!(None, problem.upstream_status);
+    }
+}
@@ -10,11 +10,18 @@ use actix_web::{get, http, web, App, HttpRequest, HttpServer};
 use std::sync::Mutex;
 
 mod azure_apis;
+mod azure_cloud;
+mod azure_credentials;
 mod azure_token_cache;
+mod encrypted_config;
 mod errors;
+mod permission_check;
 mod routes;
+mod secrets;
 mod settings;
 mod static_file_handlers;
+#[cfg(test)]
+mod test_server;
 //
 // #[get("/api/hello/{name}")]
 // async fn greet(
@@ -34,18 +41,50 @@ mod static_file_handlers;
 async fn main() -> anyhow::Result<()> {
     // Initialize logging
     log4rs::init_file("log4rs.yaml", Default::default()).unwrap_or(());
+    // Run the "encrypt-config" command instead of the server, if asked
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.get(1).map(String::as_str) == Some("encrypt-config") {
+        return encrypted_config::encrypt_config_command(&args[2..]);
+    }
     log::debug!(" - loading the configuration file");
     let settings = DashboardSettings::new().unwrap();
     log::debug!(" - loading settings = {:?}", settings);
     // Save the host and port
     let host = settings.host.clone();
     let port = settings.port;
+    // Create the resolver for secrets that are given as references
+    let secret_resolver = std::sync::Arc::new(secrets::create_secret_resolver(&settings)?);
     // Create a token cache map as web data
-    let token_caches = web::Data::new(AccessTokenCacheMap::new(&settings.subscriptions));
+    let token_caches = web::Data::new(AccessTokenCacheMap::new(&settings, &secret_resolver)?);
+    // Resolve the secrets now, so any problems are found on startup
+    token_caches.resolve_secrets().await?;
+    // Keep the access tokens fresh, if required
+    if settings.background_token_refresh {
+        token_caches.start_background_refresh();
+    }
     // Make the settings available as web data
     let settings_data = web::Data::new(settings);
     // Create a reusable HTTP client
     let http_client = web::Data::new(reqwest::Client::new());
+    // Create the policy for retrying failed Azure API requests
+    let retry_policy = web::Data::new(azure_apis::retry::RetryPolicy::new(&settings_data.retry));
+    // Create the Resource Manager client
+    let arm_client = web::Data::new(azure_apis::arm_client::ArmClient::new(
+        http_client.get_ref().clone(),
+        token_caches.clone().into_inner(),
+        &settings_data.arm,
+        retry_policy.get_ref().clone(),
+        azure_apis::concurrency::ConcurrencyLimiter::new(&settings_data.concurrency),
+    ));
+    // Check the permissions on the configured resource groups in the background, and log any problems
+    if settings_data.check_permissions_on_startup {
+        let (arm_client, settings_data) = (arm_client.clone(), settings_data.clone());
+        actix_web::rt::spawn(async move {
+            permission_check::check_permissions(&arm_client, &settings_data)
+                .await
+                .log_summary();
+        });
+    }
     // Start the Actix server
     HttpServer::new(move || {
         // Configure cords
@@ -63,10 +102,18 @@ async fn main() -> anyhow::Result<()> {
             .app_data(settings_data.clone())
             // Add a re-usable http client
             .app_data(http_client.clone())
+            // Add the retry policy for Azure API requests
+            .app_data(retry_policy.clone())
+            // Add the Resource Manager client
+            .app_data(arm_client.clone())
             // Add API routes
             .service(routes::dashboard::dashboard)
+            .service(routes::dashboard_usage::dashboard_usage)
+            .service(routes::diagnostics::concurrency)
+            .service(routes::diagnostics::permissions)
             .service(routes::database_usage::database_usage)
             .service(routes::elastic_pool_usage::elastic_pool_usage)
+            .service(routes::health::credential_health)
             // Add static file handling
             .route("/{filename:.*.*}", web::get().to(static_file))
     })
@@ -0,0 +1,261 @@
+use crate::azure_apis::arm_client::{ArmClient, ResourceGroupId};
+use crate::settings::{
+    DashboardSettings, ElasticPoolUsageSource, ResourceGroupSettings, SubscriptionSettings,
+};
+
+// The actions needed to show a database's usage.
+const DATABASE_ACTIONS: [&str; 2] = [
+    "Microsoft.Sql/servers/databases/read",
+    "Microsoft.Sql/servers/databases/usages/read",
+];
+// The actions needed to show an elastic pool's usage, which includes the usage of the databases in it.
+const ELASTIC_POOL_ACTIONS: [&str; 3] = [
+    "Microsoft.Sql/servers/elasticPools/read",
+    "Microsoft.Sql/servers/elasticPools/databases/read",
+    "Microsoft.Sql/servers/databases/usages/read",
+];
+// The action needed to read an elastic pool's metrics, when that's where its usage comes from.
+const ELASTIC_POOL_METRICS_ACTION: &str = "Microsoft.Insights/metrics/read";
+
+// The result of checking the permissions on a resource group.
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResourceGroupPermissions {
+    // The resource group name
+    pub resource_group_name: String,
+    // The actions the configured databases and elastic pools need
+    pub required_actions: Vec<String>,
+    // The required actions that aren't allowed
+    pub missing_actions: Vec<String>,
+    // Why the permissions couldn't be checked, e.g. because the resource group doesn't exist
+    pub error: Option<String>,
+}
+
+impl ResourceGroupPermissions {
+    // Whether the permissions were checked and nothing is missing.
+    pub fn is_ok(&self) -> bool {
+        self.error.is_none() && self.missing_actions.is_empty()
+    }
+}
+
+// The result of checking the permissions on a subscription's resource groups.
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SubscriptionPermissions {
+    // The display name for this subscription
+    pub display_name: String,
+    // The subscription ID (a GUID)
+    pub subscription_id: String,
+    // The resource groups
+    pub resource_groups: Vec<ResourceGroupPermissions>,
+}
+
+// The result of checking the permissions on all the configured resource groups.
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PermissionReport {
+    // Whether every resource group has all the permissions it needs
+    pub ok: bool,
+    // The subscriptions
+    pub subscriptions: Vec<SubscriptionPermissions>,
+}
+
+impl PermissionReport {
+    // Logs a summary of the report: a warning for each problem, or that all is well.
+    pub fn log_summary(&self) {
+        let mut resource_group_count = 0;
+        for subscription in &self.subscriptions {
+            for resource_group in &subscription.resource_groups {
+                resource_group_count += 1;
+                if let Some(error) = &resource_group.error {
+                    log::warn!(
+                        "Could not check the permissions on subscription {} ({}) resource group {}: {error}",
+                        subscription.display_name,
+                        subscription.subscription_id,
+                        resource_group.resource_group_name
+                    );
+                } else if !resource_group.missing_actions.is_empty() {
+                    log::warn!(
+                        "Subscription {} ({}) resource group {} is missing permissions: {}",
+                        subscription.display_name,
+                        subscription.subscription_id,
+                        resource_group.resource_group_name,
+                        resource_group.missing_actions.join(", ")
+                    );
+                }
+            }
+        }
+        if self.ok {
+            log::info!("All {resource_group_count} resource groups have the required permissions");
+        }
+    }
+}
+
+// Gets the actions the databases and elastic pools configured in a resource group need.
+fn required_actions(
+    settings: &DashboardSettings,
+    resource_group: &ResourceGroupSettings,
+) -> Vec<String> {
+    let mut actions = Vec::new();
+    if !resource_group.databases.is_empty() {
+        actions.extend(DATABASE_ACTIONS);
+    }
+    if !resource_group.elastic_pools.is_empty() {
+        actions.extend(ELASTIC_POOL_ACTIONS);
+        if ElasticPoolUsageSource::Metrics == settings.elastic_pool_usage_source {
+            actions.push(ELASTIC_POOL_METRICS_ACTION);
+        }
+    }
+    actions.sort();
+    actions.dedup();
+    actions.into_iter().map(String::from).collect()
+}
+
+// Checks the permissions on a resource group.
+async fn check_resource_group(
+    arm_client: &ArmClient,
+    settings: &DashboardSettings,
+    subscription: &SubscriptionSettings,
+    resource_group: &ResourceGroupSettings,
+) -> ResourceGroupPermissions {
+    let required_actions = required_actions(settings, resource_group);
+    // Get the permissions, which also confirms the resource group exists
+    let (missing_actions, error) = match arm_client
+        .list_permissions(&ResourceGroupId {
+            subscription_id: subscription.subscription_id.clone(),
+            resource_group_name: resource_group.resource_group_name.clone(),
+        })
+        .await
+    {
+        Ok(permissions) => (
+            required_actions
+                .iter()
+                .filter(|action| !permissions.allows(action))
+                .cloned()
+                .collect(),
+            None,
+        ),
+        Err(e) => (Vec::new(), Some(e.to_string())),
+    };
+    ResourceGroupPermissions {
+        resource_group_name: resource_group.resource_group_name.clone(),
+        required_actions,
+        missing_actions,
+        error,
+    }
+}
+
+// Checks the permissions on each configured resource group.
+pub async fn check_permissions(
+    arm_client: &ArmClient,
+    settings: &DashboardSettings,
+) -> PermissionReport {
+    log::debug!("check_permissions");
+    // Check the subscriptions' resource groups
+    let subscriptions = futures::future::join_all(settings.subscriptions.iter().map(
+        |subscription| async move {
+            let resource_groups = futures::future::join_all(
+                subscription.resource_groups.iter().map(|resource_group| {
+                    check_resource_group(arm_client, settings, subscription, resource_group)
+                }),
+            )
+            .await;
+            SubscriptionPermissions {
+                display_name: subscription.display_name.clone(),
+                subscription_id: subscription.subscription_id.clone(),
+                resource_groups,
+            }
+        },
+    ))
+    .await;
+    // It's all ok if nothing is missing
+    let ok = subscriptions
+        .iter()
+        .flat_map(|s| &s.resource_groups)
+        .all(|r| r.is_ok());
+    PermissionReport { ok, subscriptions }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Reads a resource group's settings.
+    fn resource_group(settings: serde_json::Value) -> ResourceGroupSettings {
+        serde_json::from_value(settings).unwrap()
+    }
+
+    // Reads the dashboard settings, with the given elastic pool usage source.
+    fn settings(elastic_pool_usage_source: &str) -> DashboardSettings {
+        serde_json::from_value(serde_json::json!({
+            "host": "127.0.0.1",
+            "port": 8080,
+            "subscriptions": [],
+            "elastic_pool_usage_source": elastic_pool_usage_source,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn databases_need_database_actions() {
+        let resource_group = resource_group(serde_json::json!({
+            "resource_group_name": "rg",
+            "databases": [{ "server_name": "s", "database_name": "d" }],
+            "elastic_pools": [],
+        }));
+        assert_eq!(
+            DATABASE_ACTIONS.to_vec(),
+            required_actions(&settings("databases"), &resource_group)
+        );
+    }
+
+    #[test]
+    fn elastic_pools_need_elastic_pool_actions() {
+        let resource_group = resource_group(serde_json::json!({
+            "resource_group_name": "rg",
+            "databases": [],
+            "elastic_pools": [{ "server_name": "s", "elastic_pool_name": "p" }],
+        }));
+        let mut expected = ELASTIC_POOL_ACTIONS.to_vec();
+        expected.sort();
+        assert_eq!(
+            expected,
+            required_actions(&settings("databases"), &resource_group)
+        );
+        // Their metrics too, if that's where their usage comes from
+        expected.push(ELASTIC_POOL_METRICS_ACTION);
+        expected.sort();
+        assert_eq!(
+            expected,
+            required_actions(&settings("metrics"), &resource_group)
+        );
+    }
+
+    #[test]
+    fn actions_needed_by_both_are_listed_once() {
+        let resource_group = resource_group(serde_json::json!({
+            "resource_group_name": "rg",
+            "databases": [{ "server_name": "s", "database_name": "d" }],
+            "elastic_pools": [{ "server_name": "s", "elastic_pool_name": "p" }],
+        }));
+        let actions = required_actions(&settings("databases"), &resource_group);
+        assert_eq!(
+            1,
+            actions
+                .iter()
+                .filter(|a| *a == "Microsoft.Sql/servers/databases/usages/read")
+                .count()
+        );
+        assert_eq!(4, actions.len());
+    }
+
+    #[test]
+    fn empty_resource_group_needs_nothing() {
+        let resource_group = resource_group(serde_json::json!({
+            "resource_group_name": "rg",
+            "databases": [],
+            "elastic_pools": [],
+        }));
+        assert!(required_actions(&settings("metrics"), &resource_group).is_empty());
+    }
+}
@@ -0,0 +1,410 @@
+use crate::azure_apis::arm_client::{ArmClient, DatabaseId, ElasticPoolId};
+use crate::errors::ProblemDetails;
+use crate::routes::database_usage::{
+    database_usage_view_model, get_database_usage_view_model, DatabaseUsageViewModel,
+};
+use crate::routes::elastic_pool_usage::{
+    add_elastic_pool_usage_to_batch, get_elastic_pool_usage_view_model,
+    get_elastic_pool_usage_view_models_from_batch, ElasticPoolUsageViewModel,
+};
+use crate::settings::DashboardSettings;
+use crate::AzureDashboardError;
+use actix_web::{get, http, web, HttpRequest, HttpResponse};
+use futures::{FutureExt, StreamExt};
+use std::collections::BTreeMap;
+
+// The content type of a streamed response: one JSON document per line.
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+// A resource shown in the dashboard.
+#[derive(Clone, Debug)]
+enum DashboardResource {
+    // A database
+    Database(DatabaseId),
+    // An elastic pool
+    ElasticPool(ElasticPoolId),
+}
+
+impl DashboardResource {
+    // The key the resource's usage is found under: the path of its own usage route, without the "/api/" and
+    // "/usage", e.g. "subscription/SUBSCRIPTION_ID/resource-group/RESOURCE_GROUP_NAME/server/SERVER_NAME/database/DATABASE_NAME"
+    fn key(&self) -> String {
+        match self {
+            DashboardResource::Database(database) => format!(
+                "subscription/{}/resource-group/{}/server/{}/database/{}",
+                database.subscription_id,
+                database.resource_group_name,
+                database.server_name,
+                database.database_name
+            ),
+            DashboardResource::ElasticPool(elastic_pool) => format!(
+                "subscription/{}/resource-group/{}/server/{}/elastic-pool/{}",
+                elastic_pool.subscription_id,
+                elastic_pool.resource_group_name,
+                elastic_pool.server_name,
+                elastic_pool.elastic_pool_name
+            ),
+        }
+    }
+}
+
+// Lists the databases and elastic pools in the settings, in the order they're configured.
+fn configured_resources(settings: &DashboardSettings) -> Vec<DashboardResource> {
+    let mut resources = Vec::new();
+    for subscription in &settings.subscriptions {
+        for resource_group in &subscription.resource_groups {
+            for database in &resource_group.databases {
+                resources.push(DashboardResource::Database(DatabaseId {
+                    subscription_id: subscription.subscription_id.clone(),
+                    resource_group_name: resource_group.resource_group_name.clone(),
+                    server_name: database.server_name.clone(),
+                    database_name: database.database_name.clone(),
+                }));
+            }
+            for elastic_pool in &resource_group.elastic_pools {
+                resources.push(DashboardResource::ElasticPool(ElasticPoolId {
+                    subscription_id: subscription.subscription_id.clone(),
+                    resource_group_name: resource_group.resource_group_name.clone(),
+                    server_name: elastic_pool.server_name.clone(),
+                    elastic_pool_name: elastic_pool.elastic_pool_name.clone(),
+                }));
+            }
+        }
+    }
+    resources
+}
+
+// The usage of a database or an elastic pool, as its own usage route returns it.
+#[derive(Debug, serde::Serialize)]
+#[serde(untagged)]
+pub enum ResourceUsageViewModel {
+    // A database's usage
+    Database(DatabaseUsageViewModel),
+    // An elastic pool's usage
+    ElasticPool(ElasticPoolUsageViewModel),
+}
+
+// The usage of a resource in the dashboard, or why it couldn't be had.
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ResourceUsageResultViewModel {
+    // The key the usage is found under, e.g. "subscription/.../server/SERVER_NAME/database/DATABASE_NAME"
+    key: String,
+    // The kind of resource: "database" or "elasticPool"
+    kind: &'static str,
+    // The subscription ID (a GUID)
+    subscription_id: String,
+    // The resource group name
+    resource_group_name: String,
+    // The server name
+    server_name: String,
+    // The database or elastic pool name
+    name: String,
+    // The usage, if it could be had
+    #[serde(skip_serializing_if = "Option::is_none")]
+    usage: Option<ResourceUsageViewModel>,
+    // Why the usage couldn't be had, as the resource's own usage route would have described it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ProblemDetails>,
+}
+
+// The usage of every resource in the dashboard.
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DashboardUsageViewModel {
+    // The usage of each resource, by key
+    resources: BTreeMap<String, ResourceUsageResultViewModel>,
+    // Whether some resources' usages couldn't be had
+    partial: bool,
+}
+
+// Whether to return the usages as NDJSON, one resource per line as each is had.
+#[derive(Debug, serde::Deserialize)]
+pub struct DashboardUsageQuery {
+    // Whether to stream the usages
+    #[serde(default)]
+    stream: bool,
+}
+
+// Makes the result for a resource from its usage, noting the error if it couldn't be had.
+fn resource_usage_result(
+    resource: &DashboardResource,
+    usage: Result<ResourceUsageViewModel, AzureDashboardError>,
+) -> ResourceUsageResultViewModel {
+    let key = resource.key();
+    let (kind, subscription_id, resource_group_name, server_name, name) = match resource {
+        DashboardResource::Database(database) => (
+            "database",
+            database.subscription_id.clone(),
+            database.resource_group_name.clone(),
+            database.server_name.clone(),
+            database.database_name.clone(),
+        ),
+        DashboardResource::ElasticPool(elastic_pool) => (
+            "elasticPool",
+            elastic_pool.subscription_id.clone(),
+            elastic_pool.resource_group_name.clone(),
+            elastic_pool.server_name.clone(),
+            elastic_pool.elastic_pool_name.clone(),
+        ),
+    };
+    // Note the error, if there was one
+    let (usage, error) = match usage {
+        Ok(usage) => (Some(usage), None),
+        Err(e) => {
+            log::warn!("Could not get the usage of {key}: {e}");
+            (None, Some(e.problem_details()))
+        }
+    };
+    ResourceUsageResultViewModel {
+        key,
+        kind,
+        subscription_id,
+        resource_group_name,
+        server_name,
+        name,
+        usage,
+        error,
+    }
+}
+
+// Gets the usage of a resource, noting the error if it couldn't be had.
+async fn get_resource_usage(
+    arm_client: web::Data<ArmClient>,
+    settings: web::Data<DashboardSettings>,
+    resource: DashboardResource,
+) -> ResourceUsageResultViewModel {
+    log::debug!("get_resource_usage - resource = {resource:?}");
+    // Get the usage, as the resource's own usage route would
+    let usage = match &resource {
+        DashboardResource::Database(database) => {
+            get_database_usage_view_model(&arm_client, database)
+                .await
+                .map(ResourceUsageViewModel::Database)
+        }
+        DashboardResource::ElasticPool(elastic_pool) => {
+            get_elastic_pool_usage_view_model(&arm_client, &settings, elastic_pool)
+                .await
+                .map(ResourceUsageViewModel::ElasticPool)
+        }
+    };
+    resource_usage_result(&resource, usage)
+}
+
+// Gets the usage of a subscription's resources in as few batches as possible: one with the databases' usages, the
+// elastic pools and the databases in them, then one with the usages of the databases in the pools.
+async fn get_subscription_usage_in_batches(
+    arm_client: web::Data<ArmClient>,
+    settings: web::Data<DashboardSettings>,
+    subscription_id: String,
+    resources: Vec<DashboardResource>,
+) -> Vec<ResourceUsageResultViewModel> {
+    log::debug!(
+        "get_subscription_usage_in_batches - subscription_id = {subscription_id}, {} resources",
+        resources.len()
+    );
+    // Add the requests for each resource to the batch
+    let mut batch = arm_client.batch(&subscription_id);
+    let mut database_items = Vec::new();
+    let mut elastic_pool_items = Vec::new();
+    for (index, resource) in resources.iter().enumerate() {
+        match resource {
+            DashboardResource::Database(database) => {
+                database_items.push((index, batch.get_database_usage(database)));
+            }
+            DashboardResource::ElasticPool(elastic_pool) => elastic_pool_items.push((
+                index,
+                (
+                    elastic_pool.clone(),
+                    add_elastic_pool_usage_to_batch(&mut batch, &settings, elastic_pool),
+                ),
+            )),
+        }
+    }
+    // Send it, and take the databases' usages from the responses
+    let mut responses = batch.send().await;
+    let mut usages = resources.iter().map(|_| None).collect::<Vec<_>>();
+    for (index, item) in database_items {
+        let usage = match item {
+            Ok(item) => responses.take_list(item).await,
+            Err(e) => Err(e),
+        };
+        usages[index] = Some(
+            usage
+                .map(|usage| ResourceUsageViewModel::Database(database_usage_view_model(&usage)))
+                .map_err(AzureDashboardError::from),
+        );
+    }
+    // Get the elastic pools' usages from theirs
+    let (elastic_pool_indexes, elastic_pools): (Vec<_>, Vec<_>) =
+        elastic_pool_items.into_iter().unzip();
+    let elastic_pool_usages = get_elastic_pool_usage_view_models_from_batch(
+        &arm_client,
+        &settings,
+        &subscription_id,
+        elastic_pools,
+        &mut responses,
+    )
+    .await;
+    for (index, usage) in elastic_pool_indexes.into_iter().zip(elastic_pool_usages) {
+        usages[index] = Some(usage.map(ResourceUsageViewModel::ElasticPool));
+    }
+    // Every resource has its usage by now
+    resources
+        .iter()
+        .zip(usages)
+        .filter_map(|(resource, usage)| Some(resource_usage_result(resource, usage?)))
+        .collect()
+}
+
+// Groups resources by subscription, in the order the subscriptions are configured.
+fn resources_by_subscription(
+    resources: Vec<DashboardResource>,
+) -> Vec<(String, Vec<DashboardResource>)> {
+    let mut subscriptions: Vec<(String, Vec<DashboardResource>)> = Vec::new();
+    for resource in resources {
+        let subscription_id = match &resource {
+            DashboardResource::Database(database) => &database.subscription_id,
+            DashboardResource::ElasticPool(elastic_pool) => &elastic_pool.subscription_id,
+        };
+        match subscriptions
+            .iter_mut()
+            .find(|(id, _)| id.eq_ignore_ascii_case(subscription_id))
+        {
+            Some((_, resources)) => resources.push(resource),
+            None => subscriptions.push((subscription_id.clone(), vec![resource])),
+        }
+    }
+    subscriptions
+}
+
+// Returns the usage of every database and elastic pool in the dashboard, fetched concurrently, as JSON keyed by
+// resource.  Resources whose usage couldn't be had have an error in place of their usage.
+// With ?stream=true, or if NDJSON is accepted, each resource's usage is instead streamed as a line of JSON as soon
+// as it's had.  If arm.batch_requests is set, each subscription's resources are fetched in batches, so their usages
+// come together.
+#[get("/api/dashboard/usage")]
+pub async fn dashboard_usage(
+    request: HttpRequest,
+    query: web::Query<DashboardUsageQuery>,
+    arm_client: web::Data<ArmClient>,
+    settings: web::Data<DashboardSettings>,
+) -> Result<HttpResponse, AzureDashboardError> {
+    let resources = configured_resources(&settings);
+    log::debug!("dashboard_usage - {} resources", resources.len());
+    // Get the futures that will fetch the usage of each resource.  They all start at once, and the ARM client's
+    // concurrency limits decide how many actually run.  If batching, each subscription's resources are fetched
+    // together.
+    let usage_futures = if settings.arm.batch_requests {
+        resources_by_subscription(resources)
+            .into_iter()
+            .map(|(subscription_id, resources)| {
+                get_subscription_usage_in_batches(
+                    arm_client.clone(),
+                    settings.clone(),
+                    subscription_id,
+                    resources,
+                )
+                .boxed_local()
+            })
+            .collect::<Vec<_>>()
+    } else {
+        resources
+            .into_iter()
+            .map(|resource| {
+                get_resource_usage(arm_client.clone(), settings.clone(), resource)
+                    .map(|result| vec![result])
+                    .boxed_local()
+            })
+            .collect::<Vec<_>>()
+    };
+    // Stream the usages if asked to
+    let accepts_ndjson = request
+        .headers()
+        .get(http::header::ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.contains(NDJSON_CONTENT_TYPE));
+    if query.stream || accepts_ndjson {
+        // Write each usage as a line as soon as it's had
+        let lines = usage_futures
+            .into_iter()
+            .collect::<futures::stream::FuturesUnordered<_>>()
+            .flat_map(futures::stream::iter)
+            .map(|result| {
+                serde_json::to_vec(&result).map(|mut line| {
+                    line.push(b'\n');
+                    web::Bytes::from(line)
+                })
+            });
+        return Ok(HttpResponse::Ok()
+            .content_type(NDJSON_CONTENT_TYPE)
+            .streaming(lines));
+    }
+    // Otherwise, wait for them all
+    let results = futures::future::join_all(usage_futures)
+        .await
+        .into_iter()
+        .flatten()
+        .collect::<Vec<_>>();
+    let view_model = DashboardUsageViewModel {
+        partial: results.iter().any(|result| result.error.is_some()),
+        resources: results
+            .into_iter()
+            .map(|result| (result.key.clone(), result))
+            .collect(),
+    };
+    // Return the view model as JSON
+    Ok(HttpResponse::Ok().json(view_model))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Identifies a database in a subscription.
+    fn database(subscription_id: &str, database_name: &str) -> DashboardResource {
+        DashboardResource::Database(DatabaseId {
+            subscription_id: subscription_id.to_string(),
+            resource_group_name: "rg".to_string(),
+            server_name: "s".to_string(),
+            database_name: database_name.to_string(),
+        })
+    }
+
+    #[test]
+    fn resources_are_grouped_by_subscription_in_order() {
+        let subscriptions = resources_by_subscription(vec![
+            database("s1", "a"),
+            database("s2", "b"),
+            database("S1", "c"),
+        ]);
+        let keys = subscriptions
+            .iter()
+            .map(|(subscription_id, resources)| {
+                (
+                    subscription_id.as_str(),
+                    resources
+                        .iter()
+                        .map(DashboardResource::key)
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                (
+                    "s1",
+                    vec![
+                        "subscription/s1/resource-group/rg/server/s/database/a".to_string(),
+                        "subscription/S1/resource-group/rg/server/s/database/c".to_string(),
+                    ]
+                ),
+                (
+                    "s2",
+                    vec!["subscription/s2/resource-group/rg/server/s/database/b".to_string()]
+                ),
+            ],
+            keys
+        );
+    }
+}
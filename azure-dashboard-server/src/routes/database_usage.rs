@@ -1,8 +1,8 @@
-use crate::azure_apis::get_database_usage::get_database_usage;
+use crate::azure_apis::arm_client::{ArmClient, DatabaseId};
+use crate::azure_apis::get_database_usage::DatabaseUsageResponse;
 use crate::settings::{
     DashboardSettings, DatabaseSettings, ElasticPoolSettings, SubscriptionSettings,
 };
-use crate::AzureDashboardError::AzureApiError;
 use crate::{AccessTokenCacheMap, AzureDashboardError};
 use actix_web::{get, web};
 
@@ -17,45 +17,200 @@ pub struct DatabaseUsageViewModel {
     database_size_max: u64,
 }
 
-// Returns info related to a database as JSON
-#[get("/api/subscription/{subscription_id}/resource-group/{resource_group_name}/server/{server_name}/database/{database_name}/usage")]
-pub async fn database_usage(
-    path: web::Path<(String, String, String, String)>,
-    http_client: web::Data<reqwest::Client>,
-    settings: web::Data<DashboardSettings>,
-    token_cache_map: web::Data<AccessTokenCacheMap>,
-) -> Result<web::Json<DatabaseUsageViewModel>, AzureDashboardError> {
-    // Get the path components
-    let (subscription_id, resource_group_name, server_name, database_name) = path.into_inner();
-    log::debug!(
-        "database - \
-         subscription_id = {subscription_id}, \
-         resource_group_name = {resource_group_name}, \
-         server_name = {server_name}, \
-         database_name = {database_name}",
-    );
+// Whether the usage of a database can be requested: it must be configured or, if allowed, be in a configured
+// elastic pool.  A pool that can't be listed isn't taken to hold the database, so looking for it can't fail with
+// an Azure error that would tell the caller about resources the dashboard wasn't set up for.
+async fn is_allowed_database(
+    arm_client: &ArmClient,
+    settings: &DashboardSettings,
+    database: &DatabaseId,
+) -> bool {
+    if settings.is_configured_database(database) {
+        return true;
+    }
+    if !settings.allow_discovered_databases {
+        return false;
+    }
+    // Look for the database in the configured elastic pools on its server
+    for elastic_pool in settings.elastic_pools_on_server(database) {
+        match arm_client.list_databases_in_elastic_pool(&elastic_pool).await {
+            Ok(database_list_response) => {
+                if database_list_response
+                    .values()
+                    .iter()
+                    .any(|d| d.name.eq_ignore_ascii_case(&database.database_name))
+                {
+                    return true;
+                }
+            }
+            Err(e) => log::warn!(
+                "Could not list the databases in elastic pool {}/{} while looking for database {}: {e}",
+                elastic_pool.server_name,
+                elastic_pool.elastic_pool_name,
+                database.database_name
+            ),
+        }
+    }
+    false
+}
+
+// Gets the usage of a database.
+pub async fn get_database_usage_view_model(
+    arm_client: &ArmClient,
+    database: &DatabaseId,
+) -> Result<DatabaseUsageViewModel, AzureDashboardError> {
     // Get the database usages
-    let database_usage_response = get_database_usage(
-        http_client.get_ref(),
-        token_cache_map.get_ref(),
-        subscription_id.clone(),
-        resource_group_name.clone(),
-        server_name.clone(),
-        database_name.clone(),
-    )
-    .await
-    // If we got an error, convert it to an Azure API error
-    .map_err(|e| AzureApiError(e.to_string()))?;
+    let database_usage_response = arm_client
+        .get_database_usage(database)
+        .await
+        // If we got an error, convert it to an Azure API error
+        .map_err(AzureDashboardError::from)?;
     log::debug!(" - got response\r\n{:?}", database_usage_response);
+    Ok(database_usage_view_model(&database_usage_response))
+}
+
+// Makes a database's usage view model from its usages.
+pub fn database_usage_view_model(
+    database_usage_response: &DatabaseUsageResponse,
+) -> DatabaseUsageViewModel {
     // Get the databases sizes
     let (database_size_used, database_size_allocated, database_size_max) =
         database_usage_response.get_sizes();
     // Create the view model
-    let view_model = DatabaseUsageViewModel {
+    DatabaseUsageViewModel {
         database_size_used,
         database_size_allocated,
         database_size_max,
-    };
+    }
+}
+
+// Returns info related to a database as JSON
+#[get("/api/subscription/{subscription_id}/resource-group/{resource_group_name}/server/{server_name}/database/{database_name}/usage")]
+pub async fn database_usage(
+    database: web::Path<DatabaseId>,
+    arm_client: web::Data<ArmClient>,
+    settings: web::Data<DashboardSettings>,
+) -> Result<web::Json<DatabaseUsageViewModel>, AzureDashboardError> {
+    log::debug!("database - database = {database:?}");
+    // Don't let the dashboard be used to look at databases it wasn't set up for
+    if !is_allowed_database(&arm_client, &settings, &database).await {
+        return Err(AzureDashboardError::NotConfigured(format!(
+            "Database {}/{}",
+            database.server_name, database.database_name
+        )));
+    }
+    // Get the database usage
+    let view_model = get_database_usage_view_model(&arm_client, &database).await?;
     // Return the view model as JSON
     Ok(web::Json(view_model))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{
+        arm_client, dashboard_settings, database_json, TestResponse, TestServer,
+    };
+
+    // The path of the configured elastic pool
+    const POOL_PATH: &str =
+        "/subscriptions/s1/resourceGroups/rg1/providers/Microsoft.Sql/servers/server1/elasticPools/pool1";
+
+    // Creates settings with a configured database and elastic pool.
+    fn settings(server: &TestServer, allow_discovered_databases: bool) -> DashboardSettings {
+        dashboard_settings(
+            server,
+            serde_json::json!({
+                "allow_discovered_databases": allow_discovered_databases,
+                "subscriptions": [{
+                    "display_name": "Subscription 1",
+                    "subscription_id": "s1",
+                    "resource_groups": [{
+                        "resource_group_name": "rg1",
+                        "databases": [{ "server_name": "server1", "database_name": "db1" }],
+                        "elastic_pools": [{ "server_name": "server1", "elastic_pool_name": "pool1" }],
+                    }],
+                }],
+            }),
+        )
+    }
+
+    // Identifies a database.
+    fn database(
+        subscription_id: &str,
+        resource_group_name: &str,
+        database_name: &str,
+    ) -> DatabaseId {
+        DatabaseId {
+            subscription_id: subscription_id.to_string(),
+            resource_group_name: resource_group_name.to_string(),
+            server_name: "SERVER1".to_string(),
+            database_name: database_name.to_string(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn configured_database_is_allowed_without_asking_azure() {
+        let server = TestServer::start_arm(|_| TestResponse::new(500));
+        let settings = settings(&server, false);
+        let arm_client = arm_client(&settings);
+        assert!(is_allowed_database(&arm_client, &settings, &database("S1", "RG1", "DB1")).await);
+        // The same server and database in another subscription or resource group isn't
+        assert!(!is_allowed_database(&arm_client, &settings, &database("s2", "rg1", "db1")).await);
+        assert!(!is_allowed_database(&arm_client, &settings, &database("s1", "rg2", "db1")).await);
+        assert!(server.requests().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn discovered_database_is_only_allowed_if_enabled_and_in_a_configured_pool() {
+        let server = TestServer::start_arm(|request| {
+            // ARM paths aren't case-sensitive
+            if request
+                .path
+                .to_lowercase()
+                .starts_with(&format!("{POOL_PATH}/databases?").to_lowercase())
+            {
+                TestResponse::json(
+                    200,
+                    serde_json::json!({ "value": [database_json("db2", POOL_PATH)] }),
+                )
+            } else {
+                TestResponse::new(404)
+            }
+        });
+        // If discovered databases aren't allowed, the pool isn't looked at
+        let settings_without_discovery = settings(&server, false);
+        let arm_client = arm_client(&settings_without_discovery);
+        assert!(
+            !is_allowed_database(
+                &arm_client,
+                &settings_without_discovery,
+                &database("s1", "rg1", "db2")
+            )
+            .await
+        );
+        assert!(server.requests().is_empty());
+        // If they are, a database in the pool is allowed, and one that isn't isn't
+        let settings = settings(&server, true);
+        let arm_client = self::arm_client(&settings);
+        assert!(is_allowed_database(&arm_client, &settings, &database("s1", "rg1", "DB2")).await);
+        assert!(!is_allowed_database(&arm_client, &settings, &database("s1", "rg1", "db3")).await);
+        // ...but not in another resource group, whose pools aren't configured
+        let requests = server.requests().len();
+        assert!(!is_allowed_database(&arm_client, &settings, &database("s1", "rg2", "db2")).await);
+        assert_eq!(requests, server.requests().len());
+    }
+
+    #[actix_web::test]
+    async fn pool_that_cant_be_listed_doesnt_allow_the_database() {
+        let server = TestServer::start_arm(|_| {
+            TestResponse::json(
+                403,
+                serde_json::json!({ "error": { "code": "AuthorizationFailed", "message": "No" } }),
+            )
+        });
+        let settings = settings(&server, true);
+        let arm_client = arm_client(&settings);
+        assert!(!is_allowed_database(&arm_client, &settings, &database("s1", "rg1", "db2")).await);
+    }
+}
@@ -0,0 +1,30 @@
+use crate::azure_apis::arm_client::ArmClient;
+use crate::azure_apis::concurrency::ConcurrencyMetrics;
+use crate::permission_check::{check_permissions, PermissionReport};
+use crate::settings::DashboardSettings;
+use crate::AzureDashboardError;
+use actix_web::{get, web};
+
+// Checks the permissions on each configured resource group, and returns the result as JSON
+#[get("/api/diagnostics/permissions")]
+pub async fn permissions(
+    arm_client: web::Data<ArmClient>,
+    settings: web::Data<DashboardSettings>,
+) -> Result<web::Json<PermissionReport>, AzureDashboardError> {
+    log::debug!("permissions");
+    // Check the permissions
+    let report = check_permissions(arm_client.get_ref(), settings.get_ref()).await;
+    report.log_summary();
+    // Return the report as JSON
+    Ok(web::Json(report))
+}
+
+// Returns the limits on concurrent Azure requests, and how requests have queued because of them, as JSON
+#[get("/api/diagnostics/concurrency")]
+pub async fn concurrency(
+    arm_client: web::Data<ArmClient>,
+) -> Result<web::Json<ConcurrencyMetrics>, AzureDashboardError> {
+    log::debug!("concurrency");
+    // Return the metrics as JSON
+    Ok(web::Json(arm_client.concurrency_metrics()))
+}
@@ -1,15 +1,47 @@
-use crate::azure_apis::get_database_usage::get_database_usage;
-use crate::azure_apis::get_elastic_pool::{get_elastic_pool, ElasticPool};
-use crate::azure_apis::list_databases_in_elastic_pool::{
-    list_databases_in_elastic_pool, Database, DatabaseListResponse,
-};
+use crate::azure_apis::arm_client::{ArmClient, DatabaseId, ElasticPoolId};
+use crate::azure_apis::batch::{Batch, BatchItem, BatchListItem, BatchResponses};
+use crate::azure_apis::get_database_usage::DatabaseUsageResponse;
+use crate::azure_apis::get_elastic_pool::ElasticPool;
+use crate::azure_apis::list_databases_in_elastic_pool::{Database, DatabaseListResponse};
 use crate::settings::{
-    DashboardSettings, DatabaseSettings, ElasticPoolSettings, SubscriptionSettings,
+    DashboardSettings, DatabaseSettings, ElasticPoolSettings, ElasticPoolUsageSource,
+    SubscriptionSettings,
 };
-use crate::AzureDashboardError::AzureApiError;
 use crate::{AccessTokenCacheMap, AzureDashboardError};
 use actix_web::{get, web};
 
+// The usage of a database in the pool.
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PoolDatabaseUsageViewModel {
+    // The database name
+    database_name: String,
+    // The amount of data used
+    database_size_used: u64,
+    // The amount of data allocated
+    database_size_allocated: u64,
+    // The maximum size of the database
+    database_size_max: u64,
+    // The SKU name, e.g. "ElasticPool"
+    sku_name: Option<String>,
+    // The SKU tier, e.g. "Standard"
+    sku_tier: Option<String>,
+    // The database status, e.g. "Online"
+    status: String,
+    // The fraction of the pool's maximum size the database uses, from 0 to 1
+    pool_share: f64,
+}
+
+// A database in the pool whose usage couldn't be had, so isn't included in the totals.
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FailedDatabaseViewModel {
+    // The database name
+    database_name: String,
+    // Why its usage couldn't be had
+    error: String,
+}
+
 #[derive(Debug, serde::Serialize)]
 #[serde(rename_all = "camelCase")]
 pub struct ElasticPoolUsageViewModel {
@@ -19,107 +51,124 @@ pub struct ElasticPoolUsageViewModel {
     database_size_allocated: u64,
     // The maximum size of the database
     database_size_max: u64,
+    // Where the amounts used and allocated came from: "databases" or "metrics"
+    source: ElasticPoolUsageSource,
+    // The usage of each database in the pool, largest first.  Empty if the usage came from the pool's metrics.
+    databases: Vec<PoolDatabaseUsageViewModel>,
+    // Whether some databases' usages couldn't be had, so the amounts used and allocated are too low
+    partial: bool,
+    // The databases whose usages couldn't be had
+    failed_databases: Vec<FailedDatabaseViewModel>,
 }
 
-// Returns info related to an elastic pool as JSON
-#[get("/api/subscription/{subscription_id}/resource-group/{resource_group_name}/server/{server_name}/elastic-pool/{elastic_pool_name}/usage")]
-pub async fn elastic_pool_usage(
-    path: web::Path<(String, String, String, String)>,
-    settings: web::Data<DashboardSettings>,
-    http_client: web::Data<reqwest::Client>,
-    token_cache_map: web::Data<AccessTokenCacheMap>,
-) -> Result<web::Json<ElasticPoolUsageViewModel>, AzureDashboardError> {
-    log::debug!("elastic_pool");
-    // Get the path components
-    let (subscription_id, resource_group_name, server_name, elastic_pool_name) = path.into_inner();
-    log::debug!(
-        "database - \
-         subscription_id = {subscription_id}, \
-         resource_group_name = {resource_group_name}, \
-         server_name = {server_name}, \
-         elastic_pool_name = {elastic_pool_name}",
-    );
-    log::debug!(" - getting elastic pool info");
-    // Get the elastic pool info
-    let elastic_pool_response = get_elastic_pool(
-        http_client.get_ref(),
-        token_cache_map.get_ref(),
-        subscription_id.clone(),
-        resource_group_name.clone(),
-        server_name.clone(),
-        elastic_pool_name.clone(),
-    )
-    .await
-    // If we got an error, convert it to an Azure API error
-    .map_err(|e| AzureApiError(e.to_string()))?;
-    log::debug!(" - got elastic pool response");
+// Gets the usages of the given databases, in the same order, in one batch.
+async fn get_database_usages_in_batch(
+    arm_client: &ArmClient,
+    elastic_pool: &ElasticPoolId,
+    databases: &[DatabaseId],
+) -> Vec<anyhow::Result<DatabaseUsageResponse>> {
+    // Add the requests to the batch
+    let mut batch = arm_client.batch(&elastic_pool.subscription_id);
+    let items = databases
+        .iter()
+        .map(|database| batch.get_database_usage(database))
+        .collect::<Vec<_>>();
+    // Send it, and take each database's usages from the responses
+    let mut responses = batch.send().await;
+    let mut database_usage_responses = Vec::with_capacity(items.len());
+    for item in items {
+        database_usage_responses.push(match item {
+            Ok(item) => responses.take_list(item).await,
+            Err(e) => Err(e),
+        });
+    }
+    database_usage_responses
+}
+
+// Gets the usage of an elastic pool by summing the usages of each database in it.  If batch_requests is set, the
+// databases' usages are got in one batch.
+async fn sum_database_usages(
+    arm_client: &ArmClient,
+    elastic_pool: &ElasticPoolId,
+    database_size_max: u64,
+    batch_requests: bool,
+) -> Result<ElasticPoolUsageViewModel, AzureDashboardError> {
     log::debug!(" - getting elastic pool list");
     // Get the databases in the elastic pool
-    let database_list_response = list_databases_in_elastic_pool(
-        http_client.get_ref(),
-        token_cache_map.get_ref(),
-        subscription_id.clone(),
-        resource_group_name.clone(),
-        server_name.clone(),
-        elastic_pool_name.clone(),
-    )
-    .await
-    // If we got an error, convert it to an Azure API error
-    .map_err(|e| AzureApiError(e.to_string()))?;
+    let database_list_response = arm_client
+        .list_databases_in_elastic_pool(elastic_pool)
+        .await
+        // If we got an error, convert it to an Azure API error
+        .map_err(AzureDashboardError::from)?;
     log::debug!(" - got database list response");
 
-    // We have the size of the elastic pool as a whole.
-    let database_size_max: u64 = elastic_pool_response.properties.max_size_bytes;
     // Since there's no elastic pool usage API, we need to sum the usages of each database in the pool.
+    // Identify each database in the pool
+    let databases = database_list_response
+        .values()
+        .iter()
+        .map(|database| elastic_pool.database(&database.name))
+        .collect::<Vec<_>>();
+    let database_usage_responses = if batch_requests {
+        get_database_usages_in_batch(arm_client, elastic_pool, &databases).await
+    } else {
+        // Get the futures that will fetch the database usages for each database
+        let database_usage_response_futures = databases.iter().map(|database|
+            // Get the database usages
+            arm_client.get_database_usage(database));
+        // Execute the futures in parallel, as far as the ARM client's concurrency limits allow, keeping going if
+        // any fail
+        futures::future::join_all(database_usage_response_futures).await
+    };
+    Ok(total_database_usages(
+        elastic_pool,
+        database_size_max,
+        &database_list_response,
+        &databases,
+        database_usage_responses,
+    ))
+}
+
+// Totals the usages of the databases in an elastic pool, in the order they were listed in, into the pool's usage.
+// Databases whose usages couldn't be had are noted and left out of the totals.
+fn total_database_usages(
+    elastic_pool: &ElasticPoolId,
+    database_size_max: u64,
+    database_list_response: &DatabaseListResponse,
+    databases: &[DatabaseId],
+    database_usage_responses: Vec<anyhow::Result<DatabaseUsageResponse>>,
+) -> ElasticPoolUsageViewModel {
+    // There's an ID and a usage response for each listed database, or they'd be matched up wrongly
+    debug_assert_eq!(database_list_response.values().len(), databases.len());
+    debug_assert_eq!(databases.len(), database_usage_responses.len());
     let mut database_size_used: u64 = 0;
     let mut database_size_allocated: u64 = 0;
-    /*
-    // Get the futures that will fetch the database usages for each database
-    for database in database_list_response.values() {
-        log::debug!(" - getting usage info for database {:?}", database.name);
-        // Get the database usages
-        let database_usage_response = get_database_usage(
-            http_client.get_ref(),
-            token_cache_map.get_ref(),
-            subscription_id.clone(),
-            resource_group_name.clone(),
-            server_name.clone(),
-            database.name.clone(),
-        )
-        .await
-        // If we got an error, convert it to an Azure API error
-        .map_err(|e| AzureApiError(e.to_string()))?;
-        // Get the database's sizes
-        let (size_used, size_allocated, _size_max) = database_usage_response.get_sizes();
-        // Add them to the elastic pool's sizes
-        database_size_used += size_used;
-        database_size_allocated += size_allocated;
-        log::debug!(
-            " - adding sizes size = {:?}, allocated = {:?}",
-            database_size_used,
-            database_size_allocated
-        );
-    }
-     */
-
-    // Get the futures that will fetch the database usages for each database
-    let database_usage_response_futures = database_list_response.values().iter().map(|database|
-        // Get the database usages
-        get_database_usage(
-            http_client.get_ref(),
-            token_cache_map.get_ref(),
-            subscription_id.clone(),
-            resource_group_name.clone(),
-            server_name.clone(),
-            database.name.clone(),
-        ));
-    // Execute the futures in parallel
-    let database_usage_responses = futures::future::try_join_all(database_usage_response_futures)
-        .await
-        .unwrap();
-    for database_usage_response in database_usage_responses {
+    let mut database_usages = Vec::new();
+    let mut failed_databases = Vec::new();
+    for ((listed_database, database), database_usage_response) in database_list_response
+        .values()
+        .iter()
+        .zip(databases)
+        .zip(database_usage_responses)
+    {
+        // Note the databases whose usages we couldn't get, and leave them out of the totals
+        let database_usage_response = match database_usage_response {
+            Ok(database_usage_response) => database_usage_response,
+            Err(e) => {
+                log::warn!(
+                    "Could not get the usage of database {} in elastic pool {}: {e}",
+                    database.database_name,
+                    elastic_pool.elastic_pool_name
+                );
+                failed_databases.push(FailedDatabaseViewModel {
+                    database_name: database.database_name.clone(),
+                    error: e.to_string(),
+                });
+                continue;
+            }
+        };
         // Get the databases sizes
-        let (size_used, size_allocated, _size_max) = database_usage_response.get_sizes();
+        let (size_used, size_allocated, size_max) = database_usage_response.get_sizes();
         // Add them to the elastic pool's sizes
         database_size_used += size_used;
         database_size_allocated += size_allocated;
@@ -128,18 +177,350 @@ pub async fn elastic_pool_usage(
             database_size_used,
             database_size_allocated
         );
+        // Add the database to the breakdown
+        database_usages.push(PoolDatabaseUsageViewModel {
+            database_name: listed_database.name.clone(),
+            database_size_used: size_used,
+            database_size_allocated: size_allocated,
+            database_size_max: size_max,
+            sku_name: listed_database.sku.as_ref().map(|sku| sku.name.clone()),
+            sku_tier: listed_database.sku.as_ref().map(|sku| sku.tier.clone()),
+            status: listed_database.properties.status.clone(),
+            pool_share: if 0 < database_size_max {
+                size_used as f64 / database_size_max as f64
+            } else {
+                0.0
+            },
+        });
     }
+    // Put the largest databases first
+    database_usages.sort_by(|a, b| {
+        b.database_size_used
+            .cmp(&a.database_size_used)
+            .then(b.database_size_allocated.cmp(&a.database_size_allocated))
+            .then(a.database_name.cmp(&b.database_name))
+    });
     log::debug!(
         " - final, size = {:?}, allocated = {:?}",
         database_size_used,
         database_size_allocated
     );
     // Create the view model
-    let view_model = ElasticPoolUsageViewModel {
+    ElasticPoolUsageViewModel {
         database_size_allocated,
         database_size_used,
         database_size_max,
-    };
+        source: ElasticPoolUsageSource::Databases,
+        databases: database_usages,
+        partial: !failed_databases.is_empty(),
+        failed_databases,
+    }
+}
+
+// Returns info related to an elastic pool as JSON
+#[get("/api/subscription/{subscription_id}/resource-group/{resource_group_name}/server/{server_name}/elastic-pool/{elastic_pool_name}/usage")]
+pub async fn elastic_pool_usage(
+    elastic_pool: web::Path<ElasticPoolId>,
+    arm_client: web::Data<ArmClient>,
+    settings: web::Data<DashboardSettings>,
+) -> Result<web::Json<ElasticPoolUsageViewModel>, AzureDashboardError> {
+    log::debug!("elastic_pool - elastic_pool = {elastic_pool:?}");
+    // Don't let the dashboard be used to look at elastic pools it wasn't set up for
+    if !settings.is_configured_elastic_pool(&elastic_pool) {
+        return Err(AzureDashboardError::NotConfigured(format!(
+            "Elastic pool {}/{}",
+            elastic_pool.server_name, elastic_pool.elastic_pool_name
+        )));
+    }
+    // Get the elastic pool usage
+    let view_model =
+        get_elastic_pool_usage_view_model(&arm_client, &settings, &elastic_pool).await?;
     // Return the view model as json
     Ok(web::Json(view_model))
 }
+
+// Gets the usage of an elastic pool, from its metrics or its databases' usages as configured.
+pub async fn get_elastic_pool_usage_view_model(
+    arm_client: &ArmClient,
+    settings: &DashboardSettings,
+    elastic_pool: &ElasticPoolId,
+) -> Result<ElasticPoolUsageViewModel, AzureDashboardError> {
+    log::debug!(" - getting elastic pool info");
+    // Get the elastic pool info
+    let elastic_pool_response = arm_client
+        .get_elastic_pool(elastic_pool)
+        .await
+        // If we got an error, convert it to an Azure API error
+        .map_err(AzureDashboardError::from)?;
+    log::debug!(" - got elastic pool response");
+    // We have the size of the elastic pool as a whole.
+    let database_size_max: u64 = elastic_pool_response.properties.max_size_bytes;
+    get_usage_from_source(arm_client, settings, elastic_pool, database_size_max).await
+}
+
+// Gets the usage of an elastic pool of the given maximum size, from its metrics or its databases' usages as
+// configured.
+async fn get_usage_from_source(
+    arm_client: &ArmClient,
+    settings: &DashboardSettings,
+    elastic_pool: &ElasticPoolId,
+    database_size_max: u64,
+) -> Result<ElasticPoolUsageViewModel, AzureDashboardError> {
+    // Get the pool's storage from its metrics, if configured to
+    if ElasticPoolUsageSource::Metrics == settings.elastic_pool_usage_source {
+        match arm_client.get_elastic_pool_storage(elastic_pool).await {
+            Ok(storage) => {
+                log::debug!(" - got elastic pool storage {storage:?}");
+                // Create the view model
+                return Ok(ElasticPoolUsageViewModel {
+                    database_size_used: storage.used,
+                    database_size_allocated: storage.allocated,
+                    database_size_max,
+                    source: ElasticPoolUsageSource::Metrics,
+                    databases: Vec::new(),
+                    partial: false,
+                    failed_databases: Vec::new(),
+                });
+            }
+            // If the metrics aren't available, sum the databases' usages instead
+            Err(e) => log::warn!(
+                "Could not get the metrics of elastic pool {}, so summing its databases' usages: {e}",
+                elastic_pool.elastic_pool_name
+            ),
+        }
+    }
+    // Sum the usages of the databases in the pool
+    sum_database_usages(
+        arm_client,
+        elastic_pool,
+        database_size_max,
+        settings.arm.batch_requests,
+    )
+    .await
+}
+
+// The requests for an elastic pool's usage added to a batch.
+pub struct ElasticPoolBatchItems {
+    // The elastic pool
+    elastic_pool: BatchItem<ElasticPool>,
+    // The databases in it, unless its usage comes from its metrics
+    database_list: Option<BatchListItem<Database, DatabaseListResponse>>,
+}
+
+// Adds the requests for an elastic pool's usage to a batch: the pool itself and, unless its usage comes from its
+// metrics, the databases in it.
+pub fn add_elastic_pool_usage_to_batch(
+    batch: &mut Batch<'_>,
+    settings: &DashboardSettings,
+    elastic_pool: &ElasticPoolId,
+) -> anyhow::Result<ElasticPoolBatchItems> {
+    Ok(ElasticPoolBatchItems {
+        elastic_pool: batch.get_elastic_pool(elastic_pool)?,
+        database_list: match settings.elastic_pool_usage_source {
+            ElasticPoolUsageSource::Databases => {
+                Some(batch.list_databases_in_elastic_pool(elastic_pool)?)
+            }
+            ElasticPoolUsageSource::Metrics => None,
+        },
+    })
+}
+
+// Gets the usages of elastic pools in a subscription from a batch's responses to the requests added for them by
+// add_elastic_pool_usage_to_batch, in the same order.  The usages of the databases in them are got in one more
+// batch, and pools whose usage comes from their metrics are got as the elastic pool usage route would.
+pub async fn get_elastic_pool_usage_view_models_from_batch(
+    arm_client: &ArmClient,
+    settings: &DashboardSettings,
+    subscription_id: &str,
+    elastic_pools: Vec<(ElasticPoolId, anyhow::Result<ElasticPoolBatchItems>)>,
+    responses: &mut BatchResponses<'_>,
+) -> Vec<Result<ElasticPoolUsageViewModel, AzureDashboardError>> {
+    // Take each pool's size and the databases in it, and add the requests for those databases' usages to the
+    // next batch
+    let mut database_usage_batch = arm_client.batch(subscription_id);
+    let mut pending = Vec::with_capacity(elastic_pools.len());
+    for (elastic_pool, items) in elastic_pools {
+        let pool = async {
+            let items = items?;
+            let database_size_max = responses
+                .take(items.elastic_pool)?
+                .properties
+                .max_size_bytes;
+            let Some(database_list) = items.database_list else {
+                return Ok((database_size_max, None));
+            };
+            let database_list_response = responses.take_list(database_list).await?;
+            let databases = database_list_response
+                .values()
+                .iter()
+                .map(|database| elastic_pool.database(&database.name))
+                .collect::<Vec<_>>();
+            let database_usages = databases
+                .iter()
+                .map(|database| database_usage_batch.get_database_usage(database))
+                .collect::<Vec<_>>();
+            Ok::<_, anyhow::Error>((
+                database_size_max,
+                Some((database_list_response, databases, database_usages)),
+            ))
+        }
+        .await;
+        pending.push((elastic_pool, pool));
+    }
+    // Send it, and total each pool's databases' usages
+    let mut database_usage_responses = database_usage_batch.send().await;
+    let mut elastic_pool_usages = Vec::with_capacity(pending.len());
+    let mut metrics_usage_futures = Vec::new();
+    for (index, (elastic_pool, pool)) in pending.into_iter().enumerate() {
+        elastic_pool_usages.push(match pool {
+            Ok((database_size_max, Some((database_list_response, databases, database_usages)))) => {
+                let mut usages = Vec::with_capacity(database_usages.len());
+                for item in database_usages {
+                    usages.push(match item {
+                        Ok(item) => database_usage_responses.take_list(item).await,
+                        Err(e) => Err(e),
+                    });
+                }
+                Some(Ok(total_database_usages(
+                    &elastic_pool,
+                    database_size_max,
+                    &database_list_response,
+                    &databases,
+                    usages,
+                )))
+            }
+            // If its usage comes from its metrics, get them as the elastic pool usage route would
+            Ok((database_size_max, None)) => {
+                metrics_usage_futures.push(async move {
+                    (
+                        index,
+                        get_usage_from_source(
+                            arm_client,
+                            settings,
+                            &elastic_pool,
+                            database_size_max,
+                        )
+                        .await,
+                    )
+                });
+                None
+            }
+            Err(e) => Some(Err(AzureDashboardError::from(e))),
+        });
+    }
+    // Get the metrics in parallel
+    for (index, usage) in futures::future::join_all(metrics_usage_futures).await {
+        elastic_pool_usages[index] = Some(usage);
+    }
+    // Every pool has its usage by now
+    elastic_pool_usages.into_iter().flatten().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_server::{database_json, database_usages_json};
+
+    // The maximum size of the pool
+    const POOL_SIZE_MAX: u64 = 1000;
+
+    // Identifies the elastic pool.
+    fn elastic_pool() -> ElasticPoolId {
+        ElasticPoolId {
+            subscription_id: "s1".to_string(),
+            resource_group_name: "rg1".to_string(),
+            server_name: "server1".to_string(),
+            elastic_pool_name: "pool1".to_string(),
+        }
+    }
+
+    // Lists the databases with the given names in the pool, and identifies them.
+    fn list_databases(names: &[&str]) -> (DatabaseListResponse, Vec<DatabaseId>) {
+        let pool_id = "/subscriptions/s1/resourceGroups/rg1/providers/Microsoft.Sql/servers/server1/elasticPools/pool1";
+        let database_list_response = serde_json::from_value(serde_json::json!({
+            "value": names.iter().map(|name| database_json(name, pool_id)).collect::<Vec<_>>(),
+        }))
+        .unwrap();
+        let databases = names
+            .iter()
+            .map(|name| elastic_pool().database(name))
+            .collect();
+        (database_list_response, databases)
+    }
+
+    // Creates a database's usage response.
+    fn usage(used: u64, allocated: u64) -> anyhow::Result<DatabaseUsageResponse> {
+        Ok(serde_json::from_value(serde_json::json!({
+            "value": database_usages_json(used, allocated, 500),
+        }))
+        .unwrap())
+    }
+
+    #[test]
+    fn databases_whose_usage_failed_are_left_out_of_the_totals() {
+        let (database_list_response, databases) = list_databases(&["db1", "db2", "db3"]);
+        let view_model = total_database_usages(
+            &elastic_pool(),
+            POOL_SIZE_MAX,
+            &database_list_response,
+            &databases,
+            vec![
+                usage(100, 150),
+                Err(anyhow::anyhow!("Throttled")),
+                usage(200, 250),
+            ],
+        );
+        assert_eq!(300, view_model.database_size_used);
+        assert_eq!(400, view_model.database_size_allocated);
+        assert_eq!(POOL_SIZE_MAX, view_model.database_size_max);
+        assert!(view_model.partial);
+        assert_eq!(1, view_model.failed_databases.len());
+        assert_eq!("db2", view_model.failed_databases[0].database_name);
+        assert_eq!("Throttled", view_model.failed_databases[0].error);
+    }
+
+    #[test]
+    fn databases_are_listed_largest_first() {
+        let (database_list_response, databases) =
+            list_databases(&["small", "b-tied", "a-tied", "less-allocated", "large"]);
+        let view_model = total_database_usages(
+            &elastic_pool(),
+            POOL_SIZE_MAX,
+            &database_list_response,
+            &databases,
+            vec![
+                usage(50, 60),
+                usage(100, 150),
+                usage(100, 150),
+                usage(100, 120),
+                usage(250, 300),
+            ],
+        );
+        // By size used, then size allocated, then name
+        assert_eq!(
+            vec!["large", "a-tied", "b-tied", "less-allocated", "small"],
+            view_model
+                .databases
+                .iter()
+                .map(|d| d.database_name.as_str())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(0.25, view_model.databases[0].pool_share);
+        assert_eq!(500, view_model.databases[0].database_size_max);
+        assert!(!view_model.partial);
+    }
+
+    #[test]
+    fn pool_share_is_zero_if_the_pool_has_no_maximum_size() {
+        let (database_list_response, databases) = list_databases(&["db1"]);
+        let view_model = total_database_usages(
+            &elastic_pool(),
+            0,
+            &database_list_response,
+            &databases,
+            vec![usage(100, 150)],
+        );
+        assert_eq!(0.0, view_model.databases[0].pool_share);
+        assert_eq!(100, view_model.database_size_used);
+    }
+}
@@ -0,0 +1,396 @@
+use crate::azure_apis::get_application::{get_application, PasswordCredential};
+use crate::azure_apis::retry::RetryPolicy;
+use crate::azure_token_cache::TokenAcquisition;
+use crate::settings::{CredentialKind, DashboardSettings, SubscriptionSettings};
+use crate::{AccessTokenCacheMap, AzureDashboardError};
+use actix_web::{get, web};
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+
+// The result of the most recent attempt to get an access token.
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TokenAcquisitionViewModel {
+    // When the attempt was made
+    attempted_at: DateTime<Utc>,
+    // Whether a token was returned
+    succeeded: bool,
+    // The error code, e.g. "AADSTS7000222", if the token endpoint returned one
+    error_code: Option<String>,
+    // The error message, if the attempt failed
+    error_message: Option<String>,
+}
+
+// Creates the view model from a token acquisition.
+impl From<TokenAcquisition> for TokenAcquisitionViewModel {
+    fn from(value: TokenAcquisition) -> Self {
+        Self {
+            attempted_at: value.attempted_at,
+            succeeded: value.succeeded,
+            error_code: value.error_code,
+            error_message: value.error_message,
+        }
+    }
+}
+
+// The health of the credential a subscription uses.
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct SubscriptionCredentialHealthViewModel {
+    // The display name for this subscription
+    display_name: String,
+    // The subscription ID (a GUID)
+    subscription_id: String,
+    // The credential key, e.g. "CLOUD/credentials/NAME" or "CLOUD/clients/TENANT_ID/CLIENT_ID/KINDS"
+    credential: String,
+    // The result of the most recent attempt to get an access token, if one has been made
+    last_token_acquisition: Option<TokenAcquisitionViewModel>,
+    // When the current access token expires, if there is one
+    token_expiry: Option<DateTime<Utc>>,
+    // When the client secret expires, if the credential uses one and it could be looked up
+    secret_expiry: Option<DateTime<Utc>>,
+    // Why the client secret's expiry couldn't be looked up, if it couldn't
+    secret_expiry_error: Option<String>,
+    // Problems that need attention, e.g. a secret that expires soon
+    warnings: Vec<String>,
+}
+
+// The health of the credentials of all subscriptions.
+#[derive(Debug, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CredentialHealthViewModel {
+    // The subscriptions.
+    subscriptions: Vec<SubscriptionCredentialHealthViewModel>,
+}
+
+// Finds the client secret in use among an app registration's secrets, by its hint.
+// If the hint isn't known or doesn't match, assumes the secret that expires soonest.
+fn find_password_credential<'a>(
+    password_credentials: &'a [PasswordCredential],
+    hint: Option<&str>,
+) -> Option<&'a PasswordCredential> {
+    hint.and_then(|hint| {
+        password_credentials
+            .iter()
+            .find(|p| p.hint.as_deref() == Some(hint))
+    })
+    .or_else(|| {
+        password_credentials
+            .iter()
+            .filter(|p| p.end_date_time.is_some())
+            .min_by_key(|p| p.end_date_time)
+    })
+}
+
+// Looks up when the client secret the subscription uses expires.
+async fn get_secret_expiry(
+    http_client: &reqwest::Client,
+    retry_policy: &RetryPolicy,
+    token_cache_map: &AccessTokenCacheMap,
+    settings: &DashboardSettings,
+    subscription: &SubscriptionSettings,
+    client_id: &str,
+) -> anyhow::Result<Option<DateTime<Utc>>> {
+    // Get the app registration
+    let application = get_application(
+        http_client,
+        retry_policy,
+        token_cache_map,
+        subscription.subscription_id.clone(),
+        settings.credential_health.graph_endpoint.as_deref(),
+        client_id,
+    )
+    .await?;
+    // Find the secret we use
+    let hint = token_cache_map
+        .client_secret_hint(&subscription.subscription_id)
+        .await?;
+    let password_credential =
+        find_password_credential(&application.password_credentials, hint.as_deref())
+            .ok_or_else(|| anyhow::anyhow!("The app registration has no client secrets"))?;
+    log::debug!(
+        " - subscription {} uses secret {}",
+        subscription.subscription_id,
+        password_credential.key_id
+    );
+    Ok(password_credential.end_date_time)
+}
+
+// When a credential's client secret expires, if it uses one and it could be looked up, and why it couldn't be
+// looked up, if it couldn't.
+#[derive(Clone, Debug, Default)]
+struct SecretExpiry {
+    // When the client secret expires
+    expiry: Option<DateTime<Utc>>,
+    // Why the expiry couldn't be looked up
+    error: Option<String>,
+}
+
+// Looks up when the client secret of the credential the subscription uses expires, if it uses one.
+async fn get_credential_secret_expiry(
+    http_client: &reqwest::Client,
+    retry_policy: &RetryPolicy,
+    token_cache_map: &AccessTokenCacheMap,
+    settings: &DashboardSettings,
+    subscription: &SubscriptionSettings,
+) -> anyhow::Result<SecretExpiry> {
+    let credential_settings = settings.credential_settings(subscription)?;
+    Ok(match &credential_settings.client_id {
+        Some(client_id)
+            if credential_settings
+                .credentials
+                .contains(&CredentialKind::ClientSecret) =>
+        {
+            match get_secret_expiry(
+                http_client,
+                retry_policy,
+                token_cache_map,
+                settings,
+                subscription,
+                client_id,
+            )
+            .await
+            {
+                Ok(expiry) => SecretExpiry {
+                    expiry,
+                    error: None,
+                },
+                Err(e) => SecretExpiry {
+                    expiry: None,
+                    error: Some(e.to_string()),
+                },
+            }
+        }
+        _ => SecretExpiry::default(),
+    })
+}
+
+// Gets the health of the credential a subscription uses, given when its client secret expires.
+fn get_subscription_credential_health(
+    token_cache_map: &AccessTokenCacheMap,
+    settings: &DashboardSettings,
+    subscription: &SubscriptionSettings,
+    secret_expiry: &SecretExpiry,
+) -> anyhow::Result<SubscriptionCredentialHealthViewModel> {
+    let subscription_id = &subscription.subscription_id;
+    let mut warnings = Vec::new();
+    // Get the state of the subscription's token cache
+    let token_health = token_cache_map.token_health(subscription_id)?;
+    if let Some(acquisition) = token_health
+        .last_acquisition
+        .as_ref()
+        .filter(|a| !a.succeeded)
+    {
+        warnings.push(format!(
+            "The last token request failed{}",
+            acquisition
+                .error_code
+                .as_ref()
+                .map(|code| format!(" with {code}"))
+                .unwrap_or_default()
+        ));
+    }
+    // Warn if the client secret has expired or expires soon
+    if let Some(secret_expiry) = secret_expiry.expiry {
+        let days_left = (secret_expiry - Utc::now()).num_days();
+        if secret_expiry < Utc::now() {
+            warnings.push(format!("The client secret expired on {secret_expiry}"));
+        } else if days_left < settings.credential_health.secret_expiry_warning_days {
+            warnings.push(format!(
+                "The client secret expires in {days_left} days, on {secret_expiry}"
+            ));
+        }
+    }
+    for warning in &warnings {
+        log::warn!("Subscription {subscription_id}: {warning}");
+    }
+    Ok(SubscriptionCredentialHealthViewModel {
+        display_name: subscription.display_name.clone(),
+        subscription_id: subscription_id.clone(),
+        credential: token_cache_map.credential_key(subscription_id)?.to_string(),
+        last_token_acquisition: token_health.last_acquisition.map(|a| a.into()),
+        token_expiry: token_health.token_expiry,
+        secret_expiry: secret_expiry.expiry,
+        secret_expiry_error: secret_expiry.error.clone(),
+        warnings,
+    })
+}
+
+// Gets the health of each subscription's credential.
+async fn get_credential_health(
+    http_client: &reqwest::Client,
+    retry_policy: &RetryPolicy,
+    token_cache_map: &AccessTokenCacheMap,
+    settings: &DashboardSettings,
+) -> anyhow::Result<CredentialHealthViewModel> {
+    // Find the first subscription that uses each credential.  Subscriptions that share a credential share its
+    // app registration, so its secret's expiry is only looked up once.
+    let mut credential_subscriptions: Vec<(&str, &SubscriptionSettings)> = Vec::new();
+    for subscription in &settings.subscriptions {
+        let credential_key = token_cache_map.credential_key(&subscription.subscription_id)?;
+        if !credential_subscriptions
+            .iter()
+            .any(|(key, _)| *key == credential_key)
+        {
+            credential_subscriptions.push((credential_key, subscription));
+        }
+    }
+    // Look up when each credential's client secret expires
+    let secret_expiries = futures::future::try_join_all(credential_subscriptions.iter().map(
+        |(credential_key, subscription)| async move {
+            let secret_expiry = get_credential_secret_expiry(
+                http_client,
+                retry_policy,
+                token_cache_map,
+                settings,
+                subscription,
+            )
+            .await?;
+            anyhow::Ok((*credential_key, secret_expiry))
+        },
+    ))
+    .await?
+    .into_iter()
+    .collect::<HashMap<_, _>>();
+    // Get the health of each subscription's credential
+    let subscriptions = settings
+        .subscriptions
+        .iter()
+        .map(|subscription| {
+            let credential_key = token_cache_map.credential_key(&subscription.subscription_id)?;
+            get_subscription_credential_health(
+                token_cache_map,
+                settings,
+                subscription,
+                &secret_expiries[credential_key],
+            )
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok(CredentialHealthViewModel { subscriptions })
+}
+
+// Returns the health of each subscription's credential as JSON
+#[get("/api/health/credentials")]
+pub async fn credential_health(
+    http_client: web::Data<reqwest::Client>,
+    retry_policy: web::Data<RetryPolicy>,
+    settings: web::Data<DashboardSettings>,
+    token_cache_map: web::Data<AccessTokenCacheMap>,
+) -> Result<web::Json<CredentialHealthViewModel>, AzureDashboardError> {
+    log::debug!("credential_health");
+    // Get the health of each subscription's credential
+    let credential_health = get_credential_health(
+        http_client.get_ref(),
+        retry_policy.get_ref(),
+        token_cache_map.get_ref(),
+        settings.get_ref(),
+    )
+    .await
+    .map_err(|e| {
+        log::error!("Could not get the credential health: {e}");
+        AzureDashboardError::InternalError
+    })?;
+    // Return the view model as JSON
+    Ok(web::Json(credential_health))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::secrets::SecretResolver;
+    use crate::settings::RetrySettings;
+    use crate::test_server::{TestResponse, TestServer};
+    use std::sync::Arc;
+
+    #[actix_web::test]
+    async fn secret_expiry_is_looked_up_once_per_credential() {
+        // The token endpoint and Graph
+        let server = TestServer::start(|request| {
+            if request.path.starts_with("/v1.0/applications") {
+                TestResponse::json(
+                    200,
+                    serde_json::json!({
+                        "displayName": "dashboard",
+                        "passwordCredentials": [
+                            { "keyId": "k1", "hint": "abc", "endDateTime": "2030-01-01T00:00:00Z" },
+                            { "keyId": "k2", "hint": "xyz", "endDateTime": "2029-01-01T00:00:00Z" },
+                        ],
+                    }),
+                )
+            } else {
+                TestResponse::token("token")
+            }
+        });
+        let credential = |client_id: &str| {
+            serde_json::json!({
+                "client_id": client_id,
+                "client_secret": "abcdef",
+                "token_url": format!("{}/t/oauth2/token", server.url()),
+            })
+        };
+        let subscription = |subscription_id: &str, credential: &str| {
+            serde_json::json!({
+                "display_name": subscription_id,
+                "subscription_id": subscription_id,
+                "resource_groups": [],
+                "credential": credential,
+            })
+        };
+        let settings = serde_json::from_value::<DashboardSettings>(serde_json::json!({
+            "host": "127.0.0.1",
+            "port": 8080,
+            "credentials": { "shared": credential("c1"), "other": credential("c2") },
+            "subscriptions": [
+                subscription("s1", "shared"),
+                subscription("s2", "shared"),
+                subscription("s3", "other"),
+            ],
+            "credential_health": { "graph_endpoint": server.url() },
+        }))
+        .unwrap();
+        let token_cache_map =
+            AccessTokenCacheMap::new(&settings, &Arc::new(SecretResolver::default())).unwrap();
+        let health = get_credential_health(
+            &reqwest::Client::new(),
+            &RetryPolicy::new(&RetrySettings::default()),
+            &token_cache_map,
+            &settings,
+        )
+        .await
+        .unwrap();
+        // Graph was asked once for each app registration
+        let application_requests = server
+            .requests()
+            .into_iter()
+            .filter(|request| request.path.starts_with("/v1.0/applications"))
+            .map(|request| request.path)
+            .collect::<Vec<_>>();
+        assert_eq!(2, application_requests.len(), "{application_requests:?}");
+        assert!(application_requests
+            .iter()
+            .any(|path| path.contains("'c1'")));
+        assert!(application_requests
+            .iter()
+            .any(|path| path.contains("'c2'")));
+        // ...and each subscription has the expiry of the secret its credential uses
+        let secret_expiries = health
+            .subscriptions
+            .iter()
+            .map(|subscription| {
+                (
+                    subscription.subscription_id.as_str(),
+                    subscription.secret_expiry.map(|expiry| expiry.to_rfc3339()),
+                )
+            })
+            .collect::<Vec<_>>();
+        let expiry = Some("2030-01-01T00:00:00+00:00".to_string());
+        assert_eq!(
+            vec![
+                ("s1", expiry.clone()),
+                ("s2", expiry.clone()),
+                ("s3", expiry)
+            ],
+            secret_expiries
+        );
+    }
+}
@@ -1,3 +1,6 @@
 pub mod dashboard;
+pub mod dashboard_usage;
 pub mod database_usage;
+pub mod diagnostics;
 pub mod elastic_pool_usage;
+pub mod health;
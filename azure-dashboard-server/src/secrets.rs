@@ -0,0 +1,410 @@
+use crate::azure_credentials::create_credential;
+use crate::azure_token_cache::AccessTokenCache;
+use crate::settings::DashboardSettings;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+use zeroize::Zeroize;
+
+// The Key Vault API version.
+const KEY_VAULT_API_VERSION: &str = "7.4";
+
+// A secret value, e.g. a client secret or an access token.
+// It's redacted when formatted, so it can't end up in the logs, and wiped from memory when dropped.
+// The value itself is only available through expose_secret(), which should be called where it's sent.
+#[derive(Clone, PartialEq, Eq, serde::Deserialize)]
+#[serde(transparent)]
+pub struct SecretString(String);
+
+impl SecretString {
+    // Creates a new secret
+    pub fn new(value: String) -> Self {
+        SecretString(value)
+    }
+    // Gets the secret value
+    pub fn expose_secret(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for SecretString {
+    fn from(value: String) -> Self {
+        SecretString::new(value)
+    }
+}
+
+impl std::fmt::Debug for SecretString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+impl std::fmt::Display for SecretString {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[REDACTED]")
+    }
+}
+
+impl Drop for SecretString {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+// A reference to a secret value in the settings.  Secrets may be given as:
+// - "env:NAME", the value of the environment variable NAME
+// - "file:/run/secrets/x", the contents of the file (without any trailing newline)
+// - "keyvault:https://VAULT.vault.azure.net/secrets/NAME" (optionally followed by "/VERSION"), a Key Vault secret.
+//   Any other "keyvault:" reference is an error.
+// - anything else, which is the secret value itself
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(try_from = "String")]
+pub enum SecretReference {
+    // The secret value itself
+    Literal(SecretString),
+    // The name of an environment variable
+    Environment(String),
+    // The path of a file
+    File(PathBuf),
+    // A Key Vault secret
+    KeyVault {
+        // The vault URL, e.g. "https://VAULT.vault.azure.net"
+        vault_url: String,
+        // The secret path within the vault, e.g. "secrets/NAME" or "secrets/NAME/VERSION"
+        secret_path: String,
+    },
+}
+
+impl TryFrom<String> for SecretReference {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        if let Some(name) = value.strip_prefix("env:") {
+            Ok(SecretReference::Environment(name.to_string()))
+        } else if let Some(path) = value.strip_prefix("file:") {
+            Ok(SecretReference::File(PathBuf::from(path)))
+        } else if let Some(url) = value.strip_prefix("keyvault:") {
+            // Split the URL into the vault and the secret path, which must name a secret.  Anything else is
+            // almost certainly a mistake, and sending it as the secret itself would leak the vault's URL.
+            match url.find("/secrets/") {
+                Some(index) if !url[index + "/secrets/".len()..].trim_matches('/').is_empty() => {
+                    Ok(SecretReference::KeyVault {
+                        vault_url: url[..index].to_string(),
+                        secret_path: url[index + 1..].trim_end_matches('/').to_string(),
+                    })
+                }
+                _ => Err(anyhow::anyhow!(
+                    "The Key Vault secret reference {url:?} is not a secret URL, e.g. https://VAULT.vault.azure.net/secrets/NAME"
+                )),
+            }
+        } else {
+            Ok(SecretReference::Literal(value.into()))
+        }
+    }
+}
+
+impl SecretReference {
+    // Whether the secret is given in the settings, so resolving it again won't change it.
+    pub fn is_literal(&self) -> bool {
+        matches!(self, SecretReference::Literal(_))
+    }
+}
+
+// The response from a Key Vault "get secret" request.
+#[derive(Debug, serde::Deserialize)]
+struct KeyVaultSecretResponse {
+    // The secret value
+    value: SecretString,
+}
+
+// Access to Key Vault for resolving secret references.
+pub struct KeyVaultClient {
+    // The token cache for the Key Vault audience
+    token_cache: AccessTokenCache,
+    // The endpoint to send requests to in place of the vault URL in the reference, if any (e.g. a local stub)
+    endpoint: Option<String>,
+    // The HTTP client
+    http_client: reqwest::Client,
+}
+
+impl KeyVaultClient {
+    // Creates a new Key Vault client
+    pub fn new(token_cache: AccessTokenCache, endpoint: Option<String>) -> Self {
+        KeyVaultClient {
+            token_cache,
+            endpoint,
+            http_client: reqwest::Client::new(),
+        }
+    }
+
+    // Gets a secret from a vault.
+    async fn get_secret(&self, vault_url: &str, secret_path: &str) -> anyhow::Result<SecretString> {
+        log::debug!(
+            "KeyVaultClient.get_secret - vault_url = {vault_url}, secret_path = {secret_path}"
+        );
+        // Get an access token
+        let access_token = self.token_cache.access_token().await?;
+        // Form the URL, sending it to the configured endpoint if there is one
+        let base_url = self.endpoint.as_deref().unwrap_or(vault_url);
+        let url = format!(
+            "{}/{secret_path}?api-version={KEY_VAULT_API_VERSION}",
+            base_url.trim_end_matches('/')
+        );
+        // Make the request
+        let response = self
+            .http_client
+            .get(url)
+            .header(
+                "Authorization",
+                format!("Bearer {}", access_token.expose_secret()),
+            )
+            .send()
+            .await?;
+        // If successful, return the value
+        if reqwest::StatusCode::OK == response.status() {
+            Ok(response.json::<KeyVaultSecretResponse>().await?.value)
+        } else {
+            Err(anyhow::anyhow!(
+                "Failed to get Key Vault secret {vault_url}/{secret_path}.  Error response: {:?} = {:?}",
+                response.status(),
+                response.text().await?
+            ))
+        }
+    }
+}
+
+// Resolves secret references to their values.
+#[derive(Default)]
+pub struct SecretResolver {
+    // The Key Vault client, if Key Vault is configured
+    key_vault: Option<KeyVaultClient>,
+}
+
+impl SecretResolver {
+    // Creates a resolver that can read Key Vault secrets with the given client.
+    pub fn with_key_vault(key_vault: KeyVaultClient) -> Self {
+        SecretResolver {
+            key_vault: Some(key_vault),
+        }
+    }
+
+    // Gets the current value of a secret.
+    pub async fn resolve(&self, reference: &SecretReference) -> anyhow::Result<SecretString> {
+        match reference {
+            SecretReference::Literal(value) => Ok(value.clone()),
+            SecretReference::Environment(name) => std::env::var(name)
+                .map(SecretString::new)
+                .map_err(|_| anyhow::anyhow!("Environment variable {name} is not set")),
+            SecretReference::File(path) => std::fs::read_to_string(path)
+                .map(SecretString::new)
+                .map(|contents| {
+                    SecretString::new(
+                        contents
+                            .expose_secret()
+                            .trim_end_matches(['\r', '\n'])
+                            .to_string(),
+                    )
+                })
+                .map_err(|e| anyhow::anyhow!("Could not read the secret file {path:?}: {e}")),
+            SecretReference::KeyVault {
+                vault_url,
+                secret_path,
+            } => match &self.key_vault {
+                Some(key_vault) => key_vault.get_secret(vault_url, secret_path).await,
+                None => Err(anyhow::anyhow!(
+                    "The secret {vault_url}/{secret_path} is in Key Vault, but the key_vault setting is not configured"
+                )),
+            },
+        }
+    }
+}
+
+// A secret whose value is resolved from its reference when first needed, and again when asked to refresh.
+pub struct ResolvableSecret {
+    // The reference
+    reference: SecretReference,
+    // The resolver
+    resolver: Arc<SecretResolver>,
+    // The current value, if resolved
+    value: RwLock<Option<SecretString>>,
+}
+
+impl ResolvableSecret {
+    // Creates a new secret from a reference.
+    pub fn new(reference: SecretReference, resolver: Arc<SecretResolver>) -> Self {
+        ResolvableSecret {
+            reference,
+            resolver,
+            value: RwLock::new(None),
+        }
+    }
+
+    // Creates a secret from a known value.
+    pub fn literal(value: SecretString) -> Self {
+        Self::new(
+            SecretReference::Literal(value),
+            Arc::new(SecretResolver::default()),
+        )
+    }
+
+    // Whether refreshing the secret could change its value.
+    pub fn is_refreshable(&self) -> bool {
+        !self.reference.is_literal()
+    }
+
+    // Gets the secret value, resolving it if it hasn't been already.
+    pub async fn value(&self) -> anyhow::Result<SecretString> {
+        if let Some(value) = self.value.read().unwrap().as_ref() {
+            return Ok(value.clone());
+        }
+        self.refresh().await
+    }
+
+    // Resolves the secret value again, e.g. because it's been rotated.
+    pub async fn refresh(&self) -> anyhow::Result<SecretString> {
+        // Only keep the message, so a secret that couldn't be had from Key Vault isn't mistaken for a failed token
+        // request
+        let value = self
+            .resolver
+            .resolve(&self.reference)
+            .await
+            .map_err(|e| anyhow::anyhow!("Could not resolve a secret: {e}"))?;
+        *self.value.write().unwrap() = Some(value.clone());
+        Ok(value)
+    }
+}
+
+// Creates the secret resolver for the settings, which can read Key Vault secrets if Key Vault is configured.
+pub fn create_secret_resolver(settings: &DashboardSettings) -> anyhow::Result<SecretResolver> {
+    // If Key Vault isn't configured, only other kinds of secret can be resolved
+    let key_vault_settings = match &settings.key_vault {
+        Some(key_vault_settings) => key_vault_settings,
+        None => return Ok(SecretResolver::default()),
+    };
+    // Get the cloud the vaults are in
+    let cloud = key_vault_settings.cloud.profile();
+    // Create the credential used to read secrets.  Its own secrets can't come from Key Vault.
+    let credential_settings = settings.named_credential(&key_vault_settings.credential)?;
+    let credential = create_credential(
+        credential_settings,
+        &cloud,
+        &Arc::new(SecretResolver::default()),
+    )?;
+    // Create a token cache for the Key Vault audience
+    let token_cache = AccessTokenCache::new(
+        Arc::from(credential),
+        vec![format!(
+            "{}/.default",
+            cloud.key_vault_audience.trim_end_matches('/')
+        )],
+        chrono::Duration::seconds(settings.token_refresh_skew_seconds),
+    );
+    Ok(SecretResolver::with_key_vault(KeyVaultClient::new(
+        token_cache,
+        key_vault_settings.endpoint.clone(),
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parses a secret reference as the settings would.
+    fn parse(value: &str) -> anyhow::Result<SecretReference> {
+        SecretReference::try_from(value.to_string())
+    }
+
+    // Gets a path for a secret file that's unique to the test.
+    fn secret_file(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("secret-{}-{name}", std::process::id()))
+    }
+
+    #[test]
+    fn secret_references_are_parsed() {
+        let key_vault = |vault_url: &str, secret_path: &str| SecretReference::KeyVault {
+            vault_url: vault_url.to_string(),
+            secret_path: secret_path.to_string(),
+        };
+        let cases = [
+            (
+                "plain-value",
+                SecretReference::Literal(SecretString::new("plain-value".to_string())),
+            ),
+            ("env:NAME", SecretReference::Environment("NAME".to_string())),
+            (
+                "file:/run/secrets/x",
+                SecretReference::File(PathBuf::from("/run/secrets/x")),
+            ),
+            (
+                "keyvault:https://v.vault.azure.net/secrets/name",
+                key_vault("https://v.vault.azure.net", "secrets/name"),
+            ),
+            // A trailing slash is trimmed
+            (
+                "keyvault:https://v.vault.azure.net/secrets/name/",
+                key_vault("https://v.vault.azure.net", "secrets/name"),
+            ),
+            // A version may follow the name
+            (
+                "keyvault:https://v.vault.azure.net/secrets/name/0123abcd",
+                key_vault("https://v.vault.azure.net", "secrets/name/0123abcd"),
+            ),
+        ];
+        for (value, expected) in cases {
+            assert_eq!(expected, parse(value).unwrap(), "{value}");
+        }
+    }
+
+    #[test]
+    fn key_vault_reference_that_is_not_a_secret_url_is_refused() {
+        for value in [
+            "keyvault:https://v.vault.azure.net",
+            "keyvault:https://v.vault.azure.net/keys/name",
+            "keyvault:https://v.vault.azure.net/secrets/",
+        ] {
+            assert!(parse(value).is_err(), "{value}");
+        }
+        // ...including when it's read from the settings
+        assert!(serde_json::from_value::<SecretReference>(serde_json::json!(
+            "keyvault:https://v.vault.azure.net"
+        ))
+        .is_err());
+    }
+
+    #[actix_web::test]
+    async fn environment_references_are_resolved() {
+        let resolver = SecretResolver::default();
+        let value = resolver.resolve(&parse("env:PATH").unwrap()).await.unwrap();
+        assert_eq!(std::env::var("PATH").unwrap(), value.expose_secret());
+        let error = resolver
+            .resolve(&parse("env:AZURE_DASHBOARD_TEST_UNSET").unwrap())
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("AZURE_DASHBOARD_TEST_UNSET"),
+            "{error}"
+        );
+    }
+
+    #[actix_web::test]
+    async fn file_references_are_resolved_without_the_trailing_newline() {
+        let resolver = SecretResolver::default();
+        let path = secret_file("crlf");
+        std::fs::write(&path, "secret\r\n").unwrap();
+        let reference = parse(&format!("file:{}", path.display())).unwrap();
+        let value = resolver.resolve(&reference).await;
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!("secret", value.unwrap().expose_secret());
+        // A missing file is an error
+        assert!(resolver.resolve(&reference).await.is_err());
+    }
+
+    #[actix_web::test]
+    async fn key_vault_references_need_key_vault_to_be_configured() {
+        let resolver = SecretResolver::default();
+        let error = resolver
+            .resolve(&parse("keyvault:https://v.vault.azure.net/secrets/name").unwrap())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("key_vault setting"), "{error}");
+    }
+}
@@ -1,3 +1,9 @@
+use crate::azure_apis::arm_client::{DatabaseId, ElasticPoolId};
+use crate::azure_cloud::CloudProfile;
+use crate::encrypted_config;
+use crate::secrets::{SecretReference, SecretString};
+use std::collections::HashMap;
+
 // Settings for a database to be displayed in the dashboard.
 #[derive(Clone, Debug, serde::Deserialize)]
 pub struct DatabaseSettings {
@@ -27,28 +33,426 @@ pub struct ResourceGroupSettings {
     pub elastic_pools: Vec<ElasticPoolSettings>,
 }
 
-// The settings relating to a single subscription.
-#[derive(Clone, Debug, serde::Deserialize)]
-pub struct SubscriptionSettings {
+// A source of credentials that can be used to get an access token.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CredentialKind {
+    // The client ID and secret in the subscription settings
+    ClientSecret,
+    // The AZURE_TENANT_ID, AZURE_CLIENT_ID, and AZURE_CLIENT_SECRET environment variables
+    Environment,
+    // The token cache written by the Azure CLI after "az login"
+    AzureCli,
+    // A client assertion signed by the certificate at certificate_path
+    Certificate,
+    // The managed identity of the Azure VM or App Service we're running on
+    ManagedIdentity,
+    // A federated token, e.g. a projected Kubernetes service account token, used as a client assertion
+    WorkloadIdentity,
+}
+
+impl CredentialKind {
+    // The kind's name in the settings, e.g. "client_secret"
+    pub fn name(&self) -> &'static str {
+        match self {
+            CredentialKind::ClientSecret => "client_secret",
+            CredentialKind::Environment => "environment",
+            CredentialKind::AzureCli => "azure_cli",
+            CredentialKind::Certificate => "certificate",
+            CredentialKind::ManagedIdentity => "managed_identity",
+            CredentialKind::WorkloadIdentity => "workload_identity",
+        }
+    }
+}
+
+// The version of the Microsoft identity platform token endpoint to use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TokenEndpointVersion {
+    // The v1 endpoint (.../oauth2/token), which takes a "resource"
+    #[default]
+    V1,
+    // The v2 endpoint (.../oauth2/v2.0/token), which takes a "scope"
+    V2,
+}
+
+// The credentials used if a subscription doesn't specify any.
+fn default_credentials() -> Vec<CredentialKind> {
+    vec![CredentialKind::ClientSecret]
+}
+
+// The Azure cloud a subscription is in.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloudSettings {
+    // The Azure public cloud
+    #[default]
+    Public,
+    // Azure Government (US)
+    UsGovernment,
+    // Azure China
+    China,
+    // A cloud whose endpoints and audiences are all given, e.g. { "custom": { "name": "stack", ... } }
+    Custom(Box<CloudProfile>),
+}
+
+impl CloudSettings {
+    // Gets the profile of the cloud.
+    pub fn profile(&self) -> CloudProfile {
+        match self {
+            CloudSettings::Public => CloudProfile::public(),
+            CloudSettings::UsGovernment => CloudProfile::us_government(),
+            CloudSettings::China => CloudProfile::china(),
+            CloudSettings::Custom(profile) => profile.as_ref().clone(),
+        }
+    }
+}
+
+// The settings for getting access tokens, either defined once in DashboardSettings.credentials and
+// referred to by name, or given directly in a subscription.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+pub struct CredentialSettings {
+    // The tenant ID (a GUID)
+    #[serde(default)]
+    pub tenant_id: Option<String>,
     // The Azure AD App Registration client ID (a GUID)
-    pub client_id: String,
+    #[serde(default)]
+    pub client_id: Option<String>,
     // The Azure AD App Registration secret value (a long string of random chars)
     // Note: Not the secret ID (a GUID)
     // Note2: This secret expires 6, 12, or however many months were specified at time of
     // creation and will have to be updated.
-    pub client_secret: String,
+    // Only needed for the "client_secret" credential.
+    // May be a reference to the secret, e.g. "env:NAME", "file:/run/secrets/x", or "keyvault:https://VAULT/secrets/NAME".
+    #[serde(default)]
+    pub client_secret: Option<SecretReference>,
+    // The credentials to try, in order, when getting an access token, e.g. ["environment", "azure_cli"]
+    // Defaults to ["client_secret"].
+    #[serde(default = "default_credentials")]
+    pub credentials: Vec<CredentialKind>,
+    // The path to a PEM file (with both the certificate and private key) or PFX file used to sign
+    // client assertions, for the "certificate" credential.
+    #[serde(default)]
+    pub certificate_path: Option<String>,
+    // The PFX file password, if any
+    #[serde(default)]
+    pub certificate_password: Option<SecretString>,
+    // The managed identity token endpoint, for the "managed_identity" credential.
+    // Defaults to the instance metadata endpoint (or App Service's endpoint, if running there).
+    #[serde(default)]
+    pub managed_identity_endpoint: Option<String>,
+    // The client ID of a user-assigned managed identity.  If none of the client, object or resource IDs are set,
+    // the system-assigned identity is used.
+    #[serde(default)]
+    pub managed_identity_client_id: Option<String>,
+    // The object (principal) ID of a user-assigned managed identity, as an alternative to its client ID
+    #[serde(default)]
+    pub managed_identity_object_id: Option<String>,
+    // The ARM resource ID of a user-assigned managed identity, as an alternative to its client ID
+    #[serde(default)]
+    pub managed_identity_resource_id: Option<String>,
+    // The path of the federated token file, for the "workload_identity" credential.
+    // Defaults to the AZURE_FEDERATED_TOKEN_FILE environment variable.
+    #[serde(default)]
+    pub federated_token_file: Option<String>,
+    // The OAuth2 resource name, e.g. "https://management.azure.com"
+    // Defaults to the cloud's Resource Manager audience.
+    #[serde(default)]
+    pub resource: Option<String>,
+    // The OAuth2 scopes to request for the resource, e.g. ["https://management.azure.com/.default"]
+    // Defaults to the ".default" scope of the resource.
+    #[serde(default)]
+    pub scopes: Vec<String>,
+    // The token endpoint version token_url refers to, "v1" or "v2".  Defaults to "v1".
+    #[serde(default)]
+    pub token_version: TokenEndpointVersion,
+    // The Azure OAuth2 base auth URL, e.g. https://login.microsoftonline.com/TENANT_ID/oauth2/token
+    // or, for v2, https://login.microsoftonline.com/TENANT_ID/oauth2/v2.0/token
+    // Defaults to the token URL for the tenant in the subscription's cloud.
+    #[serde(default)]
+    pub token_url: Option<String>,
+}
+
+impl CredentialSettings {
+    // The resource to request tokens for: the configured resource, or the cloud's Resource Manager audience.
+    pub fn token_resource(&self, cloud: &CloudProfile) -> String {
+        self.resource
+            .clone()
+            .unwrap_or_else(|| cloud.resource_manager_audience.clone())
+    }
+    // The scopes to request tokens for: the configured scopes, or the resource's ".default" scope.
+    pub fn token_scopes(&self, cloud: &CloudProfile) -> Vec<String> {
+        if self.scopes.is_empty() {
+            vec![format!(
+                "{}/.default",
+                self.token_resource(cloud).trim_end_matches('/')
+            )]
+        } else {
+            self.scopes.clone()
+        }
+    }
+    // The token URL: the configured URL, or the one for the tenant in the given cloud.
+    pub fn token_url(&self, cloud: &CloudProfile) -> anyhow::Result<String> {
+        match (&self.token_url, &self.tenant_id) {
+            (Some(token_url), _) => Ok(token_url.clone()),
+            (None, Some(tenant_id)) => Ok(cloud.token_url(
+                tenant_id,
+                TokenEndpointVersion::V2 == self.token_version,
+            )),
+            (None, None) => Err(anyhow::anyhow!(
+                "Either the token_url or tenant_id setting is required by the configured credentials"
+            )),
+        }
+    }
+}
+
+// Gets the value of a setting that the configured credentials need.
+pub fn required_setting(name: &str, value: &Option<String>) -> anyhow::Result<String> {
+    value.clone().ok_or_else(|| {
+        anyhow::anyhow!("The {name} setting is required by the configured credentials")
+    })
+}
+
+// The settings relating to a single subscription.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct SubscriptionSettings {
     // The display name for this subscription
     pub display_name: String,
-    // The OAuth2 resource name, e.g. "https://management.azure.com"
-    pub resource: String,
     // The subscription ID (a GUID)
     pub subscription_id: String,
-    // The tenant ID (a GUID)
-    pub tenant_id: String,
-    // The Azure OAuth2 base auth URL, e.g. https://login.microsoftonline.com/TENANT_ID/oauth2/token
-    pub token_url: String,
     // The resource groups
     pub resource_groups: Vec<ResourceGroupSettings>,
+    // The cloud the subscription is in: "public" (the default), "us_government", "china", or { "custom": { ... } }
+    #[serde(default)]
+    pub cloud: CloudSettings,
+    // The name of the credential in DashboardSettings.credentials to use for this subscription.
+    // If not set, the credential settings given directly in the subscription are used.
+    #[serde(default)]
+    pub credential: Option<String>,
+    // The credential settings given directly in the subscription
+    #[serde(flatten)]
+    pub credential_settings: CredentialSettings,
+}
+
+impl SubscriptionSettings {
+    // The key that identifies the credential the subscription uses in the given cloud.  A named credential's key
+    // is "CLOUD/credentials/NAME", and the subscriptions in the cloud that use it share it and its tokens.
+    // Credential settings given directly in the subscription are keyed on the principal they authenticate as,
+    // "CLOUD/clients/TENANT_ID/CLIENT_ID/KINDS", so subscriptions that give the same tenant, client and credential
+    // kinds share them too.  The client is the managed identity's ID if there's no client ID, and "-" if neither
+    // is given (e.g. a system-assigned managed identity).
+    pub fn credential_key(&self, cloud: &CloudProfile) -> String {
+        match &self.credential {
+            Some(name) => format!("{}/credentials/{name}", cloud.name),
+            None => {
+                let settings = &self.credential_settings;
+                let client_id = settings
+                    .client_id
+                    .as_ref()
+                    .or(settings.managed_identity_client_id.as_ref())
+                    .or(settings.managed_identity_object_id.as_ref())
+                    .or(settings.managed_identity_resource_id.as_ref());
+                format!(
+                    "{}/clients/{}/{}/{}",
+                    cloud.name,
+                    settings.tenant_id.as_deref().unwrap_or("-"),
+                    client_id.map(String::as_str).unwrap_or("-"),
+                    settings
+                        .credentials
+                        .iter()
+                        .map(CredentialKind::name)
+                        .collect::<Vec<_>>()
+                        .join("+")
+                )
+            }
+        }
+        .to_lowercase()
+    }
+}
+
+// The settings for reading secrets from Key Vault.
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct KeyVaultSettings {
+    // The name of the credential in DashboardSettings.credentials used to read secrets.
+    // Its own secrets can't be in Key Vault.
+    pub credential: String,
+    // The cloud the vaults are in
+    #[serde(default)]
+    pub cloud: CloudSettings,
+    // The endpoint to send Key Vault requests to in place of the vault URL, e.g. a local stub
+    #[serde(default)]
+    pub endpoint: Option<String>,
+}
+
+// Warn about secrets this many days before they expire, if not configured otherwise.
+fn default_secret_expiry_warning_days() -> i64 {
+    30
+}
+
+// The settings for the credential health check.
+#[derive(Debug, serde::Deserialize)]
+pub struct CredentialHealthSettings {
+    // The Microsoft Graph endpoint to look up app registrations' secrets at, in place of the cloud's, e.g. a local stub
+    #[serde(default)]
+    pub graph_endpoint: Option<String>,
+    // How many days before a client secret expires to warn about it
+    #[serde(default = "default_secret_expiry_warning_days")]
+    pub secret_expiry_warning_days: i64,
+}
+
+impl Default for CredentialHealthSettings {
+    fn default() -> Self {
+        CredentialHealthSettings {
+            graph_endpoint: None,
+            secret_expiry_warning_days: default_secret_expiry_warning_days(),
+        }
+    }
+}
+
+// The refresh skew used if none is configured: 5 minutes.
+fn default_token_refresh_skew_seconds() -> i64 {
+    300
+}
+
+// Background token refresh is on unless turned off.
+fn default_background_token_refresh() -> bool {
+    true
+}
+
+// Retry a failed request up to 3 times, if not configured otherwise.
+fn default_max_retries() -> u32 {
+    3
+}
+
+// Wait half a second before the first retry, if not configured otherwise.
+fn default_initial_retry_delay_ms() -> u64 {
+    500
+}
+
+// Wait at most 10 seconds before a retry, if not configured otherwise.
+fn default_max_retry_delay_ms() -> u64 {
+    10_000
+}
+
+// The settings for retrying Azure API requests that fail with a 429 or 5xx status, or a network error.
+#[derive(Debug, serde::Deserialize)]
+pub struct RetrySettings {
+    // How many times to retry a request before giving up
+    #[serde(default = "default_max_retries")]
+    pub max_retries: u32,
+    // How long to wait before the first retry, in milliseconds.  This doubles with each retry, and the
+    // actual delay is a random fraction of it.
+    #[serde(default = "default_initial_retry_delay_ms")]
+    pub initial_delay_ms: u64,
+    // The longest to wait before a retry, in milliseconds.  A request isn't retried if its response asks us
+    // to wait longer than this with a Retry-After header.
+    #[serde(default = "default_max_retry_delay_ms")]
+    pub max_delay_ms: u64,
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        RetrySettings {
+            max_retries: default_max_retries(),
+            initial_delay_ms: default_initial_retry_delay_ms(),
+            max_delay_ms: default_max_retry_delay_ms(),
+        }
+    }
+}
+
+// Slow down when a subscription has fewer than 100 reads left, if not configured otherwise.
+fn default_slow_down_below_remaining_reads() -> u32 {
+    100
+}
+
+// Delay requests by up to 2 seconds when slowing down, if not configured otherwise.
+fn default_max_slow_down_delay_ms() -> u64 {
+    2_000
+}
+
+// The Azure Resource Manager API settings.
+#[derive(Debug, serde::Deserialize)]
+pub struct ArmSettings {
+    // The endpoint to send all requests to, in place of each subscription's cloud's, e.g. a local stub
+    #[serde(default)]
+    pub base_url: Option<String>,
+    // The API versions to use in place of the defaults, by resource type,
+    // e.g. {"Microsoft.Sql/servers/elasticPools": "2021-11-01"}
+    #[serde(default)]
+    pub api_versions: HashMap<String, String>,
+    // Requests to a subscription are slowed down when ARM says it has fewer reads than this left
+    // (the x-ms-ratelimit-remaining-subscription-reads header), so it isn't throttled
+    #[serde(default = "default_slow_down_below_remaining_reads")]
+    pub slow_down_below_remaining_reads: u32,
+    // The delay added to each request to a subscription with no reads left, in milliseconds.
+    // Subscriptions with some reads left get a proportionally shorter delay.
+    #[serde(default = "default_max_slow_down_delay_ms")]
+    pub max_slow_down_delay_ms: u64,
+    // Whether to send requests that are made together to the same subscription, e.g. for the usages of the
+    // databases in an elastic pool, as one request to ARM's /batch endpoint
+    #[serde(default)]
+    pub batch_requests: bool,
+}
+
+impl Default for ArmSettings {
+    fn default() -> Self {
+        ArmSettings {
+            base_url: None,
+            api_versions: HashMap::new(),
+            slow_down_below_remaining_reads: default_slow_down_below_remaining_reads(),
+            max_slow_down_delay_ms: default_max_slow_down_delay_ms(),
+            batch_requests: false,
+        }
+    }
+}
+
+// Run at most 32 Azure requests at once, if not configured otherwise.
+fn default_max_concurrent_requests() -> usize {
+    32
+}
+
+// Run at most 8 Azure requests to a subscription at once, if not configured otherwise.
+fn default_max_concurrent_requests_per_subscription() -> usize {
+    8
+}
+
+// The settings for limiting how many Azure Resource Manager requests run at once.  Requests over the limits
+// wait their turn.
+#[derive(Debug, serde::Deserialize)]
+pub struct ConcurrencySettings {
+    // The most requests that can run at once
+    #[serde(default = "default_max_concurrent_requests")]
+    pub max_concurrent_requests: usize,
+    // The most requests to one subscription that can run at once
+    #[serde(default = "default_max_concurrent_requests_per_subscription")]
+    pub max_concurrent_requests_per_subscription: usize,
+}
+
+impl Default for ConcurrencySettings {
+    fn default() -> Self {
+        ConcurrencySettings {
+            max_concurrent_requests: default_max_concurrent_requests(),
+            max_concurrent_requests_per_subscription:
+                default_max_concurrent_requests_per_subscription(),
+        }
+    }
+}
+
+// Where the usage of an elastic pool comes from.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ElasticPoolUsageSource {
+    // The sum of the usages of the databases in the pool, which takes a call per database
+    #[default]
+    Databases,
+    // The pool's storage_used and allocated_data_storage Azure Monitor metrics, which takes one call.
+    // Falls back to the databases if the metrics aren't available.
+    Metrics,
+}
+
+// The permission check is run on startup unless turned off.
+fn default_check_permissions_on_startup() -> bool {
+    true
 }
 
 // The application configuration settings.
@@ -58,11 +462,126 @@ pub struct DashboardSettings {
     pub host: String,
     // The port we'll run on
     pub port: u16,
+    // The named credentials that subscriptions can refer to.
+    #[serde(default)]
+    pub credentials: HashMap<String, CredentialSettings>,
+    // The Key Vault settings, needed if any secrets are Key Vault references
+    #[serde(default)]
+    pub key_vault: Option<KeyVaultSettings>,
     // The subscriptions.
     pub subscriptions: Vec<SubscriptionSettings>,
+    // How many seconds before an access token expires it should be refreshed
+    #[serde(default = "default_token_refresh_skew_seconds")]
+    pub token_refresh_skew_seconds: i64,
+    // Whether access tokens should be refreshed in the background before they expire, so requests don't wait for them
+    #[serde(default = "default_background_token_refresh")]
+    pub background_token_refresh: bool,
+    // The credential health check settings
+    #[serde(default)]
+    pub credential_health: CredentialHealthSettings,
+    // The Azure Resource Manager API settings
+    #[serde(default)]
+    pub arm: ArmSettings,
+    // The settings for retrying failed Azure API requests
+    #[serde(default)]
+    pub retry: RetrySettings,
+    // The settings for limiting how many Azure requests run at once
+    #[serde(default)]
+    pub concurrency: ConcurrencySettings,
+    // Where elastic pools' usage comes from: "databases" (the default) or "metrics"
+    #[serde(default)]
+    pub elastic_pool_usage_source: ElasticPoolUsageSource,
+    // Whether to check the permissions on the configured resource groups on startup
+    #[serde(default = "default_check_permissions_on_startup")]
+    pub check_permissions_on_startup: bool,
+    // Whether the usage of databases that aren't configured, but are in a configured elastic pool, can be
+    // requested.  Off by default, so only configured resources can be requested.
+    #[serde(default)]
+    pub allow_discovered_databases: bool,
 }
 
 impl DashboardSettings {
+    // Gets the credential settings a subscription uses: the named credential it refers to, or its own.
+    pub fn credential_settings<'a>(
+        &'a self,
+        subscription: &'a SubscriptionSettings,
+    ) -> anyhow::Result<&'a CredentialSettings> {
+        match &subscription.credential {
+            Some(name) => self
+                .named_credential(name)
+                .map_err(|e| anyhow::anyhow!("Subscription {}: {e}", subscription.subscription_id)),
+            None => Ok(&subscription.credential_settings),
+        }
+    }
+    // Gets the named credential settings.
+    pub fn named_credential(&self, name: &str) -> anyhow::Result<&CredentialSettings> {
+        // The configuration library lower-cases keys, so look the name up without regard to case
+        self.credentials
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, credential_settings)| credential_settings)
+            .ok_or_else(|| anyhow::anyhow!("Credential {name:?} is not defined"))
+    }
+    // Gets the configured resource group with the given name in the given subscription, if there is one.
+    // Azure names are case-insensitive, so these are too.
+    fn resource_group(
+        &self,
+        subscription_id: &str,
+        resource_group_name: &str,
+    ) -> Option<&ResourceGroupSettings> {
+        self.subscriptions
+            .iter()
+            .filter(|s| s.subscription_id.eq_ignore_ascii_case(subscription_id))
+            .flat_map(|s| &s.resource_groups)
+            .find(|r| {
+                r.resource_group_name
+                    .eq_ignore_ascii_case(resource_group_name)
+            })
+    }
+    // Whether the database is configured.
+    pub fn is_configured_database(&self, database: &DatabaseId) -> bool {
+        self.resource_group(&database.subscription_id, &database.resource_group_name)
+            .is_some_and(|resource_group| {
+                resource_group.databases.iter().any(|d| {
+                    d.server_name.eq_ignore_ascii_case(&database.server_name)
+                        && d.database_name
+                            .eq_ignore_ascii_case(&database.database_name)
+                })
+            })
+    }
+    // Whether the elastic pool is configured.
+    pub fn is_configured_elastic_pool(&self, elastic_pool: &ElasticPoolId) -> bool {
+        self.resource_group(
+            &elastic_pool.subscription_id,
+            &elastic_pool.resource_group_name,
+        )
+        .is_some_and(|resource_group| {
+            resource_group.elastic_pools.iter().any(|p| {
+                p.server_name
+                    .eq_ignore_ascii_case(&elastic_pool.server_name)
+                    && p.elastic_pool_name
+                        .eq_ignore_ascii_case(&elastic_pool.elastic_pool_name)
+            })
+        })
+    }
+    // Gets the configured elastic pools on the same server as the database, which it may be in.
+    pub fn elastic_pools_on_server(&self, database: &DatabaseId) -> Vec<ElasticPoolId> {
+        self.resource_group(&database.subscription_id, &database.resource_group_name)
+            .map(|resource_group| {
+                resource_group
+                    .elastic_pools
+                    .iter()
+                    .filter(|p| p.server_name.eq_ignore_ascii_case(&database.server_name))
+                    .map(|p| ElasticPoolId {
+                        subscription_id: database.subscription_id.clone(),
+                        resource_group_name: database.resource_group_name.clone(),
+                        server_name: database.server_name.clone(),
+                        elastic_pool_name: p.elastic_pool_name.clone(),
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
     // Loads the settings from file.
     pub fn new() -> Result<Self, config::ConfigError> {
         log::debug!("Settings.new");
@@ -71,16 +590,138 @@ impl DashboardSettings {
         let run_mode = std::env::var("RUN_MODE").unwrap_or_else(|_| "local".into());
         log::debug!(" - run_mode = {:?}", run_mode);
         // Load the settings from configuration
-        let settings = config::Config::builder()
-            // Add the default configuration file
-            .add_source(config::File::with_name("config.json"))
-            // Add the file for the selected run mode, if present
-            .add_source(
-                config::File::with_name(&format!("config.{}.json", run_mode)).required(false),
-            )
-            // Build the file
-            .build()?;
+        let mut builder = config::Config::builder();
+        // Add the default configuration file, and the file for the selected run mode if present.
+        // Either may be encrypted, so read (and decrypt) them ourselves.
+        for (file_name, required) in [
+            ("config.json".to_string(), true),
+            (format!("config.{}.json", run_mode), false),
+        ] {
+            let path = std::path::Path::new(&file_name);
+            if !required && !path.exists() {
+                continue;
+            }
+            let contents = encrypted_config::read_config_file(path).map_err(|e| {
+                config::ConfigError::Message(format!("Could not load {file_name}: {e}"))
+            })?;
+            builder = builder.add_source(config::File::from_str(
+                contents.expose_secret(),
+                config::FileFormat::Json,
+            ));
+        }
+        // Build the file
+        let settings = builder.build()?;
         // Try to deserialize the file
         settings.try_deserialize()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Creates settings with a database and two elastic pools on one server, and a pool on another.
+    fn settings() -> DashboardSettings {
+        serde_json::from_value(serde_json::json!({
+            "host": "127.0.0.1",
+            "port": 8080,
+            "subscriptions": [{
+                "display_name": "Subscription 1",
+                "subscription_id": "s1",
+                "client_id": "c",
+                "client_secret": "s",
+                "resource_groups": [{
+                    "resource_group_name": "rg1",
+                    "databases": [{ "server_name": "server1", "database_name": "db1" }],
+                    "elastic_pools": [
+                        { "server_name": "server1", "elastic_pool_name": "pool1" },
+                        { "server_name": "server1", "elastic_pool_name": "pool2" },
+                        { "server_name": "server2", "elastic_pool_name": "pool3" },
+                    ],
+                }],
+            }],
+        }))
+        .unwrap()
+    }
+
+    // Identifies a database.
+    fn database(
+        subscription_id: &str,
+        resource_group_name: &str,
+        server_name: &str,
+        database_name: &str,
+    ) -> DatabaseId {
+        DatabaseId {
+            subscription_id: subscription_id.to_string(),
+            resource_group_name: resource_group_name.to_string(),
+            server_name: server_name.to_string(),
+            database_name: database_name.to_string(),
+        }
+    }
+
+    // Identifies an elastic pool.
+    fn elastic_pool(
+        subscription_id: &str,
+        resource_group_name: &str,
+        server_name: &str,
+        elastic_pool_name: &str,
+    ) -> ElasticPoolId {
+        ElasticPoolId {
+            subscription_id: subscription_id.to_string(),
+            resource_group_name: resource_group_name.to_string(),
+            server_name: server_name.to_string(),
+            elastic_pool_name: elastic_pool_name.to_string(),
+        }
+    }
+
+    #[test]
+    fn configured_database_is_matched_without_regard_to_case() {
+        let settings = settings();
+        assert!(settings.is_configured_database(&database("s1", "rg1", "server1", "db1")));
+        assert!(settings.is_configured_database(&database("S1", "RG1", "SERVER1", "DB1")));
+        // The same server and database in another subscription or resource group isn't configured
+        assert!(!settings.is_configured_database(&database("s2", "rg1", "server1", "db1")));
+        assert!(!settings.is_configured_database(&database("s1", "rg2", "server1", "db1")));
+        // ...nor is another database, or the same one on another server
+        assert!(!settings.is_configured_database(&database("s1", "rg1", "server1", "db2")));
+        assert!(!settings.is_configured_database(&database("s1", "rg1", "server2", "db1")));
+    }
+
+    #[test]
+    fn configured_elastic_pool_is_matched_without_regard_to_case() {
+        let settings = settings();
+        assert!(settings.is_configured_elastic_pool(&elastic_pool("s1", "rg1", "server1", "pool1")));
+        assert!(settings.is_configured_elastic_pool(&elastic_pool("S1", "RG1", "SERVER1", "POOL1")));
+        assert!(
+            !settings.is_configured_elastic_pool(&elastic_pool("s2", "rg1", "server1", "pool1"))
+        );
+        assert!(
+            !settings.is_configured_elastic_pool(&elastic_pool("s1", "rg2", "server1", "pool1"))
+        );
+        assert!(
+            !settings.is_configured_elastic_pool(&elastic_pool("s1", "rg1", "server2", "pool1"))
+        );
+    }
+
+    #[test]
+    fn elastic_pools_on_server_are_those_configured_on_the_database_server() {
+        let settings = settings();
+        let pool_names = |database: DatabaseId| {
+            settings
+                .elastic_pools_on_server(&database)
+                .into_iter()
+                .map(|p| p.elastic_pool_name)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            vec!["pool1", "pool2"],
+            pool_names(database("S1", "RG1", "SERVER1", "db2"))
+        );
+        assert_eq!(
+            vec!["pool3"],
+            pool_names(database("s1", "rg1", "server2", "db2"))
+        );
+        assert!(pool_names(database("s2", "rg1", "server1", "db2")).is_empty());
+        assert!(pool_names(database("s1", "rg2", "server1", "db2")).is_empty());
+    }
+}
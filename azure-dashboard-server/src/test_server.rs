@@ -0,0 +1,340 @@
+// A local HTTP server for tests, standing in for Azure's token endpoints and APIs.
+use crate::azure_apis::arm_client::ArmClient;
+use crate::azure_apis::concurrency::ConcurrencyLimiter;
+use crate::azure_apis::retry::RetryPolicy;
+use crate::azure_token_cache::AccessTokenCacheMap;
+use crate::secrets::SecretResolver;
+use crate::settings::DashboardSettings;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+
+// A request the server received.
+#[derive(Clone, Debug)]
+pub struct TestRequest {
+    // The method, e.g. "GET"
+    pub method: String,
+    // The path and query, e.g. "/subscriptions/s1?api-version=2021-05-01-preview"
+    pub path: String,
+    // The headers, by lower-case name
+    pub headers: HashMap<String, String>,
+    // The body
+    pub body: Vec<u8>,
+}
+
+impl TestRequest {
+    // Gets a header's value.  Names are case-insensitive.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    // Gets the value of a query parameter, if it's there.
+    pub fn query(&self, name: &str) -> Option<String> {
+        reqwest::Url::parse(&format!("http://localhost{}", self.path))
+            .ok()?
+            .query_pairs()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.into_owned())
+    }
+
+    // Reads the body as a form, e.g. a token request.
+    pub fn form(&self) -> HashMap<String, String> {
+        reqwest::Url::parse(&format!(
+            "http://localhost/?{}",
+            String::from_utf8_lossy(&self.body)
+        ))
+        .unwrap()
+        .query_pairs()
+        .into_owned()
+        .collect()
+    }
+
+    // Reads the body as JSON.
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_slice(&self.body).unwrap()
+    }
+}
+
+// A response for the server to send.
+#[derive(Clone, Debug)]
+pub struct TestResponse {
+    // The status, e.g. 200
+    status: u16,
+    // The headers, other than Content-Length
+    headers: Vec<(String, String)>,
+    // The body
+    body: Vec<u8>,
+    // How long to wait between sending the headers and the body
+    body_delay: std::time::Duration,
+}
+
+impl TestResponse {
+    // Creates a response with the given status and no body.
+    pub fn new(status: u16) -> Self {
+        TestResponse {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            body_delay: std::time::Duration::ZERO,
+        }
+    }
+
+    // Creates a response with the given status and JSON body.
+    pub fn json(status: u16, body: serde_json::Value) -> Self {
+        TestResponse {
+            status,
+            headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+            body: serde_json::to_vec(&body).unwrap(),
+            body_delay: std::time::Duration::ZERO,
+        }
+    }
+
+    // Creates a successful token response, as the v1 endpoint gives it.
+    pub fn token(access_token: &str) -> Self {
+        Self::json(
+            200,
+            serde_json::json!({
+                "token_type": "Bearer",
+                "expires_in": "3600",
+                "expires_on": (chrono::Utc::now().timestamp() + 3600).to_string(),
+                "access_token": access_token,
+            }),
+        )
+    }
+
+    // Adds a header.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    // Sends the body the given time after the headers, like a slow download.
+    pub fn body_delay(mut self, body_delay: std::time::Duration) -> Self {
+        self.body_delay = body_delay;
+        self
+    }
+}
+
+// A server answering requests on a local port with a handler, and remembering them.
+pub struct TestServer {
+    // The server's URL, e.g. "http://127.0.0.1:12345"
+    url: String,
+    // The requests received so far
+    requests: Arc<Mutex<Vec<TestRequest>>>,
+}
+
+impl TestServer {
+    // Starts a server that answers each request with the handler.  It runs until the test ends.
+    pub fn start<F>(handler: F) -> Self
+    where
+        F: Fn(&TestRequest) -> TestResponse + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler = Arc::new(handler);
+        let server_requests = requests.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let Ok(stream) = stream else {
+                    continue;
+                };
+                // Answer each connection on its own thread, so slow handlers don't hold up others
+                let handler = handler.clone();
+                let requests = server_requests.clone();
+                std::thread::spawn(move || Self::answer(stream, handler.as_ref(), &requests));
+            }
+        });
+        TestServer { url, requests }
+    }
+
+    // Starts a server that stands in for both the token endpoint, at "/token", and ARM, whose requests are
+    // answered with the handler.
+    pub fn start_arm<F>(handler: F) -> Self
+    where
+        F: Fn(&TestRequest) -> TestResponse + Send + Sync + 'static,
+    {
+        Self::start(move |request| {
+            if "/token" == request.path {
+                TestResponse::token("token")
+            } else {
+                handler(request)
+            }
+        })
+    }
+
+    // Reads a request from the connection, and writes the handler's response.
+    fn answer(
+        mut stream: TcpStream,
+        handler: &(dyn Fn(&TestRequest) -> TestResponse + Send + Sync),
+        requests: &Mutex<Vec<TestRequest>>,
+    ) {
+        let Some(request) = Self::read_request(&mut stream) else {
+            return;
+        };
+        requests.lock().unwrap().push(request.clone());
+        let response = handler(&request);
+        let mut head = format!(
+            "HTTP/1.1 {} Test\r\nContent-Length: {}\r\nConnection: close\r\n",
+            response.status,
+            response.body.len()
+        );
+        for (name, value) in &response.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str("\r\n");
+        // The client may have given up, e.g. if it timed out
+        let _ = stream
+            .write_all(head.as_bytes())
+            .and_then(|_| stream.flush())
+            .map(|_| std::thread::sleep(response.body_delay))
+            .and_then(|_| stream.write_all(&response.body));
+    }
+
+    // Reads a request: the request line, the headers, and a body of Content-Length bytes.
+    fn read_request(stream: &mut TcpStream) -> Option<TestRequest> {
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        reader.read_line(&mut line).ok()?;
+        let mut parts = line.split_whitespace();
+        let method = parts.next()?.to_string();
+        let path = parts.next()?.to_string();
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).ok()?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':')?;
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+        let content_length = headers
+            .get("content-length")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0);
+        let mut body = vec![0; content_length];
+        reader.read_exact(&mut body).ok()?;
+        Some(TestRequest {
+            method,
+            path,
+            headers,
+            body,
+        })
+    }
+
+    // The server's URL, e.g. "http://127.0.0.1:12345", without a trailing slash.
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    // The requests received so far, in the order they arrived.
+    pub fn requests(&self) -> Vec<TestRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+// Creates settings whose subscriptions get their tokens from, and send their ARM requests to, the server.
+// The given settings, e.g. the subscriptions, are added to them.
+pub fn dashboard_settings(server: &TestServer, settings: serde_json::Value) -> DashboardSettings {
+    let mut dashboard_settings = serde_json::json!({
+        "host": "127.0.0.1",
+        "port": 8080,
+        "credentials": {
+            "test": {
+                "client_id": "client",
+                "client_secret": "secret",
+                "token_url": format!("{}/token", server.url()),
+            },
+        },
+        "arm": { "base_url": server.url() },
+        "retry": { "initial_delay_ms": 1, "max_delay_ms": 1 },
+    });
+    let fields = dashboard_settings.as_object_mut().unwrap();
+    fields.extend(settings.as_object().unwrap().clone());
+    // Subscriptions use the test credential unless they say otherwise
+    for subscription in fields
+        .get_mut("subscriptions")
+        .and_then(|subscriptions| subscriptions.as_array_mut())
+        .into_iter()
+        .flatten()
+    {
+        subscription
+            .as_object_mut()
+            .unwrap()
+            .entry("credential")
+            .or_insert_with(|| "test".into());
+    }
+    serde_json::from_value(dashboard_settings).unwrap()
+}
+
+// Creates an ARM client that uses the settings.
+pub fn arm_client(settings: &DashboardSettings) -> ArmClient {
+    let token_cache_map =
+        AccessTokenCacheMap::new(settings, &Arc::new(SecretResolver::default())).unwrap();
+    ArmClient::new(
+        reqwest::Client::new(),
+        Arc::new(token_cache_map),
+        &settings.arm,
+        RetryPolicy::new(&settings.retry),
+        ConcurrencyLimiter::new(&settings.concurrency),
+    )
+}
+
+// Creates a database, as ARM lists it, in the given elastic pool.
+pub fn database_json(name: &str, elastic_pool_id: &str) -> serde_json::Value {
+    serde_json::json!({
+        "sku": { "name": "ElasticPool", "tier": "Standard", "capacity": 0 },
+        "kind": "v12.0,user,pool",
+        "properties": {
+            "collation": "SQL_Latin1_General_CP1_CI_AS",
+            "maxSizeBytes": 268435456000.0,
+            "elasticPoolId": elastic_pool_id,
+            "status": "Online",
+            "databaseId": "00000000-0000-0000-0000-000000000000",
+            "creationDate": "2023-01-01T00:00:00Z",
+            "defaultSecondaryLocation": "eastus",
+            "catalogCollation": "SQL_Latin1_General_CP1_CI_AS",
+            "zoneRedundant": false,
+            "readScale": "Disabled",
+            "currentSku": { "name": "ElasticPool", "tier": "Standard", "capacity": 0 },
+            "currentBackupStorageRedundancy": "Geo",
+            "requestedBackupStorageRedundancy": "Geo",
+            "maintenanceConfigurationId": "/subscriptions/s/providers/Microsoft.Maintenance/publicMaintenanceConfigurations/SQL_Default",
+            "isLedgerOn": false,
+            "isInfraEncryptionEnabled": false,
+        },
+        "location": "westus2",
+        "id": format!("{}/databases/{name}", elastic_pool_id.split("/elasticPools/").next().unwrap()),
+        "name": name,
+        "type": "Microsoft.Sql/servers/databases",
+    })
+}
+
+// Creates a database's usages, as ARM lists them.
+pub fn database_usages_json(used: u64, allocated: u64, max: u64) -> serde_json::Value {
+    serde_json::json!([
+        {
+            "properties": {
+                "displayName": "Database Size",
+                "currentValue": used,
+                "limit": max,
+                "unit": "Bytes",
+            },
+            "name": "database_size",
+        },
+        {
+            "properties": {
+                "displayName": "Database Allocated Size",
+                "currentValue": allocated,
+                "limit": max,
+                "unit": "Bytes",
+            },
+            "name": "database_allocated_size",
+        },
+    ])
+}